@@ -0,0 +1,120 @@
+use crate::clock::Timestamp;
+use crate::keyset::KeySet;
+use crate::OpenIdConfiguration;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+/// The default number of tenants we keep public keys for in multi-tenant mode.
+pub(crate) const DEFAULT_MAX_CACHED_TENANTS: usize = 64;
+
+/// The state of the cached public keys, see `AzureAuth::cache_status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CacheStatus {
+    /// `true` if we have public keys to validate with.
+    pub keys_loaded: bool,
+    /// When the keys were last fetched or set, `None` if they never were.
+    pub last_refresh: Option<SystemTime>,
+    /// `true` if the keys are expired and will be refreshed on the next validation. Never `true`
+    /// in offline mode, where the keys are up to you.
+    pub expired: bool,
+    /// The number of public keys we have, not counting the trusted keys.
+    pub key_count: usize,
+    /// The number of keys added with `add_trusted_key`.
+    pub trusted_key_count: usize,
+    /// `true` if the keys were fetched from the fallback authority because the primary one
+    /// failed, see `AzureAuth::set_fallback_authority`. Back to `false` once the primary
+    /// serves them again.
+    pub keys_from_fallback: bool,
+}
+
+/// The cached discovery and key state of a single tenant.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TenantKeys {
+    pub(crate) discovery: Option<OpenIdConfiguration>,
+    pub(crate) discovery_v2: Option<OpenIdConfiguration>,
+    pub(crate) jwks_uri: String,
+    pub(crate) public_keys: Option<KeySet>,
+    pub(crate) last_refresh: Option<Timestamp>,
+    pub(crate) last_fetch: Option<Timestamp>,
+    pub(crate) keys_from_fallback: bool,
+}
+
+/// A small LRU cache of per tenant key state. When the cache is full, the tenant that was used
+/// least recently is evicted. Tenants are compared ignoring ASCII case. The tenant currently in
+/// use lives on `AzureAuth` itself and counts towards `max_entries`.
+#[derive(Debug, Clone)]
+pub(crate) struct TenantCache {
+    entries: HashMap<String, (TenantKeys, u64)>,
+    max_entries: usize,
+    uses: u64,
+}
+
+impl TenantCache {
+    pub(crate) fn new(max_entries: usize) -> Self {
+        TenantCache {
+            entries: HashMap::new(),
+            max_entries,
+            uses: 0,
+        }
+    }
+
+    /// Removes and returns the state of `tenant` if we have it cached.
+    pub(crate) fn take(&mut self, tenant: &str) -> Option<TenantKeys> {
+        self.entries.remove(&key(tenant)).map(|(keys, _)| keys)
+    }
+
+    /// Stores the state of `tenant`, evicting the least recently used tenants if needed.
+    pub(crate) fn put(&mut self, tenant: String, keys: TenantKeys) {
+        self.uses += 1;
+        self.entries.insert(key(&tenant), (keys, self.uses));
+        self.evict();
+    }
+
+    /// Sets the maximum number of tenants to keep, evicting right away if we have more than that.
+    pub(crate) fn set_max_entries(&mut self, max_entries: usize) {
+        self.max_entries = max_entries;
+        self.evict();
+    }
+
+    /// `true` if we have keys for `tenant` that are at most `max_age` old.
+    pub(crate) fn is_fresh(&self, tenant: &str, max_age: Duration) -> bool {
+        self.entries
+            .get(&key(tenant))
+            .and_then(|(keys, _)| keys.last_refresh)
+            .is_some_and(|lr| lr.elapsed() <= max_age)
+    }
+
+    /// Drops tenants whose keys are older than `max_age` since they'd need to be fetched again
+    /// before use anyway. Tenants without keys yet are kept, we have their discovery document.
+    pub(crate) fn evict_expired(&mut self, max_age: Duration) {
+        self.entries.retain(|_, (keys, _)| match keys.last_refresh {
+            Some(lr) => lr.elapsed() <= max_age,
+            None => true,
+        });
+    }
+
+    fn evict(&mut self) {
+        while !self.entries.is_empty() && self.entries.len() >= self.max_entries {
+            let lru = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(tenant, _)| tenant.clone());
+
+            match lru {
+                Some(tenant) => self.entries.remove(&tenant),
+                None => break,
+            };
+        }
+    }
+
+    #[cfg(all(test, feature = "azure-claims"))]
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+fn key(tenant: &str) -> String {
+    tenant.to_ascii_lowercase()
+}
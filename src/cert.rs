@@ -0,0 +1,139 @@
+use crate::{AuthErr, Jwk};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+
+const CERTIFICATE: &str = "CERTIFICATE";
+const PUBLIC_KEY: &str = "PUBLIC KEY";
+const RSA_PUBLIC_KEY: &str = "RSA PUBLIC KEY";
+
+/// The OID of `rsaEncryption`, 1.2.840.113549.1.1.1.
+const RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+
+/// Reads the RSA public key of the PEM encoded X.509 certificate `pem` as a JWK with `kid`. The
+/// certificate itself isn't verified (its issuer, validity, ...), only the key is used.
+pub(crate) fn jwk_from_pem_cert(kid: String, pem: &str) -> Result<Jwk, AuthErr> {
+    let der = pem_body(&kid, pem, &[CERTIFICATE])?.1;
+    let key = rsa_public_key(&der);
+    jwk(kid, key, "Expected an RSA certificate.")
+}
+
+/// Like `jwk_from_pem_cert`, but `pem` may also be a bare public key, either a
+/// `SubjectPublicKeyInfo` (`-----BEGIN PUBLIC KEY-----`) or a PKCS#1 RSA key
+/// (`-----BEGIN RSA PUBLIC KEY-----`).
+pub(crate) fn jwk_from_pem(kid: String, pem: &str) -> Result<Jwk, AuthErr> {
+    let (label, der) = pem_body(&kid, pem, &[CERTIFICATE, PUBLIC_KEY, RSA_PUBLIC_KEY])?;
+    let key = match label {
+        CERTIFICATE => rsa_public_key(&der),
+        PUBLIC_KEY => spki_rsa_key(&der),
+        _ => pkcs1_rsa_key(&der),
+    };
+    jwk(kid, key, "Expected an RSA public key.")
+}
+
+fn invalid(kid: &str, msg: &str) -> AuthErr {
+    AuthErr::Other(format!("Invalid PEM for `{}`. {}", kid, msg))
+}
+
+/// The label, one of `labels`, and the decoded body of the single PEM block `pem`.
+fn pem_body(
+    kid: &str,
+    pem: &str,
+    labels: &[&'static str],
+) -> Result<(&'static str, Vec<u8>), AuthErr> {
+    let pem = pem.trim();
+    let (label, body) = labels
+        .iter()
+        .find_map(|label| {
+            let body = pem
+                .strip_prefix(&format!("-----BEGIN {}-----", label))?
+                .strip_suffix(&format!("-----END {}-----", label))?;
+            Some((*label, body))
+        })
+        .ok_or_else(|| {
+            let msg = format!("Expected a single PEM `{}` block.", labels.join("` or `"));
+            invalid(kid, &msg)
+        })?;
+    let body: String = body.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+    let der = STANDARD
+        .decode(body)
+        .map_err(|e| invalid(kid, &format!("The PEM body isn't valid base64. {}", e)))?;
+    Ok((label, der))
+}
+
+fn jwk(kid: String, key: Option<(&[u8], &[u8])>, msg: &str) -> Result<Jwk, AuthErr> {
+    let (n, e) = key.ok_or_else(|| invalid(&kid, msg))?;
+    Ok(Jwk::new(
+        kid,
+        URL_SAFE_NO_PAD.encode(n),
+        URL_SAFE_NO_PAD.encode(e),
+    ))
+}
+
+/// The modulus and exponent of the key in the DER encoded certificate `der`.
+fn rsa_public_key(der: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (certificate, _) = read(der, 0x30)?;
+    let (tbs, _) = read(certificate, 0x30)?;
+
+    // the version is optional, followed by the serial, signature, issuer, validity and subject
+    let mut rest = match read(tbs, 0xa0) {
+        Some((_, rest)) => rest,
+        None => tbs,
+    };
+    for tag in [0x02, 0x30, 0x30, 0x30, 0x30] {
+        rest = read(rest, tag)?.1;
+    }
+
+    spki_rsa_key(rest)
+}
+
+/// The modulus and exponent of the DER encoded `SubjectPublicKeyInfo` at the start of `der`.
+fn spki_rsa_key(der: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (spki, _) = read(der, 0x30)?;
+    let (algorithm, rest) = read(spki, 0x30)?;
+    let (oid, _) = read(algorithm, 0x06)?;
+    if oid != RSA_ENCRYPTION {
+        return None;
+    }
+    // the key is a bit string, starting with the number of unused bits
+    let (key, _) = read(rest, 0x03)?;
+    pkcs1_rsa_key(key.strip_prefix(&[0])?)
+}
+
+/// The modulus and exponent of the DER encoded PKCS#1 `RSAPublicKey` at the start of `der`.
+fn pkcs1_rsa_key(der: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (rsa_key, _) = read(der, 0x30)?;
+    let (n, rest) = read(rsa_key, 0x02)?;
+    let (e, _) = read(rest, 0x02)?;
+    Some((strip_zeros(n), strip_zeros(e)))
+}
+
+/// Reads a DER element with `tag` from the start of `input`, returns its content and what
+/// follows it.
+fn read(input: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (&found, rest) = input.split_first()?;
+    if found != tag {
+        return None;
+    }
+    let (&first, mut rest) = rest.split_first()?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let len_bytes = (first & 0x7f) as usize;
+        if len_bytes == 0 || len_bytes > std::mem::size_of::<usize>() || rest.len() < len_bytes {
+            return None;
+        }
+        let (len, after) = rest.split_at(len_bytes);
+        rest = after;
+        len.iter().fold(0, |len, b| len << 8 | *b as usize)
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some(rest.split_at(len))
+}
+
+/// Integers are signed in DER, so a positive one may start with a zero byte JWKs don't have.
+fn strip_zeros(int: &[u8]) -> &[u8] {
+    let start = int.iter().position(|b| *b != 0).unwrap_or(int.len());
+    &int[start..]
+}
@@ -0,0 +1,77 @@
+//! All the time handling of the crate goes through here so we can pick the time source with a
+//! feature flag. `chrono` is used by default, the `time` feature uses the `time` crate instead and
+//! with neither of them enabled we fall back to `std::time::SystemTime`.
+
+use std::ops::Sub;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A point in time, stored as seconds since the unix epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Timestamp(i64);
+
+impl Timestamp {
+    pub(crate) fn now() -> Self {
+        Timestamp(unix_now())
+    }
+
+    /// Seconds since the unix epoch, the unit used by the `exp`, `nbf` and `iat` claims.
+    #[cfg(feature = "azure-claims")]
+    pub(crate) fn unix(&self) -> i64 {
+        self.0
+    }
+
+    /// The time passed since this timestamp, zero if it's in the future.
+    pub(crate) fn elapsed(&self) -> Duration {
+        Duration::from_secs((unix_now() - self.0).max(0) as u64)
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(d) => Timestamp(d.as_secs() as i64),
+            Err(e) => Timestamp(-(e.duration().as_secs() as i64)),
+        }
+    }
+}
+
+impl From<Timestamp> for SystemTime {
+    fn from(time: Timestamp) -> Self {
+        if time.0 >= 0 {
+            UNIX_EPOCH + Duration::from_secs(time.0 as u64)
+        } else {
+            UNIX_EPOCH - Duration::from_secs(time.0.unsigned_abs())
+        }
+    }
+}
+
+impl Sub<Duration> for Timestamp {
+    type Output = Timestamp;
+
+    fn sub(self, rhs: Duration) -> Timestamp {
+        Timestamp(self.0 - rhs.as_secs() as i64)
+    }
+}
+
+/// Converts a number of hours, as used by the public api, to a `Duration`.
+pub(crate) fn hours(hours: i64) -> Duration {
+    Duration::from_secs(hours.max(0) as u64 * 60 * 60)
+}
+
+#[cfg(feature = "chrono")]
+fn unix_now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+#[cfg(all(feature = "time", not(feature = "chrono")))]
+fn unix_now() -> i64 {
+    time::OffsetDateTime::now_utc().unix_timestamp()
+}
+
+#[cfg(not(any(feature = "chrono", feature = "time")))]
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
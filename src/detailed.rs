@@ -0,0 +1,92 @@
+use crate::{AuthErr, AzureJwtClaims, Token, TokenVersion};
+use std::time::Duration;
+
+/// A check of the default validation that a token failed, see `AzureAuth::validate_detailed`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ValidationFailure {
+    /// The token expired `ago` ago, which is more than the leeway.
+    Expired { ago: Duration },
+    /// The token only becomes valid in `starts_in`, which is more than the leeway.
+    NotYetValid { starts_in: Duration },
+    /// The token is issued `by` in the future, which is more than the `iat` leeway.
+    IssuedInFuture { by: Duration },
+    /// The token is issued for another audience.
+    InvalidAudience { expected: String, found: String },
+    /// The token is issued by another issuer than the OpenID metadata advertises.
+    InvalidIssuer { expected: String, found: String },
+    /// The token's version isn't the one required with `set_required_version`.
+    UnexpectedTokenVersion {
+        expected: TokenVersion,
+        found: String,
+    },
+    /// The token is from a personal Microsoft account, see `set_allow_personal_accounts`.
+    PersonalAccountNotAllowed,
+    /// The identity provider of the token isn't allowed, see `set_allowed_idps`.
+    IdpNotAllowed { found: String },
+    /// The token is revoked according to the check set with `set_revocation_check`.
+    Revoked,
+    /// The token doesn't have a scope required with `set_required_scopes`.
+    MissingScope { name: String },
+}
+
+impl From<ValidationFailure> for AuthErr {
+    fn from(failure: ValidationFailure) -> AuthErr {
+        match failure {
+            ValidationFailure::Expired { .. } => AuthErr::TokenExpired,
+            ValidationFailure::NotYetValid { .. } => AuthErr::TokenNotYetValid,
+            ValidationFailure::InvalidAudience { expected, found } => {
+                AuthErr::InvalidAudience { expected, found }
+            }
+            ValidationFailure::IssuedInFuture { .. } => AuthErr::IssuedInFuture,
+            ValidationFailure::InvalidIssuer { expected, found } => {
+                AuthErr::InvalidIssuer { expected, found }
+            }
+            ValidationFailure::UnexpectedTokenVersion { expected, found } => {
+                AuthErr::UnexpectedTokenVersion { expected, found }
+            }
+            ValidationFailure::PersonalAccountNotAllowed => AuthErr::PersonalAccountNotAllowed,
+            ValidationFailure::IdpNotAllowed { found } => AuthErr::IdpNotAllowed(found),
+            ValidationFailure::Revoked => AuthErr::TokenRevoked,
+            ValidationFailure::MissingScope { name } => AuthErr::MissingScope(name),
+        }
+    }
+}
+
+/// The result of `AzureAuth::validate_detailed`: an authentic token together with every check of
+/// the default validation it failed.
+///
+/// The claims can be inspected for logging, but the only way to get the validated token is
+/// `into_result`, which fails if any check failed.
+#[derive(Debug)]
+pub struct DetailedValidation {
+    pub(crate) token: Token<AzureJwtClaims>,
+    pub(crate) failures: Vec<ValidationFailure>,
+}
+
+impl DetailedValidation {
+    /// `true` if the token passed every check.
+    pub fn is_valid(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    /// The checks the token failed, empty if it's valid.
+    pub fn failures(&self) -> &[ValidationFailure] {
+        &self.failures
+    }
+
+    /// The claims of the token. The signature is verified, but the token may still be expired
+    /// or otherwise invalid, check `is_valid` before trusting them.
+    pub fn claims(&self) -> &AzureJwtClaims {
+        &self.token.claims
+    }
+
+    /// The validated token, or the first failed check as the error `validate_token` would
+    /// have returned.
+    pub fn into_result(self) -> Result<Token<AzureJwtClaims>, AuthErr> {
+        match self.failures.into_iter().next() {
+            None => Ok(self.token),
+            Some(failure) => Err(failure.into()),
+        }
+    }
+}
@@ -0,0 +1,32 @@
+use serde::{Deserialize, Serialize};
+
+/// The OpenID Connect discovery document (the OpenID metadata) of the authority we validate
+/// tokens for. See: <https://openid.net/specs/openid-connect-discovery-1_0.html#ProviderMetadata>
+///
+/// Only the fields that are useful for validating tokens or driving a login flow are captured.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenIdConfiguration {
+    /// The issuer of the tokens. For the `common`, `organizations` and `consumers` tenants this
+    /// contains a `{tenantid}` placeholder instead of an actual tenant id.
+    pub issuer: String,
+    /// Where the public keys used to sign the tokens are published.
+    pub jwks_uri: String,
+    /// The OAuth 2.0 authorization endpoint.
+    pub authorization_endpoint: Option<String>,
+    /// The OAuth 2.0 token endpoint.
+    pub token_endpoint: Option<String>,
+    /// The OpenID Connect UserInfo endpoint.
+    pub userinfo_endpoint: Option<String>,
+    /// Where to send users to sign them out.
+    pub end_session_endpoint: Option<String>,
+    /// The device code flow endpoint.
+    pub device_authorization_endpoint: Option<String>,
+    /// The algorithms used to sign id tokens, i.e. `["RS256"]`.
+    pub id_token_signing_alg_values_supported: Option<Vec<String>>,
+    /// The scopes supported by the authority.
+    pub scopes_supported: Option<Vec<String>>,
+    /// The claims the authority may include in tokens.
+    pub claims_supported: Option<Vec<String>>,
+    /// The OAuth 2.0 `response_type` values supported.
+    pub response_types_supported: Option<Vec<String>>,
+}
@@ -1,12 +1,165 @@
 use crate::jwt;
+use crate::TokenVersion;
 use std::{error::Error, fmt};
 
+/// The errors returned by this crate. Variants are added as new checks are, so matches on it
+/// need a wildcard arm. Use `is_client_error`/`is_server_error` to tell the two kinds apart.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum AuthErr {
     InvalidToken(jwt::errors::Error),
     ConnectionError(reqwest::Error),
     Other(String),
     ParseError(String),
+    /// The token header has no `kid` and we have more than one key, so we can't tell which one
+    /// it was signed with.
+    MissingKid,
+    /// None of the public keys matches the `kid` in the token header.
+    KeyNotFound(String),
+    /// The token was issued by a tenant that isn't in the configured set of tenants.
+    TenantNotAllowed(String),
+    /// The token is valid but the user doesn't have the required role.
+    MissingRole(String),
+    /// The `iat` claim of the token is in the future, even with the leeway applied.
+    IssuedInFuture,
+    /// The `iss` claim of the token doesn't match the issuer advertised in the OpenID metadata.
+    InvalidIssuer {
+        expected: String,
+        found: String,
+    },
+    /// The token can't be validated without refreshing the public keys or metadata first.
+    KeysStale,
+    /// The token is signed with a key whose `kid` is not in the pinned set, see `set_pinned_kids`.
+    KidNotPinned(String),
+    /// Fetching the OpenID metadata or the public keys from `url` failed. `status` is the HTTP
+    /// status we got back, `None` if we got no response or couldn't parse it.
+    HttpError {
+        url: String,
+        status: Option<u16>,
+        source: reqwest::Error,
+    },
+    /// The `ver` claim of the token isn't the version required with `set_required_version`.
+    UnexpectedTokenVersion {
+        expected: TokenVersion,
+        found: String,
+    },
+    /// The request has no `Authorization` header.
+    MissingAuthorizationHeader,
+    /// The `Authorization` header doesn't use the `Bearer` scheme, or isn't valid text.
+    UnsupportedAuthorizationScheme(String),
+    /// The `Authorization` header uses the `Bearer` scheme but has no token.
+    EmptyBearerToken,
+    /// The algorithm in the token header isn't the one the matching key is advertised for.
+    AlgorithmKeyMismatch {
+        token: String,
+        key: String,
+    },
+    /// The token is authentic but its claims don't have the expected shape, i.e. a required claim
+    /// is missing. Often caused by validating an access token as an id token or vice versa.
+    ClaimsParseError(String),
+    /// The token isn't a JWT at all: it doesn't have exactly three base64url segments separated
+    /// by dots, or its header or payload isn't UTF-8. Usually garbage or an opaque token pasted
+    /// where a JWT was expected.
+    MalformedToken(String),
+    /// The token is from a personal Microsoft account, which `set_allow_personal_accounts`
+    /// excludes.
+    PersonalAccountNotAllowed,
+    /// The token is expired, even with the leeway applied.
+    TokenExpired,
+    /// The `nbf` claim of the token is in the future, even with the leeway applied.
+    TokenNotYetValid,
+    /// The token is issued for another audience than ours. Often the App ID was configured
+    /// while tokens are issued for the `api://` form, or the other way around.
+    InvalidAudience {
+        expected: String,
+        found: String,
+    },
+    /// The signature of the token doesn't match the key it claims to be signed with.
+    InvalidSignature,
+    /// The user didn't authenticate with multi-factor authentication, see
+    /// `AzureJwtClaims::require_mfa`.
+    MfaRequired,
+    /// The user authenticated with an identity provider (the `idp` claim, or `iss` without
+    /// it) that isn't in the allow-list, see `set_allowed_idps`.
+    IdpNotAllowed(String),
+    /// The token is authentic but revoked, according to the check set with
+    /// `set_revocation_check`.
+    TokenRevoked,
+    /// A claim that `set_require_temporal_claims` or the `Validation` requires is missing from
+    /// the token, i.e. `exp`.
+    MissingClaim(String),
+    /// The token is valid but doesn't have a scope required with `set_required_scopes`.
+    MissingScope(String),
+    /// The token is larger than the limit set with `set_max_token_bytes`.
+    TokenTooLarge {
+        size: usize,
+        max: usize,
+    },
+    /// The audience is neither a GUID nor a URI, see `set_strict_audience`. Usually the display
+    /// name of the app was configured instead of its Application (client) ID.
+    UnexpectedAudienceFormat(String),
+}
+
+impl AuthErr {
+    /// Returns `true` if the error is caused by the token the caller provided, i.e. it's
+    /// expired, not yet valid, issued for another audience or issuer, malformed or signed by a
+    /// key we don't know. In a web server this usually maps to `401 Unauthorized`.
+    ///
+    /// Every error is either a client error or a server error, never both.
+    pub fn is_client_error(&self) -> bool {
+        use jwt::errors::ErrorKind;
+        use AuthErr::*;
+
+        match self {
+            InvalidToken(err) => !matches!(
+                err.kind(),
+                ErrorKind::InvalidRsaKey(_)
+                    | ErrorKind::InvalidEcdsaKey
+                    | ErrorKind::InvalidKeyFormat
+                    | ErrorKind::Crypto(_)
+            ),
+            ParseError(_)
+            | MissingKid
+            | KeyNotFound(_)
+            | TenantNotAllowed(_)
+            | MissingRole(_)
+            | IssuedInFuture
+            | InvalidIssuer { .. }
+            | KidNotPinned(_)
+            | UnexpectedTokenVersion { .. }
+            | MissingAuthorizationHeader
+            | UnsupportedAuthorizationScheme(_)
+            | EmptyBearerToken
+            | AlgorithmKeyMismatch { .. }
+            | ClaimsParseError(_)
+            | MalformedToken(_)
+            | PersonalAccountNotAllowed
+            | TokenExpired
+            | TokenNotYetValid
+            | InvalidAudience { .. }
+            | InvalidSignature
+            | MfaRequired
+            | IdpNotAllowed(_)
+            | TokenRevoked
+            | MissingClaim(_)
+            | MissingScope(_)
+            | TokenTooLarge { .. } => true,
+            ConnectionError(_)
+            | Other(_)
+            | KeysStale
+            | HttpError { .. }
+            | UnexpectedAudienceFormat(_) => false,
+        }
+    }
+
+    /// Returns `true` if the error is not the caller's fault: Microsoft couldn't be reached,
+    /// returned something we couldn't parse, or the public keys we got are unusable. In a web
+    /// server this usually maps to `503 Service Unavailable`.
+    ///
+    /// Errors we can't classify (`Other`) are treated as server errors.
+    pub fn is_server_error(&self) -> bool {
+        !self.is_client_error()
+    }
 }
 
 impl Error for AuthErr {}
@@ -20,6 +173,84 @@ impl fmt::Display for AuthErr {
             ConnectionError(err) => write!(f, "Could not connect to Microsoft. {}", err),
             Other(msg) => write!(f, "An error occurred: {}", msg),
             ParseError(msg) => write!(f, "Could not parse token. {}", msg),
+            MissingKid => write!(f, "No `kid` in token."),
+            KeyNotFound(kid) => write!(
+                f,
+                "Invalid token. Could not verify authenticity, no key with kid `{}`.",
+                kid
+            ),
+            TenantNotAllowed(tid) => write!(f, "Tokens from tenant `{}` are not accepted.", tid),
+            MissingRole(role) => write!(f, "The user doesn't have the role `{}`.", role),
+            IssuedInFuture => write!(f, "Invalid token. The token is issued in the future."),
+            InvalidIssuer { expected, found } => write!(
+                f,
+                "Invalid token. Expected issuer `{}` but found `{}`.",
+                expected, found
+            ),
+            KeysStale => write!(f, "The public keys need to be refreshed."),
+            KidNotPinned(kid) => write!(f, "Invalid token. The key `{}` is not pinned.", kid),
+            HttpError {
+                url,
+                status: Some(status),
+                source,
+            } => write!(
+                f,
+                "Could not fetch `{}`, status {}. {}",
+                url, status, source
+            ),
+            HttpError { url, source, .. } => write!(f, "Could not fetch `{}`. {}", url, source),
+            UnexpectedTokenVersion { expected, found } => write!(
+                f,
+                "Invalid token. Expected a v{} token but found version `{}`.",
+                expected.as_str(),
+                found
+            ),
+            MissingAuthorizationHeader => write!(f, "No `Authorization` header in request."),
+            UnsupportedAuthorizationScheme(scheme) => write!(
+                f,
+                "Expected a `Bearer` token in the `Authorization` header, found `{}`.",
+                scheme
+            ),
+            EmptyBearerToken => write!(
+                f,
+                "The bearer token in the `Authorization` header is empty."
+            ),
+            AlgorithmKeyMismatch { token, key } => write!(
+                f,
+                "Invalid token. The token is signed with `{}` but the key is for `{}`.",
+                token, key
+            ),
+            ClaimsParseError(msg) => write!(f, "Could not parse the claims of the token. {}", msg),
+            MalformedToken(msg) => write!(f, "Malformed token. {}", msg),
+            PersonalAccountNotAllowed => write!(f, "Personal Microsoft accounts are not accepted."),
+            TokenExpired => write!(f, "Invalid token. The token is expired."),
+            TokenNotYetValid => write!(f, "Invalid token. The token is not valid yet."),
+            InvalidAudience { expected, found } => write!(
+                f,
+                "Invalid token. Expected audience `{}` but found `{}`.",
+                expected, found
+            ),
+            InvalidSignature => write!(f, "Invalid token. The signature is invalid."),
+            MfaRequired => write!(
+                f,
+                "The user didn't authenticate with multi-factor authentication."
+            ),
+            IdpNotAllowed(idp) => write!(f, "The identity provider `{}` is not accepted.", idp),
+            TokenRevoked => write!(f, "Invalid token. The token is revoked."),
+            MissingClaim(claim) => write!(f, "Invalid token. The `{}` claim is missing.", claim),
+            MissingScope(scope) => write!(f, "The token doesn't have the scope `{}`.", scope),
+            TokenTooLarge { size, max } => write!(
+                f,
+                "Invalid token. The token is {} bytes, more than the limit of {}.",
+                size, max
+            ),
+            UnexpectedAudienceFormat(aud) => write!(
+                f,
+                "The audience `{}` is neither a GUID nor a URI. Use the Application (client) ID \
+                 from the overview of the app registration in the Azure portal, or its \
+                 Application ID URI (`api://...`), not the display name of the app.",
+                aud
+            ),
         }
     }
 }
@@ -30,8 +261,19 @@ impl From<reqwest::Error> for AuthErr {
     }
 }
 
+/// The failed checks jsonwebtoken reports get their own variant, everything else stays an
+/// `InvalidToken`. Its `InvalidAudience` and `InvalidIssuer` do too since they don't say which
+/// values were expected and found, we fill those in where we decode the token.
 impl From<jwt::errors::Error> for AuthErr {
     fn from(e: jwt::errors::Error) -> AuthErr {
-        AuthErr::InvalidToken(e)
+        use jwt::errors::ErrorKind;
+
+        match e.kind() {
+            ErrorKind::ExpiredSignature => AuthErr::TokenExpired,
+            ErrorKind::ImmatureSignature => AuthErr::TokenNotYetValid,
+            ErrorKind::InvalidSignature => AuthErr::InvalidSignature,
+            ErrorKind::MissingRequiredClaim(claim) => AuthErr::MissingClaim(claim.clone()),
+            _ => AuthErr::InvalidToken(e),
+        }
     }
 }
@@ -0,0 +1,105 @@
+use crate::{AuthErr, AzureAuth, AzureJwtClaims, Token};
+use std::sync::Arc;
+use tokio::sync::{Mutex, MutexGuard};
+
+/// A shareable `AzureAuth`. Validating needs `&mut self` since it may refresh the keys, this
+/// wraps the instance in an `Arc<Mutex<_>>` so it can be cloned into tasks and threads or kept
+/// in a `static`.
+///
+/// Validations are serialised: each one holds the lock until it's done, which with cached keys
+/// is the few microseconds a validation takes. A validation that refreshes the keys, because
+/// they expired or the token has an unknown `kid`, holds the lock for the whole fetch, and every
+/// other validation waits for it. Calling `AzureAuth::refresh_if_needed` through `lock` from a
+/// timer keeps the expiry refreshes off the request path.
+///
+/// ```rust, no_run
+/// # async fn run(tokens: Vec<String>) -> Result<(), azure_jwt_async::AuthErr> {
+/// use azure_jwt_async::{AzureAuth, AzureAuthHandle};
+/// use std::sync::OnceLock;
+///
+/// static AUTH: OnceLock<AzureAuthHandle> = OnceLock::new();
+///
+/// // at startup
+/// let az_auth = AzureAuth::new("6e74172b-be56-4843-9ff4-e66a39bb12e3").await?;
+/// AUTH.set(AzureAuthHandle::new(az_auth)).expect("initialized once");
+///
+/// // from any task or thread
+/// let tasks: Vec<_> = tokens
+///     .into_iter()
+///     .map(|token| tokio::spawn(async move { AUTH.get().unwrap().validate(&token).await }))
+///     .collect();
+/// for task in tasks {
+///     let claims = task.await.expect("task panicked")?;
+///     println!("{}", claims.sub);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct AzureAuthHandle {
+    inner: Arc<Mutex<AzureAuth>>,
+}
+
+impl AzureAuthHandle {
+    /// Wraps `az_auth` to share it. Configure the instance before, or through `lock`.
+    pub fn new(az_auth: AzureAuth) -> Self {
+        AzureAuthHandle {
+            inner: Arc::new(Mutex::new(az_auth)),
+        }
+    }
+
+    /// Default validation, see `AzureAuth::validate_token`.
+    pub async fn validate(&self, token: &str) -> Result<AzureJwtClaims, AuthErr> {
+        Ok(self.validate_token(token).await?.claims)
+    }
+
+    /// Same as `AzureAuth::validate_token`, with the header of the token.
+    pub async fn validate_token(&self, token: &str) -> Result<Token<AzureJwtClaims>, AuthErr> {
+        self.inner.lock().await.validate_token(token).await
+    }
+
+    /// Validates a stream of tokens with up to `limit` validations in flight, yielding one result
+    /// per token in the same order as `tokens`. At most `limit` tokens are pulled ahead of the
+    /// results, so a slow consumer holds back the source.
+    ///
+    /// The validations take turns on the lock like any other, so `limit` doesn't make a single
+    /// stream validate faster. It lets tokens queue up while one of them waits for a key refresh,
+    /// and lets several streams, i.e. one per task, share the instance and its key cache. A
+    /// `limit` of 16 to 64 keeps a busy consumer fed, use `AzureAuth::validate_stream` for a
+    /// single unshared stream.
+    ///
+    /// # Panics
+    ///
+    /// If `limit` is zero.
+    #[cfg(feature = "stream")]
+    pub fn validate_stream<'a, S>(
+        &'a self,
+        tokens: S,
+        limit: usize,
+    ) -> impl futures_util::Stream<Item = Result<Token<AzureJwtClaims>, AuthErr>> + 'a
+    where
+        S: futures_util::Stream<Item = String> + 'a,
+    {
+        use futures_util::StreamExt;
+
+        assert!(
+            limit > 0,
+            "the limit of validations in flight must be at least one"
+        );
+        tokens
+            .map(move |token| async move { self.validate_token(&token).await })
+            .buffered(limit)
+    }
+
+    /// Locks the instance for everything else, i.e. changing its settings or a custom
+    /// validation. Don't keep the guard around, validations wait for it.
+    pub async fn lock(&self) -> MutexGuard<'_, AzureAuth> {
+        self.inner.lock().await
+    }
+}
+
+impl From<AzureAuth> for AzureAuthHandle {
+    fn from(az_auth: AzureAuth) -> Self {
+        AzureAuthHandle::new(az_auth)
+    }
+}
@@ -0,0 +1,71 @@
+use crate::keyset::B64;
+use crate::{jwt, AuthErr, AzureJwtClaims, AzureJwtHeader, TokenVersion};
+use base64::Engine;
+use serde::Deserialize;
+
+/// What a token contains, see `AzureAuth::inspect`.
+///
+/// **Nothing in here is verified.** Anyone can make a token with these contents, only use it for
+/// diagnostics, never to make decisions about access.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct TokenInspection {
+    /// The decoded header.
+    pub header: AzureJwtHeader,
+    /// The decoded payload, the JSON of the claims.
+    pub payload: String,
+    /// The claims, `None` if they don't have the shape of `AzureJwtClaims`, i.e. a required
+    /// claim is missing.
+    pub claims: Option<AzureJwtClaims>,
+    /// The number of `.` separated segments, 3 for a signed JWT.
+    pub segment_count: usize,
+    /// The version in the `ver` claim, `TokenVersion::V1` if it's missing and `None` if it's
+    /// unknown, see `AzureJwtClaims::version`.
+    pub version: Option<TokenVersion>,
+}
+
+#[derive(Deserialize)]
+struct VersionClaim {
+    ver: Option<String>,
+}
+
+/// Decodes the header and payload of `token` without verifying anything.
+pub(crate) fn inspect(token: &str) -> Result<TokenInspection, AuthErr> {
+    let segments: Vec<&str> = token.split('.').collect();
+    if segments.len() < 2 {
+        return Err(AuthErr::MalformedToken(format!(
+            "Expected at least 2 segments separated by `.` but found {}.",
+            segments.len()
+        )));
+    }
+
+    let decode = |segment: &str, what: &str| {
+        B64.decode(segment)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or_else(|| AuthErr::MalformedToken(format!("The {} isn't base64url.", what)))
+    };
+    let header: jwt::Header = parse_json(&decode(segments[0], "header")?, "header")?;
+    let payload = decode(segments[1], "payload")?;
+    let version: VersionClaim = parse_json(&payload, "payload")?;
+
+    Ok(TokenInspection {
+        header: header.into(),
+        claims: parse_json(&payload, "payload").ok(),
+        segment_count: segments.len(),
+        version: match version.ver.as_deref() {
+            None | Some("1.0") => Some(TokenVersion::V1),
+            Some("2.0") => Some(TokenVersion::V2),
+            _ => None,
+        },
+        payload,
+    })
+}
+
+fn parse_json<T>(json: &str, what: &str) -> Result<T, AuthErr>
+where
+    for<'de> T: Deserialize<'de>,
+{
+    serde_json::from_str(json)
+        .map_err(|e| AuthErr::MalformedToken(format!("The {} isn't valid JSON. {}", what, e)))
+}
@@ -0,0 +1,103 @@
+use crate::AuthErr;
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+
+/// An OAuth 2.0 token introspection client, see
+/// [RFC 7662](https://datatracker.ietf.org/doc/html/rfc7662). Asks the authorization server
+/// whether a token is active, for opaque tokens that can't be validated locally or when a
+/// revoked token must be rejected before it expires.
+///
+/// This is separate from the JWT validation which never calls it. Azure AD itself has no
+/// introspection endpoint, this is meant for gateways and other authorization servers in front
+/// of or next to it.
+#[derive(Clone)]
+pub struct IntrospectionClient {
+    endpoint: String,
+    client_id: String,
+    client_secret: String,
+    http: reqwest::Client,
+}
+
+/// The answer of the introspection endpoint. Only `active` is required by the RFC, the other
+/// fields are `None` when the server leaves them out, which it does for inactive tokens.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct IntrospectionResponse {
+    /// Whether the token is currently active, the only thing to rely on for accepting it.
+    pub active: bool,
+    /// The scopes of the token, separated by spaces.
+    pub scope: Option<String>,
+    /// When the token expires, in seconds since the epoch.
+    pub exp: Option<i64>,
+    /// The client the token was issued to.
+    pub client_id: Option<String>,
+    /// The subject of the token.
+    pub sub: Option<String>,
+}
+
+impl IntrospectionResponse {
+    /// The scopes of the token, empty if it has none.
+    pub fn scopes(&self) -> Vec<&str> {
+        self.scope
+            .as_deref()
+            .map(|scope| scope.split_whitespace().collect())
+            .unwrap_or_default()
+    }
+}
+
+impl IntrospectionClient {
+    /// A client for the introspection `endpoint`, authenticating with the client credentials
+    /// `client_id` and `client_secret` (HTTP basic authentication).
+    ///
+    /// # Errors
+    ///
+    /// `AuthErr::ConnectionError` if the HTTP client can't be created.
+    pub fn new(
+        endpoint: impl Into<String>,
+        client_id: impl Into<String>,
+        client_secret: impl Into<String>,
+    ) -> Result<Self, AuthErr> {
+        Ok(IntrospectionClient {
+            endpoint: endpoint.into(),
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            http: crate::oidc::shared_client()?.clone(),
+        })
+    }
+
+    /// Asks the introspection endpoint about `token`. An inactive token is not an error, check
+    /// `IntrospectionResponse::active`.
+    ///
+    /// # Errors
+    ///
+    /// `AuthErr::HttpError` if the endpoint can't be reached, doesn't answer with a success
+    /// status or its answer can't be parsed.
+    pub async fn introspect(&self, token: &str) -> Result<IntrospectionResponse, AuthErr> {
+        self.post(token).await.map_err(|e| AuthErr::HttpError {
+            url: self.endpoint.clone(),
+            status: e.status().map(|s| s.as_u16()),
+            source: e,
+        })
+    }
+
+    async fn post(&self, token: &str) -> Result<IntrospectionResponse, reqwest::Error> {
+        self.http
+            .post(&self.endpoint)
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(&[("token", token), ("token_type_hint", "access_token")])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+}
+
+impl Debug for IntrospectionClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IntrospectionClient")
+            .field("endpoint", &self.endpoint)
+            .field("client_id", &self.client_id)
+            .finish_non_exhaustive()
+    }
+}
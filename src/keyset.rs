@@ -0,0 +1,159 @@
+use crate::clock::Timestamp;
+use crate::Jwk;
+use base64::alphabet::URL_SAFE;
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig};
+use base64::engine::DecodePaddingMode;
+use base64::Engine;
+use jsonwebtoken::errors::Error;
+use jsonwebtoken::DecodingKey;
+use std::time::Duration;
+
+pub(crate) const B64: GeneralPurpose = GeneralPurpose::new(
+    &URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// The public keys we validate against. Each key is decoded once, when the keys are set, instead
+/// of decoding the base64 modulus and exponent on every validation.
+#[derive(Debug, Clone)]
+pub(crate) struct KeySet {
+    keys: Vec<Entry>,
+}
+
+#[derive(Clone)]
+struct Entry {
+    key: Jwk,
+    /// What's wrong with the key if it can't be decoded, reported when it's used.
+    decoding_key: Result<DecodingKey, Error>,
+    /// When the key was last published by the source, for `merged`.
+    last_seen: Timestamp,
+}
+
+impl Entry {
+    fn new(key: Jwk) -> Self {
+        let decoding_key = match (&key.x, rsa_public_key_der(&key)) {
+            (Some(x), _) => DecodingKey::from_ed_components(x.trim_end_matches('=')),
+            (None, Some(der)) => Ok(DecodingKey::from_rsa_der(&der)),
+            // let jsonwebtoken report what's wrong with the key
+            (None, None) => DecodingKey::from_rsa_components(key.modulus(), key.exponent()),
+        };
+        Entry {
+            key,
+            decoding_key,
+            last_seen: Timestamp::now(),
+        }
+    }
+
+    fn decoding_key(&self) -> (&Jwk, Result<&DecodingKey, Error>) {
+        (&self.key, self.decoding_key.as_ref().map_err(Clone::clone))
+    }
+}
+
+impl std::fmt::Debug for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Entry")
+            .field("key", &self.key)
+            .field("decodable", &self.decoding_key.is_ok())
+            .field("last_seen", &self.last_seen)
+            .finish()
+    }
+}
+
+impl KeySet {
+    pub(crate) fn new(keys: Vec<Jwk>) -> Self {
+        KeySet {
+            keys: keys.into_iter().map(Entry::new).collect(),
+        }
+    }
+
+    /// The freshly fetched `keys`, plus the keys of `previous` that are no longer published but
+    /// were seen less than `grace` ago. Covers a source that briefly returns a partial set
+    /// during a key rotation.
+    pub(crate) fn merged(previous: Option<&KeySet>, keys: Vec<Jwk>, grace: Duration) -> Self {
+        let mut merged = KeySet::new(keys);
+        if let Some(previous) = previous {
+            let retained = previous.keys.iter().filter(|old| {
+                old.last_seen.elapsed() < grace
+                    && !merged.keys.iter().any(|new| new.key.kid == old.key.kid)
+            });
+            merged.keys.extend(retained.cloned().collect::<Vec<_>>());
+        }
+        merged
+    }
+
+    /// The key with the given `kid` and the `DecodingKey` to verify tokens with it.
+    pub(crate) fn find(&self, kid: &str) -> Option<(&Jwk, Result<&DecodingKey, Error>)> {
+        self.keys
+            .iter()
+            .find(|entry| entry.key.kid == kid)
+            .map(Entry::decoding_key)
+    }
+
+    /// Adds `key`, replacing a key with the same `kid`.
+    pub(crate) fn insert(&mut self, key: Jwk) {
+        self.remove(&key.kid);
+        self.keys.push(Entry::new(key));
+    }
+
+    /// Removes the key with the given `kid`, `false` if there was none.
+    pub(crate) fn remove(&mut self, kid: &str) -> bool {
+        let len = self.keys.len();
+        self.keys.retain(|entry| entry.key.kid != kid);
+        self.keys.len() != len
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// The only key of the set, `None` if there are none or several.
+    pub(crate) fn single(&self) -> Option<(&Jwk, Result<&DecodingKey, Error>)> {
+        match self.keys.as_slice() {
+            [entry] => Some(entry.decoding_key()),
+            _ => None,
+        }
+    }
+}
+
+/// Encodes the key as a PKCS#1 `RSAPublicKey`, `None` if the components aren't valid base64.
+fn rsa_public_key_der(key: &Jwk) -> Option<Vec<u8>> {
+    let n = B64.decode(key.modulus()).ok()?;
+    let e = B64.decode(key.exponent()).ok()?;
+
+    let mut body = der_integer(&n);
+    body.extend(der_integer(&e));
+    Some(der_tlv(0x30, &body))
+}
+
+fn der_integer(bytes: &[u8]) -> Vec<u8> {
+    let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+    let mut value = bytes[start..].to_vec();
+    // integers are signed, a leading 1 bit would make it negative
+    if value.first().is_none_or(|b| b & 0x80 != 0) {
+        value.insert(0, 0);
+    }
+    der_tlv(0x02, &value)
+}
+
+fn der_tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = value.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let len_bytes: Vec<u8> = len
+            .to_be_bytes()
+            .iter()
+            .copied()
+            .skip_while(|b| *b == 0)
+            .collect();
+        out.push(0x80 | len_bytes.len() as u8);
+        out.extend(len_bytes);
+    }
+    out.extend_from_slice(value);
+    out
+}
@@ -16,7 +16,7 @@
 //! 6. That the algorithm in the token header is the same as we use*
 //!
 //! * Note that we do NOT use the token header to set the algorithm for us, look [at this article
-//! for more information on why that would be bad](https://auth0.com/blog/critical-vulnerabilities-in-json-web-token-libraries/)
+//!   for more information on why that would be bad](https://auth0.com/blog/critical-vulnerabilities-in-json-web-token-libraries/)
 //!
 //! The validation will `Error` on a failed validation providing more granularity for library users
 //! to find out why the token was rejected.
@@ -92,15 +92,12 @@
 //! #         general_purpose::URL_SAFE.encode(&test_token_playload),
 //! #     ]
 //! #     .join(".");
-//! #     let signature = jwt::crypto::sign(&test_token, &private_key, jwt::Algorithm::RS256).expect("Signed");
-//! #     let public_key = Jwk {
-//! #         kid: "".to_string(),
-//! #         n: PUBLIC_KEY_N.to_string(),
-//! #         e: PUBLIC_KEY_E.to_string(),
-//! #     };
-//! #     let public_key = jwt::DecodingKey::from_rsa_components(&public_key.n, &public_key.e);
+//! #     let signature = jwt::crypto::sign(test_token.as_bytes(), &private_key, jwt::Algorithm::RS256).expect("Signed");
+//! #     let n = general_purpose::URL_SAFE_NO_PAD.decode(PUBLIC_KEY_N).unwrap();
+//! #     let e = general_purpose::URL_SAFE_NO_PAD.decode(PUBLIC_KEY_E).unwrap();
+//! #     let public_key = jwt::DecodingKey::from_rsa_raw_components(&n[1..], &e);
 //! #     let complete_token = format!("{}.{}", test_token, signature);
-//! #     let verified = jwt::crypto::verify(&signature, &test_token, &public_key, jwt::Algorithm::RS256)
+//! #     let verified = jwt::crypto::verify(&signature, test_token.as_bytes(), &public_key, jwt::Algorithm::RS256)
 //! #         .expect("verified");
 //! #     assert!(verified);
 //! #     complete_token
@@ -112,11 +109,7 @@
 //! # let n: &str = "AOx0GOQcSt5AZu02nlGWUuXXppxeV9Cu_9LcgpVBg_WQb-5DBHZpqs8AMek5u5iI4hkHCcOyMbQrBsDIVa9xxZxR2kq_8GtERsnd6NClQimspxT1WVgX5_WCAd5rk__Iv0GocP2c_1CcdT8is2OZHeWQySyQNSgyJYg6Up7kFtYabiCyU5q9tTIHQPXiwY53IGsNvSkqbk-OsdWPT3E4dqp3vNraMqXhuSZ-52kLCHqwPgAsbztfFJxSAEBcp-TS3uNuHeSJwNWjvDKTPy2oMacNpbsKb2gZgzubR6hTjvupRjaQ9SHhXyL9lmSZOpCzz2XJSVRopKUUtB-VGA0qVlk";
 //! # let e: &str = "AQAB";
 //! #
-//! # let key = Jwk {
-//! #         kid: "i6lGk3FZzxRcUb2C3nEQ7syHJlY".to_string(),
-//! #         n: n.to_string(),
-//! #         e: e.to_string(),
-//! #     };
+//! # let key = Jwk::new("i6lGk3FZzxRcUb2C3nEQ7syHJlY", n, e);
 //!  
 //!  let mut az_auth = AzureAuth::new("6e74172b-be56-4843-9ff4-e66a39bb12e3").await.unwrap();
 //!  
@@ -146,20 +139,70 @@
 //!     Ok(())
 //!  }
 //! ```
+//!
+//! `AzureAuthHandle` does this wrapping for you and can be kept in a `static OnceLock`.
 
-use async_recursion::async_recursion;
-use chrono::{Duration, Local, NaiveDateTime};
 use jsonwebtoken as jwt;
-use jwt::DecodingKey;
-use reqwest::{self, Response};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
 
+mod cache;
+mod cert;
+mod clock;
+#[cfg(feature = "azure-claims")]
+mod detailed;
+mod discovery;
 mod error;
+#[cfg(feature = "azure-claims")]
+mod handle;
+#[cfg(feature = "azure-claims")]
+mod inspect;
+#[cfg(feature = "introspection")]
+mod introspection;
+mod keyset;
+mod oidc;
+#[cfg(feature = "azure-claims")]
+mod outcome;
+#[cfg(feature = "azure-claims")]
+mod principal;
+mod source;
+mod telemetry;
+pub use cache::CacheStatus;
+use cache::{TenantCache, TenantKeys, DEFAULT_MAX_CACHED_TENANTS};
+use clock::Timestamp;
+#[cfg(feature = "azure-claims")]
+pub use detailed::{DetailedValidation, ValidationFailure};
+pub use discovery::OpenIdConfiguration;
 pub use error::AuthErr;
+#[cfg(feature = "azure-claims")]
+pub use handle::AzureAuthHandle;
+#[cfg(feature = "azure-claims")]
+pub use inspect::TokenInspection;
+#[cfg(feature = "introspection")]
+pub use introspection::{IntrospectionClient, IntrospectionResponse};
+use keyset::KeySet;
+pub use oidc::OidcValidator;
+#[cfg(feature = "azure-claims")]
+use oidc::{DEFAULT_LEEWAY, EXPIRES_SOON};
+#[cfg(feature = "azure-claims")]
+pub use outcome::{ValidationDiagnostics, ValidationOutcome, ValidationWarning};
+#[cfg(feature = "azure-claims")]
+pub use principal::Principal;
+#[cfg(feature = "azure-claims")]
 use serde_aux::field_attributes::deserialize_number_from_string;
+pub use source::{JwksFetcher, KeySource};
 
-const AZ_OPENID_URL: &str =
-    "https://login.microsoftonline.com/common/.well-known/openid-configuration";
+const AZ_LOGIN_URL: &str = "https://login.microsoftonline.com";
+const AZ_DEFAULT_TENANT: &str = "common";
+/// The tenant of personal Microsoft accounts (outlook.com, xbox.com, ...). It's the `tid` of
+/// their tokens, see `AzureJwtClaims::is_personal_account`.
+pub const CONSUMER_TENANT_ID: &str = "9188040d-6c67-4c5b-b112-36a304b66dad";
+/// The audience of access tokens for Microsoft Graph in v1.0 tokens, see
+/// `AzureAuth::set_accept_graph_audience`.
+pub const GRAPH_AUDIENCE: &str = "https://graph.microsoft.com";
+/// The application ID of Microsoft Graph, the audience of its access tokens in v2.0 tokens.
+pub const GRAPH_APP_ID: &str = "00000003-0000-0000-c000-000000000000";
 
 /// AzureAuth is the what you'll use to validate your token.
 ///
@@ -167,12 +210,31 @@ const AZ_OPENID_URL: &str =
 ///
 /// - Public key expiration: dafault set to 24h, use `set_expiration` to set a different expiration
 ///   in hours.
-/// - Hashing algorithm: Sha256, you can't change this setting. Submit an issue in the github repo
-///   if this is important to you
-/// - Retry on no match. If no matching key is found and our keys are older than an hour, we
-///   refresh the keys and try once more. Limited to once in an hour. You can disable this by
-///   calling `set_no_retry()`.
-/// - The timestamps are given a 60s "leeway" to account for time skew between servers
+/// - Hashing algorithm: RS256, use `set_algorithms` to accept others, i.e. `EdDSA`.
+/// - Retry on no match. If no matching key is found, we refresh the keys and try once more, even
+///   if they were only just fetched. Limited to once a minute, use `set_min_refresh_interval`
+///   to change this. You can disable this by calling `set_no_retry()`.
+/// - At most one successful fetch from Microsoft every 30s. Requests for a refresh within that
+///   time reuse the cached metadata and keys, use `set_min_fetch_interval` to change this. The
+///   retry on no match isn't held back by it.
+/// - The timestamps are given a 60s "leeway" to account for time skew between servers. Use
+///   `set_leeway` for `exp`/`nbf`, `set_exp_leeway`/`set_nbf_leeway` for either one and
+///   `set_iat_leeway` for `iat` to change it.
+/// - The `iss` claim is validated against the issuer advertised in the OpenID metadata for the
+///   token's version (v1.0 or v2.0). Not validated in offline mode since we have no metadata.
+/// - Single tenant. Use `new_multi_tenant` to accept tokens from a set of tenants with one
+///   instance.
+///
+/// The fetching and verifying itself is done by an `OidcValidator`, which you can use directly
+/// for tokens from other OpenID Connect providers.
+///
+/// # Lifecycle
+///
+/// `AzureAuth` never spawns tasks. Keys and metadata are only fetched from within the calls you
+/// make, on the task that makes them, so there is nothing to shut down: dropping the instance
+/// (or cancelling a pending validation) frees everything it holds. The only thread is the file
+/// watcher of `from_jwks_file_watched`, stopped when the last clone of the instance is dropped.
+/// If you want to refresh in the background, drive it from a task you own.
 ///
 /// # Errors
 ///
@@ -182,17 +244,51 @@ const AZ_OPENID_URL: &str =
 /// - If the token is invalid
 #[derive(Debug, Clone)]
 pub struct AzureAuth {
-    aud_to_val: String,
-    jwks_uri: String,
-    public_keys: Option<Vec<Jwk>>,
-    last_refresh: Option<NaiveDateTime>,
-    exp_hours: i64,
-    retry_counter: u32,
-    is_retry_enabled: bool,
-    is_offline: bool,
+    oidc: OidcValidator,
+    tenant: String,
+    /// The cloud the metadata of our tenants is fetched from, `AZ_LOGIN_URL` unless `from_env`
+    /// read another one.
+    authority_host: String,
+    discovery_v2: Option<OpenIdConfiguration>,
+    #[cfg(feature = "azure-claims")]
+    iat_leeway: u64,
+    /// `None` uses the leeway of `exp`, see `set_nbf_leeway`.
+    #[cfg(feature = "azure-claims")]
+    nbf_leeway: Option<u64>,
+    #[cfg(feature = "azure-claims")]
+    required_version: Option<TokenVersion>,
+    allowed_tenants: Option<Vec<String>>,
+    #[cfg(feature = "azure-claims")]
+    allow_personal_accounts: bool,
+    #[cfg(feature = "azure-claims")]
+    allowed_idps: Option<Vec<String>>,
+    #[cfg(feature = "azure-claims")]
+    required_scopes: Vec<String>,
+    #[cfg(feature = "azure-claims")]
+    revocation_check: Option<RevocationCheck>,
+    #[cfg(feature = "azure-claims")]
+    audience_resolver: Option<AudienceResolver>,
+    #[cfg(feature = "azure-claims")]
+    strict_audience: bool,
+    #[cfg(feature = "azure-claims")]
+    accept_graph_audience: bool,
+    #[cfg(feature = "azure-claims")]
+    validate_issuer: bool,
+    tenant_cache: TenantCache,
+    /// Validators for `validate_with_metadata_url`, by metadata url.
+    providers: HashMap<String, OidcValidator>,
 }
 
 impl AzureAuth {
+    /// The version of this crate, worth including when reporting an issue. Requests to
+    /// Microsoft are sent with it in the `User-Agent`, `azure_jwt_async/<version>`.
+    pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+
+    /// The version of this crate, see `VERSION`.
+    pub fn version() -> &'static str {
+        AzureAuth::VERSION
+    }
+
     /// Creates a new dafault instance. This method will call the Microsoft apis to fetch the current keys
     /// which can fail. The public keys are fetched since we need them to perform
     /// verification. Please note that fetching the OpenID manifest and public keys are quite slow
@@ -205,46 +301,784 @@ impl AzureAuth {
     ///
     /// If there is a connection issue to the Microsoft APIs.
     pub async fn new(aud: impl Into<String>) -> Result<Self, AuthErr> {
-        Ok(AzureAuth {
-            aud_to_val: aud.into(),
-            jwks_uri: AzureAuth::get_jwks_uri().await?,
-            public_keys: None,
-            last_refresh: None,
-            exp_hours: 24,
-            retry_counter: 0,
-            is_retry_enabled: true,
-            is_offline: false,
-        })
+        let url = AzureAuth::metadata_url(AZ_LOGIN_URL, AZ_DEFAULT_TENANT);
+        let discovery = oidc::fetch_discovery(oidc::shared_client()?, &url).await?;
+        AzureAuth::with_defaults(aud.into(), Some(discovery), None, false)
+    }
+
+    /// Creates an instance configured from environment variables, like `new` otherwise:
+    ///
+    /// - `AZURE_CLIENT_ID` (required): the audience, your app's Application ID.
+    /// - `AZURE_TENANT_ID` (optional): the tenant, `common` if unset.
+    /// - `AZURE_AUTHORITY_HOST` (optional): the cloud, `https://login.microsoftonline.com` if
+    ///   unset. Set it for a sovereign cloud, i.e. `https://login.microsoftonline.us` or
+    ///   `https://login.chinacloudapi.cn`. The OpenID metadata of the tenant is fetched from it,
+    ///   and the public keys from the `jwks_uri` that metadata advertises.
+    ///
+    /// Variables set to an empty string count as unset. Everything else can be changed with the
+    /// setters afterwards, which take precedence since they're applied last: a `set_jwks_uri`
+    /// fetches the keys from there whatever the cloud, and `set_tenant` looks up the new tenant
+    /// in the same cloud.
+    ///
+    /// # Errors
+    ///
+    /// `AuthErr::Other` naming the variable if `AZURE_CLIENT_ID` is missing, a variable isn't
+    /// valid unicode or `AZURE_AUTHORITY_HOST` isn't an `https` url. Otherwise the errors of
+    /// `new`.
+    pub async fn from_env() -> Result<Self, AuthErr> {
+        let vars = EnvConfig::from_vars(env_var)?;
+
+        let url = AzureAuth::metadata_url(&vars.authority_host, &vars.tenant);
+        let discovery = oidc::fetch_discovery(oidc::shared_client()?, &url).await?;
+        let mut az_auth = AzureAuth::with_defaults(vars.audience, Some(discovery), None, false)?;
+        az_auth.oidc.metadata_url = Some(url);
+        az_auth.tenant = vars.tenant;
+        az_auth.authority_host = vars.authority_host;
+        Ok(az_auth)
+    }
+
+    /// Validates offline against the JWK set in the file at `path`, i.e. one distributed as a
+    /// mounted secret, and reloads it as soon as the file changes. This combines never calling
+    /// Microsoft with picking up rotated keys without a restart. The directory of the file is
+    /// watched with `notify`, so replacing the file through a symlink swap is picked up too.
+    ///
+    /// The watcher runs on its own thread, which is stopped when the instance and all its
+    /// clones are dropped.
+    ///
+    /// # Errors
+    ///
+    /// `AuthErr::Other` if the file can't be read or watched, `AuthErr::ParseError` if it isn't
+    /// a JWK set.
+    #[cfg(feature = "watch")]
+    pub fn from_jwks_file_watched(
+        aud: impl Into<String>,
+        path: impl Into<std::path::PathBuf>,
+    ) -> Result<Self, AuthErr> {
+        let source = source::WatchedJwksFile::new(path)?;
+        let keys = source.read()?;
+        let mut az_auth = AzureAuth::with_defaults(aud.into(), None, Some(keys), true)?;
+        az_auth.oidc.key_source = Some(std::sync::Arc::new(source));
+        Ok(az_auth)
+    }
+
+    /// The tenant (`tid` claim) of a token, to route it to the validator of its tenant when you
+    /// keep one per tenant. This is the same lookup multi-tenant mode does.
+    ///
+    /// **The tenant is unverified.** Anyone can put any `tid` in a token, it can only be
+    /// trusted once the token is validated. Use it to pick the validator, never for access
+    /// decisions.
+    ///
+    /// # Errors
+    ///
+    /// `AuthErr::MalformedToken` if the token isn't a JWT, `AuthErr::ParseError` if it has no
+    /// `tid` claim.
+    pub fn tenant_from_token(token: &str) -> Result<String, AuthErr> {
+        unverified_tenant(token)
+    }
+
+    /// Decodes the header and claims of a token for diagnostics, i.e. to find out why it's
+    /// rejected or in admin tooling. Nothing is fetched.
+    ///
+    /// **This does not validate the token.** The signature isn't checked, so anyone can make a
+    /// token that inspects to anything. Never base access decisions on it.
+    ///
+    /// # Errors
+    ///
+    /// `AuthErr::MalformedToken` if the header or the payload can't be decoded.
+    #[cfg(feature = "azure-claims")]
+    pub fn inspect(token: &str) -> Result<TokenInspection, AuthErr> {
+        inspect::inspect(token)
     }
 
     /// Does not call the Microsoft openid configuration endpoint or fetches the JWK set.
-    /// Use this if you want to handle updating the public keys yourself
+    /// Use this if you want to handle updating the public keys yourself, build them with
+    /// `Jwk::new`.
     pub fn new_offline(aud: impl Into<String>, public_keys: Vec<Jwk>) -> Result<Self, AuthErr> {
+        AzureAuth::with_defaults(aud.into(), None, Some(public_keys), true)
+    }
+
+    /// Like `new_offline`, but with the signing certificates instead of a JWK set, for keys
+    /// distributed out of band as PEM files. `certs` are pairs of the `kid` and the PEM encoded
+    /// X.509 certificate (`-----BEGIN CERTIFICATE-----`). Only the RSA public key of each
+    /// certificate is used, the certificate itself isn't validated.
+    ///
+    /// # Errors
+    ///
+    /// `AuthErr::Other` if a certificate isn't a single PEM block or has no RSA public key.
+    pub fn from_pem_certs(
+        aud: impl Into<String>,
+        certs: Vec<(String, String)>,
+    ) -> Result<Self, AuthErr> {
+        let keys = certs
+            .into_iter()
+            .map(|(kid, pem)| cert::jwk_from_pem_cert(kid, &pem))
+            .collect::<Result<_, _>>()?;
+        AzureAuth::new_offline(aud, keys)
+    }
+
+    /// Validates offline against the single RSA public key `pem`, registered as `kid`, i.e. for
+    /// tests or services mocking Azure AD with a self-signed key. `pem` is a PEM encoded public
+    /// key (`-----BEGIN PUBLIC KEY-----` or `-----BEGIN RSA PUBLIC KEY-----`) or certificate
+    /// (`-----BEGIN CERTIFICATE-----`).
+    ///
+    /// # Errors
+    ///
+    /// `AuthErr::Other` if `pem` isn't a single PEM block of one of these or has no RSA key.
+    pub fn from_public_key_pem(
+        aud: impl Into<String>,
+        kid: impl Into<String>,
+        pem: &str,
+    ) -> Result<Self, AuthErr> {
+        let key = cert::jwk_from_pem(kid.into(), pem)?;
+        AzureAuth::new_offline(aud, vec![key])
+    }
+
+    /// Starts from an OpenID metadata document you fetched yourself, i.e. injected by a sidecar
+    /// or a central config service, so the metadata is never fetched. With `keys` the public
+    /// keys aren't fetched either until they expire, without them they're fetched from the
+    /// `jwks_uri` of `discovery` on the first validation.
+    ///
+    /// `discovery` is the v1.0 document. The issuer of v2.0 tokens is validated against the
+    /// v2.0 document, inject it with `set_discovery_v2` or it's fetched for the first v2.0 token.
+    pub fn from_discovery(
+        aud: impl Into<String>,
+        discovery: OpenIdConfiguration,
+        keys: Option<Vec<Jwk>>,
+    ) -> Result<Self, AuthErr> {
+        AzureAuth::with_defaults(aud.into(), Some(discovery), keys, false)
+    }
+
+    /// Starts with the given public keys, as if they were just fetched from `jwks_uri`, but
+    /// otherwise behaves like `new`: the keys are refreshed from `jwks_uri` when they expire or
+    /// a token has an unknown `kid`. Use this to seed the keys at startup and skip the initial
+    /// round trips to Microsoft.
+    ///
+    /// Since the OpenID metadata isn't fetched, the `iss` claim isn't validated until you call
+    /// `refresh_rwks_uri`.
+    pub fn new_seeded(
+        aud: impl Into<String>,
+        jwks_uri: impl Into<String>,
+        public_keys: Vec<Jwk>,
+    ) -> Result<Self, AuthErr> {
+        let mut az_auth = AzureAuth::with_defaults(aud.into(), None, Some(public_keys), false)?;
+        az_auth.oidc.jwks_uri = jwks_uri.into();
+        Ok(az_auth)
+    }
+
+    /// Creates an instance that accepts tokens issued by any of the given tenants. The tenant
+    /// of a token is read from its (not yet verified) `tid` claim and used to look up the OpenID
+    /// metadata and public keys of that tenant, which are then used to verify the token. Tokens
+    /// from tenants not in `tenants` are rejected with `AuthErr::TenantNotAllowed`.
+    ///
+    /// Nothing is fetched up front, the keys of a tenant are fetched the first time we see a
+    /// token from it and cached from then on. At most 64 tenants are cached by default, see
+    /// `set_max_cached_key_sets`.
+    pub fn new_multi_tenant(aud: impl Into<String>, tenants: Vec<String>) -> Result<Self, AuthErr> {
+        let mut az_auth = AzureAuth::with_defaults(aud.into(), None, None, false)?;
+        az_auth.allowed_tenants = Some(tenants);
+        Ok(az_auth)
+    }
+
+    fn with_defaults(
+        aud: String,
+        discovery: Option<OpenIdConfiguration>,
+        public_keys: Option<Vec<Jwk>>,
+        is_offline: bool,
+    ) -> Result<Self, AuthErr> {
+        if let Err(e) = check_audience_format(&aud) {
+            telemetry::suspicious_audience(&e);
+        }
+        // the issuer depends on the token version and tenant, so we check it ourselves
+        let mut oidc = OidcValidator::with_defaults(aud, discovery, public_keys, is_offline)?;
+        oidc.metadata_url = Some(AzureAuth::metadata_url(AZ_LOGIN_URL, AZ_DEFAULT_TENANT));
         Ok(AzureAuth {
-            aud_to_val: aud.into(),
-            jwks_uri: String::new(),
-            public_keys: Some(public_keys),
-            last_refresh: Some(Local::now().naive_local()),
-            exp_hours: 24,
-            retry_counter: 0,
-            is_retry_enabled: true,
-            is_offline: true,
+            oidc,
+            tenant: AZ_DEFAULT_TENANT.to_string(),
+            authority_host: AZ_LOGIN_URL.to_string(),
+            discovery_v2: None,
+            #[cfg(feature = "azure-claims")]
+            iat_leeway: DEFAULT_LEEWAY,
+            #[cfg(feature = "azure-claims")]
+            nbf_leeway: None,
+            #[cfg(feature = "azure-claims")]
+            required_version: None,
+            allowed_tenants: None,
+            #[cfg(feature = "azure-claims")]
+            allow_personal_accounts: true,
+            #[cfg(feature = "azure-claims")]
+            allowed_idps: None,
+            #[cfg(feature = "azure-claims")]
+            required_scopes: Vec::new(),
+            #[cfg(feature = "azure-claims")]
+            revocation_check: None,
+            #[cfg(feature = "azure-claims")]
+            audience_resolver: None,
+            #[cfg(feature = "azure-claims")]
+            strict_audience: false,
+            #[cfg(feature = "azure-claims")]
+            accept_graph_audience: false,
+            #[cfg(feature = "azure-claims")]
+            validate_issuer: true,
+            tenant_cache: TenantCache::new(DEFAULT_MAX_CACHED_TENANTS),
+            providers: HashMap::new(),
         })
     }
 
     /// Dafault validation, see `AzureAuth` documentation for the defaults.
+    #[cfg(feature = "azure-claims")]
     pub async fn validate_token(&mut self, token: &str) -> Result<Token<AzureJwtClaims>, AuthErr> {
-        let mut validator = jwt::Validation::new(jwt::Algorithm::RS256);
+        let result = self.validate_default(token).await;
+        telemetry::validation(token, &result);
+        result
+    }
+
+    /// Same as `validate_token`, for a token you have as bytes, i.e. straight from a header
+    /// buffer. The bytes are checked to be UTF-8 in place, nothing is copied.
+    ///
+    /// # Errors
+    ///
+    /// `AuthErr::MalformedToken` if `token` isn't UTF-8, otherwise the errors of
+    /// `validate_token`.
+    #[cfg(feature = "azure-claims")]
+    pub async fn validate_token_bytes(
+        &mut self,
+        token: &[u8],
+    ) -> Result<Token<AzureJwtClaims>, AuthErr> {
+        let token = std::str::from_utf8(token)
+            .map_err(|_| AuthErr::MalformedToken("The token isn't UTF-8.".into()))?;
+        self.validate_token(token).await
+    }
 
-        // exp, nbf, iat is set to validate as default
-        validator.leeway = 60;
-        validator.set_audience(&[&self.aud_to_val]);
+    #[cfg(feature = "azure-claims")]
+    async fn validate_default(&mut self, token: &str) -> Result<Token<AzureJwtClaims>, AuthErr> {
+        self.check_strict_audience()?;
+        let validator = self.default_validation();
         let decoded: Token<AzureJwtClaims> =
             self.validate_token_authenticity(token, &validator).await?;
 
+        if self.needs_discovery_v2(&decoded.claims) {
+            if self.oidc.is_network_disabled {
+                return Err(AuthErr::KeysStale);
+            }
+            self.discovery_v2 = Some(
+                AzureAuth::get_discovery_v2(&self.oidc, &self.authority_host, &self.tenant).await?,
+            );
+        }
+        self.validate_claims(&decoded.claims)?;
+
+        Ok(decoded)
+    }
+
+    /// Like `validate_token`, but instead of failing on the first failed check it runs all of
+    /// them and returns the claims together with every check that failed, i.e. by how much a
+    /// token is expired. Useful to diagnose clock skew. Use `DetailedValidation::into_result`
+    /// to get the token, which fails unless every check passed.
+    ///
+    /// # Errors
+    ///
+    /// If the token isn't authentic or can't be parsed, in which case we know nothing about its
+    /// claims, with the same errors as `validate_token`.
+    #[cfg(feature = "azure-claims")]
+    pub async fn validate_detailed(&mut self, token: &str) -> Result<DetailedValidation, AuthErr> {
+        self.check_strict_audience()?;
+        let mut validator = self.default_validation();
+        validator.validate_exp = false;
+        validator.validate_nbf = false;
+        validator.aud = None;
+
+        let decoded: Token<AzureJwtClaims> =
+            self.validate_token_authenticity(token, &validator).await?;
+
+        if self.needs_discovery_v2(&decoded.claims) {
+            if self.oidc.is_network_disabled {
+                return Err(AuthErr::KeysStale);
+            }
+            self.discovery_v2 = Some(
+                AzureAuth::get_discovery_v2(&self.oidc, &self.authority_host, &self.tenant).await?,
+            );
+        }
+
+        let failures = self.claim_failures(&decoded.claims, true);
+        Ok(DetailedValidation {
+            token: decoded,
+            failures,
+        })
+    }
+
+    /// Default validation, like `validate_token`, that also reports what's worth knowing about
+    /// an accepted token without failing it: whether the keys had to be refreshed for it, or
+    /// whether it's about to expire or only accepted thanks to the leeway. Feed the warnings to
+    /// your metrics or logs to spot misbehaving clients before their tokens are rejected.
+    ///
+    /// # Errors
+    ///
+    /// The same errors as `validate_token`.
+    #[cfg(feature = "azure-claims")]
+    pub async fn validate_with_warnings(
+        &mut self,
+        token: &str,
+    ) -> Result<ValidationOutcome, AuthErr> {
+        let refresh_count = self.oidc.refresh_count;
+        let token = self.validate_token(token).await?;
+
+        let mut warnings = Vec::new();
+        if self.oidc.refresh_count != refresh_count {
+            warnings.push(ValidationWarning::KeysRefreshed);
+        }
+        let left = token.claims.exp as i64 - Timestamp::now().unix();
+        if left < 0 {
+            warnings.push(ValidationWarning::ExpiredWithinLeeway {
+                ago: Duration::from_secs(left.unsigned_abs()),
+            });
+        } else if left < EXPIRES_SOON as i64 {
+            warnings.push(ValidationWarning::ExpiresSoon {
+                remaining: Duration::from_secs(left as u64),
+            });
+        }
+
+        Ok(ValidationOutcome { token, warnings })
+    }
+
+    /// Default validation, like `validate_token`, that also tells what it did with the keys:
+    /// whether they were fetched, whether the token's `kid` was unknown so we retried, and
+    /// whether the next retry is allowed yet. Meant for understanding key rotations in
+    /// production, the diagnostics come with failed validations too since that's when they're
+    /// most interesting.
+    #[cfg(feature = "azure-claims")]
+    pub async fn validate_with_diagnostics(
+        &mut self,
+        token: &str,
+    ) -> (
+        Result<Token<AzureJwtClaims>, AuthErr>,
+        ValidationDiagnostics,
+    ) {
+        let (refresh_count, retry_count) = (self.oidc.refresh_count, self.oidc.retry_count);
+        let result = self.validate_token(token).await;
+
+        let oidc = &self.oidc;
+        let diagnostics = ValidationDiagnostics {
+            keys_refreshed: oidc.refresh_count != refresh_count,
+            retried: oidc.retry_count != retry_count,
+            retry_available: oidc.should_retry(),
+            last_retry: oidc.last_retry.map(SystemTime::from),
+        };
+        (result, diagnostics)
+    }
+
+    /// Validates a token of another OpenID provider, i.e. in a gateway in front of several
+    /// identity providers, without an instance for each of them. The metadata at
+    /// `metadata_url` and its keys are fetched on the first call and cached per url, the keys
+    /// are refreshed like ours. The token is checked like `OidcValidator::validate` does: our
+    /// audience, the issuer of the metadata, `exp` and `nbf` with our leeway. The Azure
+    /// specific checks, such as the allowed tenants, don't apply. Our validation and refresh
+    /// settings apply to every provider, also those added before a setter was called.
+    ///
+    /// Only pass urls from your own configuration, never one derived from the token, or anyone
+    /// can make you trust their own keys.
+    ///
+    /// # Errors
+    ///
+    /// If the metadata can't be fetched, `AuthErr::KeysStale` if it would have to be fetched
+    /// with `set_network_disabled`, otherwise the errors of `OidcValidator::validate`.
+    pub async fn validate_with_metadata_url<T>(
+        &mut self,
+        token: &str,
+        metadata_url: &str,
+    ) -> Result<Token<T>, AuthErr>
+    where
+        for<'de> T: Serialize + Deserialize<'de>,
+    {
+        self.oidc.check_size(token)?;
+        if !self.providers.contains_key(metadata_url) {
+            if self.oidc.is_network_disabled {
+                return Err(AuthErr::KeysStale);
+            }
+            let provider =
+                OidcValidator::from_metadata_url(metadata_url, self.oidc.audience.clone()).await?;
+            self.providers.insert(metadata_url.to_string(), provider);
+        }
+        match self.providers.get_mut(metadata_url) {
+            Some(provider) => {
+                // our settings may have changed since the provider was added
+                provider.take_settings_of(&self.oidc);
+                provider.validate(token).await
+            }
+            None => Err(AuthErr::Other(
+                "Internal err. No validator for the url.".into(),
+            )),
+        }
+    }
+
+    /// Default validation against the keys we currently have, without ever refreshing them or
+    /// fetching metadata. Since this only needs `&self`, many validations can run concurrently
+    /// (i.e. behind a `RwLock`) while refreshing is left to a separate `&mut self` call such as
+    /// `refresh_rwks_uri`, `set_public_keys` or a regular `validate_token`.
+    ///
+    /// # Errors
+    ///
+    /// `AuthErr::KeysStale` if `validate_token` would have to fetch something to validate this
+    /// token: the keys are expired, the token's `kid` is unknown and a refresh is allowed, the
+    /// v2.0 metadata hasn't been fetched yet or, in multi-tenant mode, the token is from another
+    /// tenant than the one currently loaded. Otherwise the same errors as `validate_token`.
+    #[cfg(feature = "azure-claims")]
+    pub fn try_validate(&self, token: &str) -> Result<Token<AzureJwtClaims>, AuthErr> {
+        self.check_strict_audience()?;
+        if let Some(tenants) = &self.allowed_tenants {
+            self.oidc.check_size(token)?;
+            let tenant = unverified_tenant(token)?;
+            if !tenants.iter().any(|t| t.eq_ignore_ascii_case(&tenant)) {
+                return Err(AuthErr::TenantNotAllowed(tenant));
+            }
+            if !tenant.eq_ignore_ascii_case(&self.tenant) || self.oidc.jwks_uri.is_empty() {
+                return Err(AuthErr::KeysStale);
+            }
+        }
+
+        if !self.oidc.is_keys_valid() && !self.oidc.is_offline {
+            return Err(AuthErr::KeysStale);
+        }
+
+        let validator = self.default_validation();
+        let decoded: Token<AzureJwtClaims> =
+            match self.oidc.decode_with_current_keys(token, &validator) {
+                Err(AuthErr::KeyNotFound(_)) if self.oidc.should_retry() => {
+                    return Err(AuthErr::KeysStale)
+                }
+                decoded => decoded?,
+            };
+
+        if self.needs_discovery_v2(&decoded.claims) {
+            return Err(AuthErr::KeysStale);
+        }
+        self.validate_claims(&decoded.claims)?;
+
         Ok(decoded)
     }
 
+    #[cfg(feature = "azure-claims")]
+    fn default_validation(&self) -> jwt::Validation {
+        // exp is validated by jsonwebtoken, nbf and iat are checked in `validate_claims` since
+        // nbf can have its own leeway
+        let mut validator = self.oidc.default_validation();
+        validator.validate_nbf = false;
+        if self.audience_resolver.is_some() {
+            // the audiences depend on the claims, checked in `validate_claims`
+            validator.aud = None;
+        }
+        if let (true, Some(aud)) = (self.accept_graph_audience, &mut validator.aud) {
+            aud.extend([GRAPH_AUDIENCE.to_string(), GRAPH_APP_ID.to_string()]);
+        }
+        validator
+    }
+
+    /// Whether `aud` is a Graph audience we accept, see `set_accept_graph_audience`.
+    #[cfg(feature = "azure-claims")]
+    fn is_graph_audience(&self, aud: &str) -> bool {
+        self.accept_graph_audience
+            && [GRAPH_AUDIENCE, GRAPH_APP_ID]
+                .iter()
+                .any(|graph| self.oidc.audience_eq(graph, aud))
+    }
+
+    /// The checks of the default validation that jsonwebtoken doesn't do for us. Expects the
+    /// token to be verified already.
+    #[cfg(feature = "azure-claims")]
+    fn validate_claims(&self, claims: &AzureJwtClaims) -> Result<(), AuthErr> {
+        match self.claim_failures(claims, false).into_iter().next() {
+            Some(failure) => Err(failure.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Every check of the default validation the claims fail. The `exp` and `aud` checks are
+    /// usually done by jsonwebtoken and only included with `with_jwt_checks`.
+    #[cfg(feature = "azure-claims")]
+    fn claim_failures(
+        &self,
+        claims: &AzureJwtClaims,
+        with_jwt_checks: bool,
+    ) -> Vec<ValidationFailure> {
+        let now = Timestamp::now().unix();
+        let secs = |secs: i64| Duration::from_secs(secs as u64);
+        let mut failures = Vec::new();
+
+        if with_jwt_checks {
+            let leeway = self.oidc.leeway as i64;
+            if now > claims.exp as i64 + leeway {
+                failures.push(ValidationFailure::Expired {
+                    ago: secs(now - claims.exp as i64),
+                });
+            }
+            if self.oidc.validate_audience
+                && self.audience_resolver.is_none()
+                && !self.oidc.is_audience(&claims.aud)
+                && !self.is_graph_audience(&claims.aud)
+            {
+                failures.push(ValidationFailure::InvalidAudience {
+                    expected: self.oidc.audience.clone(),
+                    found: claims.aud.clone(),
+                });
+            }
+        }
+
+        if let Some(AudienceResolver(resolve)) = &self.audience_resolver {
+            let audiences = resolve(claims);
+            if self.oidc.validate_audience
+                && !audiences
+                    .iter()
+                    .any(|aud| self.oidc.audience_eq(aud, &claims.aud))
+                && !self.is_graph_audience(&claims.aud)
+            {
+                failures.push(ValidationFailure::InvalidAudience {
+                    expected: audiences.join(", "),
+                    found: claims.aud.clone(),
+                });
+            }
+        }
+
+        // jsonwebtoken doesn't check `nbf` by default and would with the leeway of `exp`
+        if claims.nbf as i64 > now + self.nbf_leeway() as i64 {
+            failures.push(ValidationFailure::NotYetValid {
+                starts_in: secs(claims.nbf as i64 - now),
+            });
+        }
+
+        // jsonwebtoken doesn't check `iat` so we make sure the token isn't issued in the future
+        if claims.iat as i64 > now + self.iat_leeway as i64 {
+            failures.push(ValidationFailure::IssuedInFuture {
+                by: secs(claims.iat as i64 - now),
+            });
+        }
+
+        if let Some(expected) = self.required_version {
+            if claims.version() != Some(expected) {
+                failures.push(ValidationFailure::UnexpectedTokenVersion {
+                    expected,
+                    found: claims.ver.clone().unwrap_or_default(),
+                });
+            }
+        }
+
+        if !self.allow_personal_accounts && claims.is_personal_account() {
+            failures.push(ValidationFailure::PersonalAccountNotAllowed);
+        }
+
+        if let Some(allowed) = &self.allowed_idps {
+            let idp = claims.idp.as_ref().unwrap_or(&claims.iss);
+            if !allowed.contains(idp) {
+                failures.push(ValidationFailure::IdpNotAllowed { found: idp.clone() });
+            }
+        }
+
+        if let Some(expected) = self.expected_issuer(claims) {
+            if claims.iss != expected {
+                failures.push(ValidationFailure::InvalidIssuer {
+                    expected,
+                    found: claims.iss.clone(),
+                });
+            }
+        }
+
+        let scopes = claims.scopes();
+        for scope in &self.required_scopes {
+            if !scopes.contains(&scope.as_str()) {
+                failures.push(ValidationFailure::MissingScope {
+                    name: scope.clone(),
+                });
+            }
+        }
+
+        if let Some(RevocationCheck(is_revoked)) = &self.revocation_check {
+            if is_revoked(claims) {
+                failures.push(ValidationFailure::Revoked);
+            }
+        }
+
+        failures
+    }
+
+    /// Whether we need to fetch the v2.0 metadata document to know the issuer of this token.
+    #[cfg(feature = "azure-claims")]
+    fn needs_discovery_v2(&self, claims: &AzureJwtClaims) -> bool {
+        self.validate_issuer
+            && claims.version() == Some(TokenVersion::V2)
+            && self.oidc.discovery.is_some()
+            && self.discovery_v2.is_none()
+    }
+
+    /// The issuer a token should have according to the OpenID metadata of its version, with the
+    /// `{tenantid}` placeholder of the `common` documents replaced by the token's `tid`. `None`
+    /// if we have no metadata, i.e. in offline mode, or `set_validate_issuer` turned the check
+    /// off, in which case the issuer isn't validated.
+    #[cfg(feature = "azure-claims")]
+    fn expected_issuer(&self, claims: &AzureJwtClaims) -> Option<String> {
+        if !self.validate_issuer {
+            return None;
+        }
+        let discovery = if claims.version() == Some(TokenVersion::V2) {
+            &self.discovery_v2
+        } else {
+            &self.oidc.discovery
+        };
+
+        discovery
+            .as_ref()
+            .map(|d| d.issuer.replace("{tenantid}", &claims.tid))
+    }
+
+    /// Like `validate_token` for a token that's already split into its three base64url segments,
+    /// i.e. by an upstream parser. The parts are checked to be well-formed and then validated as
+    /// one token.
+    ///
+    /// # Errors
+    ///
+    /// `AuthErr::MalformedToken` if one of the parts is empty or not base64url, otherwise the
+    /// same errors as `validate_token`.
+    #[cfg(feature = "azure-claims")]
+    pub async fn validate_parts(
+        &mut self,
+        header_b64: &str,
+        payload_b64: &str,
+        signature_b64: &str,
+    ) -> Result<Token<AzureJwtClaims>, AuthErr> {
+        for (part, name) in [
+            (header_b64, "header"),
+            (payload_b64, "payload"),
+            (signature_b64, "signature"),
+        ] {
+            if !oidc::is_base64url_segment(part) {
+                return Err(AuthErr::MalformedToken(format!(
+                    "The {} must be non-empty base64url.",
+                    name
+                )));
+            }
+        }
+        let token = [header_b64, payload_b64, signature_b64].join(".");
+        self.validate_token(&token).await
+    }
+
+    /// Like `validate_token`, but returns the header of the token together with its claims, for
+    /// when you need the `kid` or `alg` for auditing or routing. The header is the one the
+    /// signature was verified against, not parsed again.
+    #[cfg(feature = "azure-claims")]
+    pub async fn validate_full(
+        &mut self,
+        token: &str,
+    ) -> Result<(AzureJwtHeader, AzureJwtClaims), AuthErr> {
+        let decoded = self.validate_token(token).await?;
+        Ok((decoded.header.into(), decoded.claims))
+    }
+
+    /// Default validation, and additionally requires the `roles` claim of the token to contain
+    /// `role`. A token without a `roles` claim has no roles and is rejected.
+    ///
+    /// # Errors
+    ///
+    /// `AuthErr::MissingRole` if the token is valid but doesn't have the role, otherwise the same
+    /// errors as `validate_token`.
+    #[cfg(feature = "azure-claims")]
+    pub async fn validate_for_role(
+        &mut self,
+        token: &str,
+        role: &str,
+    ) -> Result<Token<AzureJwtClaims>, AuthErr> {
+        // recorded once the role is checked, so a token without it counts as a failure
+        let result = self.validate_default(token).await.and_then(|decoded| {
+            if decoded.claims.has_role(role) {
+                Ok(decoded)
+            } else {
+                Err(AuthErr::MissingRole(role.to_string()))
+            }
+        });
+        telemetry::validation(token, &result);
+        result
+    }
+
+    /// Default validation of the bearer token in the `Authorization` header of `req`, for
+    /// frameworks built on the `http` crate such as axum or hyper.
+    ///
+    /// # Errors
+    ///
+    /// `AuthErr::MissingAuthorizationHeader` if there is no `Authorization` header,
+    /// `AuthErr::UnsupportedAuthorizationScheme` if it isn't a `Bearer` token and
+    /// `AuthErr::EmptyBearerToken` if the token is empty. Otherwise the same errors as
+    /// `validate_token`.
+    #[cfg(all(feature = "http", feature = "azure-claims"))]
+    pub async fn validate_http_request<B>(
+        &mut self,
+        req: &http::Request<B>,
+    ) -> Result<AzureJwtClaims, AuthErr> {
+        let token = bearer_token(req.headers())?;
+        Ok(self.validate_token(token).await?.claims)
+    }
+
+    /// Validates a batch of tokens with the default validation. The public keys are refreshed at
+    /// most once up front (if they're expired) instead of being checked for every token, and the
+    /// result of each token is returned in the same order as `tokens`.
+    ///
+    /// If the up front refresh fails, the first token gets its error, i.e. an
+    /// `AuthErr::HttpError` or `AuthErr::ConnectionError`, and the others are validated without
+    /// fetching again: those that need the keys get `AuthErr::KeysStale`. A batch costs at most
+    /// one refresh, even during an outage.
+    #[cfg(feature = "azure-claims")]
+    pub async fn validate_many(
+        &mut self,
+        tokens: &[&str],
+    ) -> Vec<Result<Token<AzureJwtClaims>, AuthErr>> {
+        let mut results = Vec::with_capacity(tokens.len());
+        let mut tokens = tokens.iter();
+
+        let oidc = &mut self.oidc;
+        let is_network_disabled = oidc.is_network_disabled;
+        if self.allowed_tenants.is_none()
+            && !oidc.is_offline
+            && !is_network_disabled
+            && !oidc.is_keys_valid()
+        {
+            if let Err(e) = oidc.refresh_pub_keys().await {
+                if tokens.next().is_some() {
+                    results.push(Err(e));
+                }
+                oidc.is_network_disabled = true;
+            }
+        }
+
+        for token in tokens {
+            results.push(self.validate_token(token).await);
+        }
+        self.oidc.is_network_disabled = is_network_disabled;
+        results
+    }
+
+    /// Validates a stream of tokens, yielding one result per token in the same order. The
+    /// tokens share this instance and its key cache, so the keys are only fetched once.
+    ///
+    /// Tokens are validated one at a time and only pulled from `tokens` when the next result is
+    /// polled, which gives you backpressure for free. Validating with cached keys is cheap, so
+    /// one stream usually keeps up. If it doesn't, split the tokens over several streams with a
+    /// clone of `AzureAuth` each, each clone keeps its own key cache. To share one instance
+    /// between streams, use `AzureAuthHandle::validate_stream`, which also takes a limit on the
+    /// validations in flight.
+    #[cfg(all(feature = "stream", feature = "azure-claims"))]
+    pub fn validate_stream<'a, S>(
+        &'a mut self,
+        tokens: S,
+    ) -> impl futures_util::Stream<Item = Result<Token<AzureJwtClaims>, AuthErr>> + 'a
+    where
+        S: futures_util::Stream<Item = String> + 'a,
+    {
+        use futures_util::StreamExt;
+
+        futures_util::stream::unfold(
+            (self, Box::pin(tokens)),
+            |(az_auth, mut tokens)| async move {
+                let token = tokens.next().await?;
+                let result = az_auth.validate_token(&token).await;
+                Some((result, (az_auth, tokens)))
+            },
+        )
+    }
+
     /// Allows for a custom validator and mapping the token to your own type.
     /// Useful in situations where you get fields you that are not covered by
     /// the default mapping or want to change the validaion requirements (i.e
@@ -253,6 +1087,9 @@ impl AzureAuth {
     /// # Note
     /// You'll need to pull in `jsonwebtoken` to use `Validation` from that crate.
     ///
+    /// The audience of this instance is not enforced, only what `validator` checks. Use
+    /// `validate_custom_with_audience` to keep the audience check.
+    ///
     /// # Example
     ///
     /// ```rust, no_run
@@ -287,124 +1124,856 @@ impl AzureAuth {
     where
         for<'de> T: Serialize + Deserialize<'de>,
     {
-        let decoded: Token<T> = self.validate_token_authenticity(token, validator).await?;
-        Ok(decoded)
+        let result = self.validate_token_authenticity(token, validator).await;
+        telemetry::validation(token, &result);
+        result
     }
 
-    #[async_recursion]
-    async fn validate_token_authenticity<T>(
+    /// Like `validate_custom`, but sets the audience of this instance on `validator` first, so
+    /// a custom claims type or validation doesn't accidentally skip the audience check.
+    pub async fn validate_custom_with_audience<T>(
         &mut self,
         token: &str,
-        validator: &jwt::Validation,
+        mut validator: jwt::Validation,
     ) -> Result<Token<T>, AuthErr>
     where
         for<'de> T: Serialize + Deserialize<'de>,
     {
-        // if we´re in offline, we never refresh the keys. It's up to the user to do that.
-        if !self.is_keys_valid() && !self.is_offline {
-            self.refresh_pub_keys().await?;
-        }
-        // does not validate the token!
-        let decoded = jwt::decode_header(token)?;
-
-        let key = match &self.public_keys {
-            None => return Err(AuthErr::Other("Internal err. No public keys found.".into())),
-            Some(keys) => match &decoded.kid {
-                None => return Err(AuthErr::Other("No `kid` in token.".into())),
-                Some(kid) => keys.iter().find(|k| k.kid == *kid),
-            },
-        };
-
-        let auth_key = match key {
-            None => {
-                // the first time this happens let's go and refresh the keys and try once more.
-                // It could be that our keys are out of date. Limit to once in an hour.
-                if self.should_retry() {
-                    self.refresh_pub_keys().await?;
-                    self.retry_counter += 1;
-                    self.validate_token(token).await?;
-                    unreachable!()
-                } else {
-                    self.retry_counter = 0;
-                    return Err(AuthErr::Other(
-                        "Invalid token. Could not verify authenticity.".into(),
-                    ));
-                }
-            }
-            Some(key) => {
-                self.retry_counter = 0;
-                key
-            }
-        };
-
-        let key = DecodingKey::from_rsa_components(auth_key.modulus(), auth_key.exponent());
-        let valid: Token<T> = jwt::decode(token, &key, validator)?;
-
-        Ok(valid)
+        validator.set_audience(&[&self.oidc.audience]);
+        self.validate_custom(token, &validator).await
     }
 
-    fn should_retry(&mut self) -> bool {
-        if self.is_offline || !self.is_retry_enabled {
-            return false;
-        }
-
-        match &self.last_refresh {
-            Some(lr) => {
-                self.retry_counter == 0 && Local::now().naive_local() - *lr > Duration::hours(1)
-            }
-            None => false,
-        }
+    /// Default validation, like `validate_token`, with the claims deserialized into your own
+    /// type `T`: the token is only accepted if it passes every check of `validate_token`
+    /// (signature, audience, issuer, tenant, times and so on), so unlike `validate_custom` you
+    /// keep the Azure policy while reading custom claims.
+    ///
+    /// The recommended shape of `T` flattens `AzureJwtClaims` next to your own claims:
+    ///
+    /// ```rust, no_run
+    /// use azure_jwt_async::{AzureAuth, AzureJwtClaims};
+    /// use serde::{Deserialize, Serialize};
+    ///
+    /// #[derive(Serialize, Deserialize)]
+    /// struct MyClaims {
+    ///     #[serde(flatten)]
+    ///     azure: AzureJwtClaims,
+    ///     department: Option<String>,
+    /// }
+    ///
+    /// # async fn run(token: &str) -> Result<(), azure_jwt_async::AuthErr> {
+    /// let mut az_auth = AzureAuth::new("6e74172b-be56-4843-9ff4-e66a39bb12e3").await?;
+    /// let claims = az_auth.validate_as::<MyClaims>(token).await?.claims;
+    /// println!("{} {:?}", claims.azure.sub, claims.department);
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// The same errors as `validate_token`, `AuthErr::InvalidToken` if the claims of a valid
+    /// token don't fit `T`.
+    #[cfg(feature = "azure-claims")]
+    pub async fn validate_as<T>(&mut self, token: &str) -> Result<Token<T>, AuthErr>
+    where
+        for<'de> T: Serialize + Deserialize<'de>,
+    {
+        self.validate_token(token).await?;
+        // the token passed every check above, this only reads its claims again as `T`
+        Ok(oidc::insecure_decode(token)?)
+    }
+
+    async fn validate_token_authenticity<T>(
+        &mut self,
+        token: &str,
+        validator: &jwt::Validation,
+    ) -> Result<Token<T>, AuthErr>
+    where
+        for<'de> T: Serialize + Deserialize<'de>,
+    {
+        if let Some(tenants) = &self.allowed_tenants {
+            self.oidc.check_size(token)?;
+            let tenant = unverified_tenant(token)?;
+            if !tenants.iter().any(|t| t.eq_ignore_ascii_case(&tenant)) {
+                return Err(AuthErr::TenantNotAllowed(tenant));
+            }
+            self.activate_tenant(&tenant).await?;
+        }
+
+        self.oidc.validate_with_current_keys(token, validator).await
+    }
+
+    /// Makes `tenant` the tenant whose keys we validate against, caching the keys of the
+    /// tenant we're switching away from.
+    async fn activate_tenant(&mut self, tenant: &str) -> Result<(), AuthErr> {
+        if self.tenant.eq_ignore_ascii_case(tenant) && !self.oidc.jwks_uri.is_empty() {
+            return Ok(());
+        }
+
+        let next = match self.tenant_cache.take(tenant) {
+            Some(cached) => cached,
+            None if self.oidc.is_network_disabled => return Err(AuthErr::KeysStale),
+            None => {
+                let discovery =
+                    AzureAuth::get_discovery(&self.oidc, &self.authority_host, tenant).await?;
+                TenantKeys {
+                    jwks_uri: discovery.jwks_uri.clone(),
+                    discovery: Some(discovery),
+                    ..Default::default()
+                }
+            }
+        };
+
+        self.tenant_cache
+            .evict_expired(clock::hours(self.oidc.exp_hours));
+
+        let oidc = &mut self.oidc;
+        let previous = TenantKeys {
+            discovery: std::mem::replace(&mut oidc.discovery, next.discovery),
+            discovery_v2: std::mem::replace(&mut self.discovery_v2, next.discovery_v2),
+            jwks_uri: std::mem::replace(&mut oidc.jwks_uri, next.jwks_uri),
+            public_keys: std::mem::replace(&mut oidc.public_keys, next.public_keys),
+            last_refresh: std::mem::replace(&mut oidc.last_refresh, next.last_refresh),
+            last_fetch: std::mem::replace(&mut oidc.last_fetch, next.last_fetch),
+            keys_from_fallback: std::mem::replace(
+                &mut oidc.keys_from_fallback,
+                next.keys_from_fallback,
+            ),
+        };
+        oidc.metadata_url = Some(AzureAuth::metadata_url(&self.authority_host, tenant));
+        oidc.retry_counter = 0;
+
+        let previous_tenant = std::mem::replace(&mut self.tenant, tenant.to_string());
+        if !previous.jwks_uri.is_empty() {
+            self.tenant_cache.put(previous_tenant, previous);
+        }
+
+        Ok(())
     }
 
     /// Sets the expiration of the cached public keys in hours. Pr. 04.2019 Microsoft rotates these
     /// every 24h.
     pub fn set_expiration(&mut self, hours: i64) {
-        self.exp_hours = hours;
+        self.oidc.set_expiration(hours);
+    }
+
+    /// Spreads key refreshes across a fleet: instead of every instance refreshing exactly when
+    /// the keys expire, and all at once after a rotation, each instance refreshes at a random
+    /// point in the last `fraction` of the expiration. With `0.1` and the default 24h, that's
+    /// somewhere between 21.6h and 24h, fixed per instance. Clamped to `0.0..=1.0`, defaults to
+    /// `0.0` which disables it.
+    pub fn set_refresh_jitter(&mut self, fraction: f64) {
+        self.oidc.set_refresh_jitter(fraction);
+    }
+
+    /// Sets the algorithms tokens may be signed with in the default validation. Defaults to
+    /// `RS256`. A key is only used with the algorithms of its type, RSA keys with `RS*` and
+    /// `PS*`, Ed25519 (`OKP`) keys with `EdDSA`.
+    pub fn set_algorithms(&mut self, algorithms: Vec<jwt::Algorithm>) {
+        self.oidc.set_algorithms(algorithms);
+    }
+
+    /// Sets the leeway in seconds used when checking the `exp` and `nbf` claims in the default
+    /// validation. Defaults to 60 seconds. Overrides an earlier `set_nbf_leeway`.
+    pub fn set_leeway(&mut self, seconds: u64) {
+        self.oidc.set_leeway(seconds);
+        #[cfg(feature = "azure-claims")]
+        {
+            self.nbf_leeway = None;
+        }
+    }
+
+    /// Sets the leeway in seconds on `exp` only, the one of `nbf` stays as it is. Keep it tight
+    /// to accept as few expired tokens as possible while `set_nbf_leeway` tolerates issuers
+    /// with fast clocks.
+    #[cfg(feature = "azure-claims")]
+    pub fn set_exp_leeway(&mut self, seconds: u64) {
+        self.nbf_leeway = Some(self.nbf_leeway());
+        self.oidc.set_leeway(seconds);
+    }
+
+    /// Sets the leeway in seconds on `nbf` only, the one of `exp` stays as it is.
+    ///
+    /// jsonwebtoken applies a single leeway to both claims, its `Validation::leeway` is the one
+    /// of `exp`. `nbf` is checked by us once the token is verified, so a `jwt::Validation` made
+    /// from this instance doesn't check it.
+    #[cfg(feature = "azure-claims")]
+    pub fn set_nbf_leeway(&mut self, seconds: u64) {
+        self.nbf_leeway = Some(seconds);
+    }
+
+    #[cfg(feature = "azure-claims")]
+    fn nbf_leeway(&self) -> u64 {
+        self.nbf_leeway.unwrap_or(self.oidc.leeway)
+    }
+
+    /// Sets the leeway in seconds used when checking that the token isn't issued in the future
+    /// (its `iat` claim) in the default validation. Defaults to 60 seconds. This is separate from
+    /// `set_leeway` so hosts with a skewed clock can accept freshly issued tokens without being
+    /// lenient with expired ones.
+    #[cfg(feature = "azure-claims")]
+    pub fn set_iat_leeway(&mut self, seconds: u64) {
+        self.iat_leeway = seconds;
+    }
+
+    /// Only accepts tokens of the given version in the default validation, `None` (the default)
+    /// accepts both v1.0 and v2.0 tokens. The claims differ between the versions, so enforcing
+    /// one avoids surprises when a token of the other version shows up.
+    #[cfg(feature = "azure-claims")]
+    pub fn set_required_version(&mut self, version: Option<TokenVersion>) {
+        self.required_version = version;
+    }
+
+    /// Whether tokens from personal Microsoft accounts (outlook.com, xbox.com, ...) are accepted,
+    /// recognized by the `CONSUMER_TENANT_ID` in their `tid`.
+    /// Many line-of-business apps use the `common` tenant but only want work and school
+    /// accounts. Rejected tokens fail with `AuthErr::PersonalAccountNotAllowed`. Defaults to
+    /// `true`.
+    #[cfg(feature = "azure-claims")]
+    pub fn set_allow_personal_accounts(&mut self, allow: bool) {
+        self.allow_personal_accounts = allow;
+    }
+
+    /// Only accepts tokens from users who authenticated with one of the identity providers in
+    /// `idps`, i.e. to allow your organization's IdP but not `live.com`. Checked against the
+    /// `idp` claim, or the `iss` claim for tokens without one, which is how Azure marks users
+    /// that authenticated with the issuing tenant itself. Rejected tokens fail with
+    /// `AuthErr::IdpNotAllowed`.
+    #[cfg(feature = "azure-claims")]
+    pub fn set_allowed_idps(&mut self, idps: Vec<String>) {
+        self.allowed_idps = Some(idps);
+    }
+
+    /// Decides the accepted audiences per token instead of the one set with `set_audience`, i.e.
+    /// from the app registrations of the tenant in `tid`. The token is accepted if its `aud` is
+    /// one of the audiences `resolve` returns for its claims, and rejected with
+    /// `AuthErr::InvalidAudience` if it returns none.
+    ///
+    /// `resolve` is only called for authentic tokens, before the checks of the other claims,
+    /// `nbf` included. `validate_token` only calls it for tokens that aren't expired,
+    /// `validate_detailed` calls it for expired ones too since it reports every failure. It's
+    /// called on every default validation, keep it fast and don't block. Custom validations keep
+    /// using the audience of their `Validation`.
+    #[cfg(feature = "azure-claims")]
+    pub fn set_audience_resolver(
+        &mut self,
+        resolve: impl Fn(&AzureJwtClaims) -> Vec<String> + Send + Sync + 'static,
+    ) {
+        self.audience_resolver = Some(AudienceResolver(std::sync::Arc::new(resolve)));
+    }
+
+    /// Rejects tokens longer than `max` bytes with `AuthErr::TokenTooLarge`, before anything is
+    /// decoded. Cheap protection for public endpoints against clients sending huge tokens to
+    /// keep us busy decoding them. Defaults to 16 KiB, far more than Azure tokens need.
+    pub fn set_max_token_bytes(&mut self, max: usize) {
+        self.oidc.set_max_token_bytes(max);
+    }
+
+    /// Rejects tokens that don't have an `exp`, `nbf` or `iat` claim with `AuthErr::MissingClaim`,
+    /// also with a custom `Validation` or claims type that would accept them. Azure always
+    /// includes them, so a token without them isn't from Azure or was tampered with. Defaults to
+    /// `false`.
+    pub fn set_require_temporal_claims(&mut self, require: bool) {
+        self.oidc.set_require_temporal_claims(require);
+    }
+
+    /// Rejects tokens for which `is_revoked` returns `true` with `AuthErr::TokenRevoked`. A
+    /// token stays valid until it expires, so this is the hook for apps keeping a revocation
+    /// list, i.e. by `oid`, `sub` or session. Only called for authentic tokens, after the
+    /// signature is verified. It's called on every validation, keep it fast and don't block.
+    #[cfg(feature = "azure-claims")]
+    pub fn set_revocation_check(
+        &mut self,
+        is_revoked: impl Fn(&AzureJwtClaims) -> bool + Send + Sync + 'static,
+    ) {
+        self.revocation_check = Some(RevocationCheck(std::sync::Arc::new(is_revoked)));
+    }
+
+    /// Closes connections to Microsoft that were idle for `timeout`. All instances share one
+    /// HTTP client by default, so the metadata and key fetches reuse its connections instead of
+    /// paying for a DNS lookup and TLS handshake every time, and idle connections are closed
+    /// after reqwest's default of 90 seconds. With this the instance gets a client of its own.
+    ///
+    /// Keys are refreshed every few hours, so keeping connections that long rarely pays off.
+    /// A longer timeout helps when there are many refreshes close together, i.e. in multi-tenant
+    /// mode or with a short `set_min_refresh_interval`.
+    ///
+    /// DNS results aren't cached, a new connection looks the host up again through the system
+    /// resolver, which usually caches them itself. Reusing connections is what saves the lookups.
+    ///
+    /// # Errors
+    ///
+    /// `AuthErr::ConnectionError` if the HTTP client can't be created.
+    pub fn set_pool_idle_timeout(&mut self, timeout: Duration) -> Result<(), AuthErr> {
+        self.oidc.set_pool_idle_timeout(timeout)
+    }
+
+    /// Requires every scope in `scopes` to be in the `scp` claim of the token, failing with
+    /// `AuthErr::MissingScope` for the first one that isn't. A token without a `scp` claim, such
+    /// as an app-only token, has no scopes. Empty by default.
+    #[cfg(feature = "azure-claims")]
+    pub fn set_required_scopes(&mut self, scopes: Vec<String>) {
+        self.required_scopes = scopes;
+    }
+
+    /// Sets the minimum time between two refreshes triggered by a token with an unknown `kid`.
+    /// Azure can rotate keys at any time, so this defaults to one minute which lets us
+    /// pick up new keys quickly while still preventing a flood of bad tokens from causing a
+    /// refresh storm.
+    pub fn set_min_refresh_interval(&mut self, interval: Duration) {
+        self.oidc.set_min_refresh_interval(interval);
+    }
+
+    /// Sets the minimum time between two successful fetches of the OpenID metadata or the public
+    /// keys. A refresh requested within that time reuses what we have cached, a failed fetch is
+    /// retried by the next request. This is a safety valve against refresh storms caused by
+    /// bugs or misconfiguration, on top of the key expiration. A token with an unknown `kid`
+    /// refreshes the keys regardless, that retry is limited by `set_min_refresh_interval`.
+    /// Defaults to 30 seconds.
+    pub fn set_min_fetch_interval(&mut self, interval: Duration) {
+        self.oidc.set_min_fetch_interval(interval);
+    }
+
+    /// Keeps public keys that are no longer published for `grace` after they were last fetched,
+    /// instead of replacing our keys with whatever the endpoint returns. During a rotation
+    /// Microsoft publishes the old and new keys side by side, but if the endpoint briefly
+    /// returns a reduced set this keeps recently valid keys from vanishing. Defaults to zero,
+    /// which disables it. Keys set with `set_public_keys` always replace ours.
+    pub fn set_key_grace_period(&mut self, grace: Duration) {
+        self.oidc.set_key_grace_period(grace);
     }
 
     pub fn set_no_retry(&mut self) {
-        self.is_retry_enabled = false;
+        self.oidc.set_no_retry();
+    }
+
+    /// Pins validation to the signing keys with the given `kid`s. Tokens signed with any other
+    /// key are rejected with `AuthErr::KidNotPinned` before we even look for the key, so they
+    /// never trigger a refresh either. Useful during a controlled key rotation or in locked
+    /// down environments.
+    pub fn set_pinned_kids(&mut self, kids: Vec<String>) {
+        self.oidc.set_pinned_kids(kids);
+    }
+
+    /// Guarantees that validating a token never makes a network call, not even to refresh
+    /// expired keys. Validation fails with `AuthErr::KeysStale` instead whenever something would
+    /// have to be fetched, and an unknown `kid` fails right away with `AuthErr::KeyNotFound`.
+    /// Useful for deterministic tests and when you manage the keys yourself with
+    /// `set_public_keys`. Explicit calls such as `refresh_rwks_uri` still go to the network.
+    pub fn set_network_disabled(&mut self, disabled: bool) {
+        self.oidc.set_network_disabled(disabled);
+    }
+
+    /// Sets the maximum number of tenant key sets kept in memory in multi-tenant mode, including
+    /// the one of the tenant currently in use. The default is 64. When the limit is reached the
+    /// least recently used tenant is evicted and its keys are fetched again the next time we see
+    /// a token from it. Key sets older than the key expiration are evicted as well since they'd
+    /// be re-fetched anyway.
+    pub fn set_max_cached_key_sets(&mut self, n: usize) {
+        self.tenant_cache.set_max_entries(n);
+    }
+
+    /// Changes the audience (your client id) tokens are validated against. The cached public
+    /// keys are kept since they don't depend on the audience, so this never triggers a re-fetch.
+    pub fn set_audience(&mut self, aud: impl Into<String>) {
+        let aud = aud.into();
+        if let Err(e) = check_audience_format(&aud) {
+            telemetry::suspicious_audience(&e);
+        }
+        for provider in self.providers.values_mut() {
+            provider.set_audience(aud.clone());
+        }
+        self.oidc.set_audience(aud);
+    }
+
+    /// Turns the audience check of the default validation on or off, it's on by default.
+    ///
+    /// With it off we only verify that a token is genuinely issued by Azure and is valid right
+    /// now, but not that it's meant for your application: **any** valid Azure token from the
+    /// tenant is accepted, including tokens issued to other applications. Only turn it off in
+    /// an edge layer that forwards tokens to services doing their own audience checks.
+    pub fn set_validate_audience(&mut self, validate: bool) {
+        self.oidc.set_validate_audience(validate);
+    }
+
+    /// The audience should be the Application (client) ID of your app registration, a GUID, or
+    /// its Application ID URI such as `api://...`. Anything else, usually the display name of
+    /// the app, is logged as a warning when the audience is set, with `tracing` if the feature
+    /// is enabled and on stderr otherwise. With `strict` it's an error instead: every
+    /// validation fails with `AuthErr::UnexpectedAudienceFormat` while the audience is
+    /// suspicious, i.e. after a later `set_audience`. Defaults to `false`.
+    ///
+    /// # Errors
+    ///
+    /// `AuthErr::UnexpectedAudienceFormat` if `strict` and the current audience is neither a
+    /// GUID nor a URI. Strict mode stays off then.
+    #[cfg(feature = "azure-claims")]
+    pub fn set_strict_audience(&mut self, strict: bool) -> Result<(), AuthErr> {
+        if strict {
+            check_audience_format(&self.oidc.audience)?;
+        }
+        self.strict_audience = strict;
+        Ok(())
     }
 
-    fn is_keys_valid(&self) -> bool {
-        match self.last_refresh {
-            None => false,
-            Some(lr) => (Local::now().naive_local() - lr) <= Duration::hours(self.exp_hours),
+    #[cfg(feature = "azure-claims")]
+    fn check_strict_audience(&self) -> Result<(), AuthErr> {
+        match self.strict_audience {
+            true => check_audience_format(&self.oidc.audience),
+            false => Ok(()),
         }
     }
 
-    async fn refresh_pub_keys(&mut self) -> Result<(), AuthErr> {
-        let resp: Response = reqwest::get(&self.jwks_uri).await?;
-        let resp: JwkSet = resp.json().await?;
-        self.last_refresh = Some(Local::now().naive_local());
-        self.public_keys = Some(resp.keys);
+    /// Also accepts access tokens for Microsoft Graph, whose audience is `GRAPH_AUDIENCE` or
+    /// `GRAPH_APP_ID`, for gateways that have to inspect tokens meant for Graph. Defaults to
+    /// `false`.
+    ///
+    /// Microsoft advises against validating Graph tokens anywhere but in Graph, their format
+    /// is private to it and may change. Tokens with a `nonce` in their header, which includes
+    /// most Graph tokens, have a signature that only Graph can verify and fail with
+    /// `AuthErr::InvalidSignature`. Only turn this on if you really need to.
+    #[cfg(feature = "azure-claims")]
+    pub fn set_accept_graph_audience(&mut self, accept: bool) {
+        if accept {
+            telemetry::graph_audience_accepted();
+        }
+        self.accept_graph_audience = accept;
+    }
+
+    /// Turns the issuer check of the default validation on or off, it's on by default. The
+    /// v2.0 metadata isn't fetched for v2.0 tokens with it off, since it's only needed for
+    /// their issuer.
+    ///
+    /// With it off, a token signed with a key of the `jwks_uri` is accepted whatever its `iss`
+    /// claims, which matters when the keys are shared by more than one issuer: Azure signs the
+    /// tokens of every tenant with the same keys, so other checks such as
+    /// `new_multi_tenant` or `set_allowed_idps` are all that's left to tell tenants apart.
+    /// Only turn it off in an edge proxy whose upstream services check the issuer themselves.
+    #[cfg(feature = "azure-claims")]
+    pub fn set_validate_issuer(&mut self, validate: bool) {
+        self.validate_issuer = validate;
+    }
+
+    /// Compares the audience of tokens with ours ignoring ASCII case, i.e. to accept tokens for
+    /// `api://6E74172B-...` when the audience is set as `api://6e74172b-...`. GUIDs aren't case
+    /// sensitive and some setups vary the case of the App ID URI. Applies to every audience
+    /// check, including the ones of a custom `Validation`. Defaults to `false`.
+    pub fn set_case_insensitive_audience(&mut self, case_insensitive: bool) {
+        self.oidc.set_case_insensitive_audience(case_insensitive);
+    }
+
+    /// Changes the tenant used to look up the OpenID metadata, the default is `common`.
+    ///
+    /// If the tenant differs from the current one the `jwks_uri` is re-discovered right away and
+    /// the cached public keys are dropped, so they're fetched again on the next validation.
+    /// Setting the same tenant again is a no-op. In offline mode only the tenant is updated since
+    /// you handle the public keys yourself.
+    ///
+    /// # Errors
+    ///
+    /// If there is a connection issue to the Microsoft APIs. The current configuration is left
+    /// untouched in that case.
+    pub async fn set_tenant(&mut self, tenant: impl Into<String>) -> Result<(), AuthErr> {
+        let tenant = tenant.into();
+        if tenant.eq_ignore_ascii_case(&self.tenant) {
+            return Ok(());
+        }
+
+        let oidc = &mut self.oidc;
+        if !oidc.is_offline {
+            let discovery = AzureAuth::get_discovery(oidc, &self.authority_host, &tenant).await?;
+            oidc.jwks_uri = discovery.jwks_uri.clone();
+            oidc.discovery = Some(discovery);
+            oidc.public_keys = None;
+            oidc.last_refresh = None;
+            self.discovery_v2 = None;
+        }
+        oidc.metadata_url = Some(AzureAuth::metadata_url(&self.authority_host, &tenant));
+
+        self.tenant = tenant;
         Ok(())
     }
 
+    /// Refreshes the public keys, only if they're expired. Returns whether they were fetched,
+    /// which they aren't while they're valid, in offline mode or when something was fetched
+    /// less than `min_fetch_interval` ago. Cheap enough to call on a timer from a background
+    /// task, so validations never have to wait for a refresh.
+    ///
+    /// # Errors
+    ///
+    /// If there is a connection issue to the Microsoft APIs, `AuthErr::KeysStale` if the keys
+    /// are expired and `set_network_disabled` forbids fetching them.
+    pub async fn refresh_if_needed(&mut self) -> Result<bool, AuthErr> {
+        self.oidc.refresh_if_needed().await
+    }
+
     /// Refreshes the jwks_uri by re-fetching it from the the OpenID metadata
     /// document. See: <https://openid.net/specs/openid-connect-discovery-1_0.html#ProviderMetadata>
     /// Usually, this is not needed but for some cases you might want to try
     /// to fetch a new uri on receiving an error.
     pub async fn refresh_rwks_uri(&mut self) -> Result<(), AuthErr> {
-        self.jwks_uri = AzureAuth::get_jwks_uri().await?;
+        self.oidc.refresh_rwks_uri().await?;
+        self.discovery_v2 = None;
         Ok(())
     }
 
-    async fn get_jwks_uri() -> Result<String, AuthErr> {
-        let resp: Response = reqwest::get(AZ_OPENID_URL).await?;
-        let resp: OpenIdResponse = resp.json().await?;
+    /// Checks the configuration without needing a token: the audience and tenants look sane, the
+    /// OpenID metadata of every tenant can be fetched and the public keys can be fetched and
+    /// aren't empty. In offline mode only the keys we have are checked. Returns every problem
+    /// found rather than stopping at the first one.
+    ///
+    /// Meant as a startup or readiness check so misconfigurations surface at boot rather than on
+    /// the first real token. Nothing fetched is kept, so the next validation isn't affected.
+    pub async fn self_check(&mut self) -> Result<(), Vec<AuthErr>> {
+        let mut problems = Vec::new();
+
+        if self.oidc.validate_audience && self.oidc.audience.trim().is_empty() {
+            problems.push(AuthErr::Other("The audience is empty.".into()));
+        }
+        if self.allowed_tenants.as_ref().is_some_and(Vec::is_empty) {
+            problems.push(AuthErr::Other(
+                "No tenants are allowed, every token would be rejected.".into(),
+            ));
+        }
+
+        if self.oidc.is_offline {
+            if self.oidc.public_keys.as_ref().is_none_or(KeySet::is_empty) {
+                problems.push(AuthErr::Other("No public keys to validate with.".into()));
+            }
+        } else {
+            let tenants = match &self.allowed_tenants {
+                Some(tenants) => tenants.clone(),
+                None => vec![self.tenant.clone()],
+            };
+            let mut jwks_uri = Some(self.oidc.jwks_uri.clone()).filter(|uri| !uri.is_empty());
+            for tenant in &tenants {
+                match AzureAuth::get_discovery(&self.oidc, &self.authority_host, tenant).await {
+                    Ok(discovery) => {
+                        jwks_uri.get_or_insert(discovery.jwks_uri);
+                    }
+                    Err(e) => problems.push(e),
+                }
+            }
+
+            if let Some(jwks_uri) = jwks_uri {
+                match self.oidc.fetch_keys(&jwks_uri).await {
+                    Ok(fetched) if fetched.keys.is_empty() => problems.push(AuthErr::Other(
+                        format!("No public keys published at `{}`.", jwks_uri),
+                    )),
+                    Ok(_) => {}
+                    Err(e) => problems.push(e),
+                }
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    /// Fetches and caches the OpenID metadata and public keys of `tenants` ahead of time in
+    /// multi-tenant mode, so the first token from each of them doesn't have to wait for it. The
+    /// tenants are fetched concurrently on the calling task, nothing is spawned. Useful at
+    /// startup, after loading your customer list.
+    ///
+    /// Tenants whose keys are already cached and not expired are skipped. Warming more tenants
+    /// than `set_max_cached_key_sets` allows evicts the ones warmed first.
+    ///
+    /// # Errors
+    ///
+    /// Every tenant that couldn't be warmed, with its error: `AuthErr::TenantNotAllowed` if it
+    /// isn't one of the allowed tenants (or we're not in multi-tenant mode), `AuthErr::KeysStale`
+    /// if the network is disabled, otherwise the connection error. The other tenants are still
+    /// cached.
+    pub async fn warm_tenants(&mut self, tenants: &[&str]) -> Result<(), Vec<(String, AuthErr)>> {
+        let mut errors = Vec::new();
+        let mut to_fetch = Vec::new();
+        for &tenant in tenants {
+            let allowed = self
+                .allowed_tenants
+                .as_ref()
+                .is_some_and(|allowed| allowed.iter().any(|t| t.eq_ignore_ascii_case(tenant)));
+            if !allowed {
+                errors.push((tenant.to_string(), AuthErr::TenantNotAllowed(tenant.into())));
+            } else if tenant.eq_ignore_ascii_case(&self.tenant) && !self.oidc.jwks_uri.is_empty() {
+                if !self.oidc.is_keys_valid() {
+                    if let Err(e) = self.oidc.refresh_pub_keys().await {
+                        errors.push((tenant.to_string(), e));
+                    }
+                }
+            } else if self
+                .tenant_cache
+                .is_fresh(tenant, clock::hours(self.oidc.exp_hours))
+            {
+                continue;
+            } else if self.oidc.is_network_disabled {
+                errors.push((tenant.to_string(), AuthErr::KeysStale));
+            } else {
+                to_fetch.push(tenant);
+            }
+        }
+
+        // fetched concurrently on this task, the fetches only borrow the http client, key source
+        // and fallback authority
+        let (oidc, authority_host) = (&self.oidc, self.authority_host.as_str());
+        let fetched =
+            futures_util::future::join_all(to_fetch.into_iter().map(|tenant| async move {
+                let keys = async {
+                    let discovery = AzureAuth::get_discovery(oidc, authority_host, tenant).await?;
+                    let fetched = oidc.fetch_keys(&discovery.jwks_uri).await;
+                    telemetry::key_refresh(&fetched);
+                    let fetched = fetched?;
+                    Ok(TenantKeys {
+                        jwks_uri: discovery.jwks_uri.clone(),
+                        discovery: Some(discovery),
+                        public_keys: Some(KeySet::new(fetched.keys)),
+                        keys_from_fallback: fetched.from_fallback,
+                        last_refresh: Some(Timestamp::now()),
+                        last_fetch: Some(Timestamp::now()),
+                        ..Default::default()
+                    })
+                }
+                .await;
+                (tenant.to_string(), keys)
+            }))
+            .await;
+
+        for (tenant, keys) in fetched {
+            match keys {
+                Ok(keys) => self.tenant_cache.put(tenant, keys),
+                Err(e) => errors.push((tenant, e)),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// The OpenID metadata document of the current tenant as fetched at construction, on
+    /// `refresh_rwks_uri` or when changing tenant. This saves you from fetching it again if you
+    /// need the endpoints for your login flow. `None` in offline mode and, in multi-tenant mode,
+    /// before the first token has been validated.
+    pub fn discovery(&self) -> Option<&OpenIdConfiguration> {
+        self.oidc.discovery()
+    }
+
+    /// The audience tokens must be issued for, see `set_audience`.
+    pub fn audience(&self) -> &str {
+        self.oidc.audience()
+    }
+
+    /// The leeway in seconds on `exp`, and `nbf` unless `set_nbf_leeway` set another one, see
+    /// `set_leeway`.
+    pub fn leeway(&self) -> u64 {
+        self.oidc.leeway()
+    }
+
+    /// How long the public keys are used before they're refreshed, see `set_expiration`.
+    pub fn key_expiration_hours(&self) -> i64 {
+        self.oidc.key_expiration_hours()
+    }
+
+    /// The state of the public keys of the current tenant, for monitoring and readiness checks.
+    /// Doesn't fetch anything.
+    pub fn cache_status(&self) -> CacheStatus {
+        let oidc = &self.oidc;
+        let key_count = oidc.public_keys.as_ref().map_or(0, KeySet::len);
+        CacheStatus {
+            keys_loaded: key_count > 0,
+            last_refresh: oidc.last_refresh.map(SystemTime::from),
+            expired: !oidc.is_offline && !oidc.is_keys_valid(),
+            key_count,
+            trusted_key_count: oidc.trusted_keys.len(),
+            keys_from_fallback: oidc.keys_from_fallback,
+        }
+    }
+
+    /// The generic OpenID Connect validator doing the fetching and verifying for us.
+    pub fn oidc(&self) -> &OidcValidator {
+        &self.oidc
+    }
+
+    fn metadata_url(authority_host: &str, tenant: &str) -> String {
+        format!(
+            "{}/{}/.well-known/openid-configuration",
+            authority_host, tenant
+        )
+    }
+
+    async fn get_discovery(
+        oidc: &OidcValidator,
+        authority_host: &str,
+        tenant: &str,
+    ) -> Result<OpenIdConfiguration, AuthErr> {
+        oidc.fetch_discovery(&AzureAuth::metadata_url(authority_host, tenant))
+            .await
+    }
+
+    /// The v2.0 metadata document, which advertises the issuer of v2.0 tokens.
+    #[cfg(feature = "azure-claims")]
+    async fn get_discovery_v2(
+        oidc: &OidcValidator,
+        authority_host: &str,
+        tenant: &str,
+    ) -> Result<OpenIdConfiguration, AuthErr> {
+        let url = format!(
+            "{}/{}/v2.0/.well-known/openid-configuration",
+            authority_host, tenant
+        );
+        oidc.fetch_discovery(&url).await
+    }
+
+    /// Sets another authority to fetch the OpenID metadata and public keys from when fetching
+    /// them from the primary one fails, i.e. a regional mirror to keep validating during an
+    /// outage. The primary authority is the host of the metadata url and the `jwks_uri`,
+    /// `https://login.microsoftonline.com` unless `from_env` read another cloud or you
+    /// `set_jwks_uri` or `new_seeded` with another one. The fallback's urls have to look like the primary's, with only the scheme
+    /// and host swapped, e.g. `https://mirror.contoso.com` serves
+    /// `https://mirror.contoso.com/common/discovery/v2.0/keys`. `None` (the default) never falls
+    /// back.
+    ///
+    /// The primary is always tried first, the fallback only when it can't be reached or answers
+    /// with a server error or `429 Too Many Requests`. Documents it doesn't have aren't fetched
+    /// elsewhere. Every fallback and whether it succeeded is logged, with the `tracing` feature
+    /// or on stderr, and counted with the `metrics` feature. `CacheStatus::keys_from_fallback`
+    /// tells whether the current keys came from the fallback. Whatever the fallback serves is
+    /// trusted like Microsoft's own documents, so it has to be a server you control.
+    pub fn set_fallback_authority(&mut self, authority: Option<String>) {
+        self.oidc.fallback_authority = authority;
+    }
+
+    /// Sets where the public keys are fetched from when they're refreshed. Together with
+    /// `set_public_keys`, `set_last_refresh` and `set_discovery` this lets you build a fully
+    /// populated instance without calling Microsoft, i.e. in tests or from a DI container.
+    pub fn set_jwks_uri(&mut self, uri: impl Into<String>) {
+        self.oidc.set_jwks_uri(uri);
+    }
+
+    /// Also fetches public keys from `uris` whenever the keys are refreshed, and merges them with
+    /// the keys from the `jwks_uri`. Lets one instance validate both v1.0 and v2.0 tokens when
+    /// they're signed with keys published at different endpoints. Keys are de-duplicated by
+    /// `kid`. A refresh fails if any of the uris can't be fetched.
+    ///
+    /// The uris are used for every tenant, so in multi-tenant mode they should be common to all
+    /// of them.
+    pub fn set_additional_jwks_uris(&mut self, uris: Vec<String>) {
+        self.oidc.set_additional_jwks_uris(uris);
+    }
+
+    /// Sets when the current public keys were fetched, which decides when they expire.
+    /// `set_public_keys` sets this to now.
+    pub fn set_last_refresh(&mut self, at: SystemTime) {
+        self.oidc.set_last_refresh(at);
+    }
+
+    /// Replaces the OpenID metadata of the current tenant and with it the `jwks_uri`. The
+    /// issuer of v1.0 tokens is validated against it.
+    pub fn set_discovery(&mut self, discovery: OpenIdConfiguration) {
+        self.oidc.set_discovery(discovery);
+    }
+
+    /// Replaces the v2.0 OpenID metadata, the issuer of v2.0 tokens is validated against it.
+    /// Only used together with the v1.0 metadata, see `set_discovery`.
+    pub fn set_discovery_v2(&mut self, discovery: OpenIdConfiguration) {
+        self.discovery_v2 = Some(discovery);
+    }
 
-        Ok(resp.jwks_uri)
+    /// Gets the public keys from `source` instead of fetching them from the `jwks_uri`. They're
+    /// refreshed from it whenever they'd otherwise be fetched, on expiry and on an unknown `kid`,
+    /// which also holds for instances created with `new_offline`. In multi-tenant mode the same
+    /// source is used for every tenant.
+    pub fn set_key_source(&mut self, source: impl KeySource + 'static) {
+        self.oidc.set_key_source(source);
+    }
+
+    /// Trusts `key` in addition to the fetched keys, until it's removed with
+    /// `remove_trusted_key`. Unlike the fetched keys it's never replaced or dropped by a refresh,
+    /// so tokens signed with a key that was just retired keep validating during a cutover. In
+    /// multi-tenant mode it's trusted for every tenant.
+    ///
+    /// This bypasses the revocation that retiring a key is: if the key was rotated out because
+    /// it leaked, tokens forged with it are accepted. Only add keys you know are safe and remove
+    /// them as soon as the cutover is done.
+    pub fn add_trusted_key(&mut self, key: Jwk) {
+        self.oidc.add_trusted_key(key);
+    }
+
+    /// Stops trusting a key added with `add_trusted_key`, `false` if there was none with `kid`.
+    pub fn remove_trusted_key(&mut self, kid: &str) -> bool {
+        self.oidc.remove_trusted_key(kid)
     }
 
     /// If you use the "offline" variant you'll need this to update the public keys, if you don't
     /// use the offline version you probably don't want to change these unless you're testing.
     pub fn set_public_keys(&mut self, pub_keys: Vec<Jwk>) {
-        self.last_refresh = Some(Local::now().naive_local());
-        self.public_keys = Some(pub_keys);
+        self.oidc.set_public_keys(pub_keys);
+    }
+}
+
+/// The `Validation` the default validation hands to jsonwebtoken: the algorithm, the leeway on
+/// `exp` and `nbf` and the audience. The other checks, such as the issuer, the `iat` and the
+/// allowed tenants, are done on the claims afterwards and not part of it. Note that jsonwebtoken
+/// compares the audience case sensitively even with `set_case_insensitive_audience`.
+#[cfg(feature = "azure-claims")]
+impl From<&AzureAuth> for jwt::Validation {
+    fn from(az_auth: &AzureAuth) -> Self {
+        az_auth.default_validation()
     }
 }
 
+/// The version of an Azure token, as found in its `ver` claim.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenVersion {
+    /// `ver` is `1.0`, issued by the v1.0 endpoint.
+    V1,
+    /// `ver` is `2.0`, issued by the v2.0 endpoint.
+    V2,
+}
+
+impl TokenVersion {
+    /// The value of the `ver` claim for this version.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenVersion::V1 => "1.0",
+            TokenVersion::V2 => "2.0",
+        }
+    }
+}
+
+/// Validates a single token against `keys` with the default validation (RS256, 60s leeway and
+/// `aud` as the audience) without keeping any state around. Nothing is fetched, which suits
+/// short-lived processes such as serverless functions that load the keys from their
+/// environment. Prefer an `AzureAuth` instance if you validate more than one token.
+///
+/// # Errors
+///
+/// The same errors as `AzureAuth::validate_token` for an instance created with `new_offline`.
+#[cfg(feature = "azure-claims")]
+pub fn validate_once(
+    aud: &str,
+    token: &str,
+    keys: &[Jwk],
+) -> Result<Token<AzureJwtClaims>, AuthErr> {
+    AzureAuth::new_offline(aud, keys.to_vec())?.try_validate(token)
+}
+
+/// A source of claims left out of the token, see `AzureJwtClaims::claim_sources`.
+#[cfg(feature = "azure-claims")]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct ClaimSource {
+    /// The url the claims can be fetched from.
+    pub endpoint: String,
+}
+
+/// The header of a validated token, see `AzureAuth::validate_full`. Fields the header doesn't
+/// have are empty.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct AzureJwtHeader {
     /// Indicates that the token is a JWT.
     pub typ: String,
@@ -415,7 +1984,37 @@ pub struct AzureJwtHeader {
     pub kid: String,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+impl From<jwt::Header> for AzureJwtHeader {
+    fn from(header: jwt::Header) -> Self {
+        AzureJwtHeader {
+            typ: header.typ.unwrap_or_default(),
+            alg: format!("{:?}", header.alg),
+            kid: header.kid.unwrap_or_default(),
+        }
+    }
+}
+
+/// A loose check for `local@domain.tld`, enough to tell an email address from a phone number or a
+/// plain username.
+#[cfg(feature = "azure-claims")]
+fn looks_like_email(s: &str) -> bool {
+    match s.split_once('@') {
+        Some((local, domain)) => !local.is_empty() && !domain.contains('@') && domain.contains('.'),
+        None => false,
+    }
+}
+
+/// The claims of an Azure id token, see:
+/// <https://docs.microsoft.com/en-us/azure/active-directory/develop/id-tokens>
+///
+/// New claims are added over time, so this can't be built with a struct literal outside of this
+/// crate. Start from `AzureJwtClaims::default()` and set the fields you need instead.
+///
+/// Claims we don't know, such as the opaque `aio` and `rh` Azure uses internally, are
+/// ignored so that new claims never break validation.
+#[cfg(feature = "azure-claims")]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct AzureJwtClaims {
     /// dentifies the intended recipient of the token. In id_tokens, the audience
     /// is your app's Application ID, assigned to your app in the Azure portal.
@@ -451,7 +2050,10 @@ pub struct AzureJwtClaims {
     pub appidacr: u32,
 
     /// Identifies how the subject of the token was authenticated.
-    /// Microsoft identities can authenticate in a variety of ways, which may be relevant to your application
+    /// Microsoft identities can authenticate in a variety of ways, which may be relevant to your application.
+    /// Common values are `pwd` (password), `mfa` (multi-factor authentication), `rsa` (a key,
+    /// i.e. Windows Hello or the Authenticator app), `otp`, `fed` (a federated identity
+    /// provider) and `wia` (Windows integrated authentication). See `require_mfa`.
     pub amr: Option<Vec<String>>,
 
     /// Identifies the security token service (STS) that constructs and returns
@@ -478,9 +2080,14 @@ pub struct AzureJwtClaims {
     /// tenant 9188040d-6c67-4c5b-b112-36a304b66dad
     pub idp: Option<String>,
 
-    /// The IP address the user authenticated from.
+    /// The IP address the user authenticated from, the `ipaddr` claim.
+    #[serde(alias = "ipaddr")]
     pub ipddr: Option<String>,
 
+    /// The standard JWT ID, a unique identifier of the token. Azure sends `uti` instead, see
+    /// `token_id`.
+    pub jti: Option<String>,
+
     /// Unix timestamp. The "nbf" (not before) claim identifies the time before
     /// which the JWT MUST NOT be accepted for processing.
     pub nbf: u64,
@@ -504,19 +2111,26 @@ pub struct AzureJwtClaims {
     /// validation, see the OpenID Connect specification.
     pub at_hash: Option<String>,
 
-    /// Provides the last name, surname, or family name of the user as defined on the user object.
-    pub family_name: Option<String>,
-
-    /// Provides the first or given name of the user, as set on the user object.
-    pub given_name: Option<String>,
-
     /// The email claim is present by default for guest accounts that have an
     /// email address. Your app can request the email claim for managed users
     /// (those from the same tenant as the resource) using the email optional
     /// claim. On the v2.0 endpoint, your app can also request the email OpenID
     /// Connect scope - you don't need to request both the optional claim and
     /// the scope to get the claim. The email claim only supports addressable
-    /// mail from the user's profile information.
+    /// mail from the user's profile information. See `best_email`.
+    pub email: Option<String>,
+
+    /// Provides the last name, surname, or family name of the user as defined on the user object.
+    pub family_name: Option<String>,
+
+    /// Provides the first or given name of the user, as set on the user object.
+    pub given_name: Option<String>,
+
+    /// The primary username that represents the user. It could be an email
+    /// address, phone number, or a generic username without a specified format.
+    /// For guest users it's often the UPN in their home tenant, which isn't
+    /// necessarily an address mail can be sent to. Only issued in v2.0 tokens,
+    /// the profile scope is required to receive this claim.
     pub preferred_username: Option<String>,
 
     /// The name claim provides a human-readable value that identifies the
@@ -545,6 +2159,24 @@ pub struct AzureJwtClaims {
     /// The set of roles that were assigned to the user who is logging in.
     pub roles: Option<Vec<String>>,
 
+    /// The object IDs of the groups the user is a member of, if the app registration is
+    /// configured to emit them. Left out when the user is in too many groups, see
+    /// `has_groups_overage`.
+    pub groups: Option<Vec<String>>,
+
+    /// Set instead of `groups` in tokens of the implicit flow when the user is in too many
+    /// groups, see `has_groups_overage`.
+    pub hasgroups: Option<bool>,
+
+    /// The claims that were left out of the token because they're too large, by name, pointing
+    /// to their source in `claim_sources`. Azure uses it for the `groups` overage.
+    #[serde(rename = "_claim_names")]
+    pub claim_names: Option<HashMap<String, String>>,
+
+    /// Where the claims in `claim_names` can be fetched from, by source name.
+    #[serde(rename = "_claim_sources")]
+    pub claim_sources: Option<HashMap<String, ClaimSource>>,
+
     /// The set of scopes exposed by your application for which the client
     /// application has requested (and received) consent. Your app should verify
     /// that these scopes are valid ones exposed by your app, and make authorization
@@ -563,7 +2195,7 @@ pub struct AzureJwtClaims {
     /// A GUID that represents the Azure AD tenant that the user is from.
     /// For work and school accounts, the GUID is the immutable tenant ID of
     /// the organization that the user belongs to. For personal accounts,
-    /// the value is 9188040d-6c67-4c5b-b112-36a304b66dad. The profile scope is
+    /// the value is `CONSUMER_TENANT_ID`, see `is_personal_account`. The profile scope is
     /// required to receive this claim.
     pub tid: String,
 
@@ -576,42 +2208,432 @@ pub struct AzureJwtClaims {
     /// Should only be used for display purposes and providing username hints in reauthentication scenarios.
     pub upn: Option<String>,
 
-    /// Indicates the version of the id_token. Either 1.0 or 2.0.
-    pub ver: String,
-}
+    /// The unique token identifier Azure issues, the same for every copy of a token. Useful to
+    /// detect replays or as the key of a revocation list, see `token_id`.
+    pub uti: Option<String>,
 
-#[derive(Debug, Deserialize)]
-struct JwkSet {
-    keys: Vec<Jwk>,
+    /// Indicates the version of the id_token. Either 1.0 or 2.0. Older v1.0 tokens may not
+    /// have it, see `version`.
+    pub ver: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
-pub struct Jwk {
-    pub kid: String,
-    pub n: String,
-    pub e: String,
-}
+#[cfg(feature = "azure-claims")]
+impl AzureJwtClaims {
+    /// Returns `true` if the object ID `group` is in the `groups` claim, ignoring case.
+    ///
+    /// Always `false` with the groups overage since the groups aren't in the token then, check
+    /// `has_groups_overage` first or users in many groups are denied access.
+    pub fn has_group(&self, group: &str) -> bool {
+        self.groups
+            .as_ref()
+            .is_some_and(|groups| groups.iter().any(|g| g.eq_ignore_ascii_case(group)))
+    }
 
-impl Jwk {
-    fn modulus(&self) -> &str {
-        &self.n
+    /// Returns `true` if the user is in too many groups for them to fit in the token, over 200
+    /// for JWTs and 6 for the implicit flow. `groups` is left out then and the groups have to be
+    /// fetched from Microsoft Graph, see `groups_overage_endpoint`.
+    pub fn has_groups_overage(&self) -> bool {
+        self.hasgroups == Some(true)
+            || self
+                .claim_names
+                .as_ref()
+                .is_some_and(|names| names.contains_key("groups"))
     }
 
-    fn exponent(&self) -> &str {
-        &self.e
+    /// The endpoint the token points to for fetching its groups with the groups overage. It's
+    /// the legacy Azure AD Graph API, new code should call the Microsoft Graph `memberOf` or
+    /// `getMemberObjects` API of the user instead. `None` without the overage or in tokens of
+    /// the implicit flow, which don't say.
+    pub fn groups_overage_endpoint(&self) -> Option<&str> {
+        let source = self.claim_names.as_ref()?.get("groups")?;
+        let source = self.claim_sources.as_ref()?.get(source)?;
+        Some(&source.endpoint)
     }
-}
 
-#[derive(Deserialize)]
-struct OpenIdResponse {
-    jwks_uri: String,
-}
+    /// Returns `true` if `role` is one of the roles assigned to the user. A token without a
+    /// `roles` claim has no roles.
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles
+            .as_ref()
+            .is_some_and(|roles| roles.iter().any(|r| r == role))
+    }
 
-type Token<T> = jwt::TokenData<T>;
+    /// The version of the token according to its `ver` claim, `TokenVersion::V1` if it has
+    /// none like some older v1.0 tokens. `None` if the version is unknown.
+    pub fn version(&self) -> Option<TokenVersion> {
+        match self.ver.as_deref() {
+            None | Some("1.0") => Some(TokenVersion::V1),
+            Some("2.0") => Some(TokenVersion::V2),
+            Some(_) => None,
+        }
+    }
 
-#[cfg(test)]
-mod tests {
-    use simple_base64::{engine::general_purpose, Engine};
+    /// The time left until the token expires according to its `exp` claim, without the leeway.
+    /// Zero if it's already expired. Useful to cache an authorization for the remaining life of
+    /// the token or to ask the user to sign in again before it runs out.
+    pub fn time_until_expiry(&self) -> Duration {
+        let left = self.exp as i64 - Timestamp::now().unix();
+        Duration::from_secs(left.max(0) as u64)
+    }
+
+    /// The unique identifier of the token: the `uti` claim, or the `jti` claim for tokens
+    /// without one. `None` if the token has neither.
+    pub fn token_id(&self) -> Option<&str> {
+        self.uti.as_deref().or(self.jti.as_deref())
+    }
+
+    /// Returns `true` if the token is from a personal Microsoft account rather than a work or
+    /// school account, i.e. its `tid` is the `CONSUMER_TENANT_ID`.
+    pub fn is_personal_account(&self) -> bool {
+        self.tid.eq_ignore_ascii_case(CONSUMER_TENANT_ID)
+    }
+
+    /// Checks that the user authenticated with multi-factor authentication, i.e. the `amr` claim
+    /// contains `mfa`. Use it to protect sensitive endpoints.
+    ///
+    /// # Errors
+    ///
+    /// `AuthErr::MfaRequired` if it doesn't, or the token has no `amr` claim.
+    pub fn require_mfa(&self) -> Result<(), AuthErr> {
+        match &self.amr {
+            Some(amr) if amr.iter().any(|method| method == "mfa") => Ok(()),
+            _ => Err(AuthErr::MfaRequired),
+        }
+    }
+
+    /// The scopes in the `scp` claim, which is a space separated list. Empty if the token has
+    /// no `scp` claim.
+    pub fn scopes(&self) -> Vec<&str> {
+        self.scp
+            .as_deref()
+            .map(|scp| scp.split_whitespace().collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns `true` if `scope` is one of the scopes in the `scp` claim.
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes().contains(&scope)
+    }
+
+    /// The best email address we have for the user: the `email` claim, or else
+    /// `preferred_username` or `upn` if it looks like an email address. `None` if none is usable.
+    ///
+    /// Prefer this over `preferred_username` when you need to send mail, guest users often have
+    /// an external UPN there, i.e. `abeli_contoso.com#EXT#@fabrikam.onmicrosoft.com`, which
+    /// looks like an address but can't receive mail. These are skipped.
+    pub fn best_email(&self) -> Option<&str> {
+        self.email.as_deref().or_else(|| {
+            [&self.preferred_username, &self.upn]
+                .into_iter()
+                .flatten()
+                .map(String::as_str)
+                .find(|name| looks_like_email(name) && !name.contains("#EXT#"))
+        })
+    }
+
+    /// A minimal view of who the token identifies, the same for v1.0 and v2.0 tokens.
+    pub fn principal(&self) -> Principal {
+        Principal {
+            object_id: self.oid.clone(),
+            tenant_id: self.tid.clone(),
+            username: self
+                .preferred_username
+                .as_ref()
+                .or(self.upn.as_ref())
+                .or(self.unique_name.as_ref())
+                .cloned(),
+            name: self.name.clone(),
+            roles: self.roles.clone().unwrap_or_default(),
+            scopes: self.scopes().into_iter().map(String::from).collect(),
+        }
+    }
+
+    /// The `oid` claim parsed as a `Uuid`.
+    ///
+    /// # Errors
+    ///
+    /// `AuthErr::ParseError` if the claim isn't a valid GUID.
+    #[cfg(feature = "uuid")]
+    pub fn object_id(&self) -> Result<uuid::Uuid, AuthErr> {
+        parse_guid("oid", &self.oid)
+    }
+
+    /// The `tid` claim parsed as a `Uuid`.
+    ///
+    /// # Errors
+    ///
+    /// `AuthErr::ParseError` if the claim isn't a valid GUID.
+    #[cfg(feature = "uuid")]
+    pub fn tenant_id(&self) -> Result<uuid::Uuid, AuthErr> {
+        parse_guid("tid", &self.tid)
+    }
+}
+
+#[cfg(all(feature = "uuid", feature = "azure-claims"))]
+fn parse_guid(claim: &str, value: &str) -> Result<uuid::Uuid, AuthErr> {
+    uuid::Uuid::parse_str(value)
+        .map_err(|e| AuthErr::ParseError(format!("`{}` is not a valid GUID. {}", claim, e)))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(from = "RawJwkSet")]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+/// A JWK set as published. We verify with RSA and Ed25519 (`OKP`) keys, other keys, such as EC
+/// keys, are skipped instead of failing to parse the whole set.
+#[derive(Deserialize)]
+struct RawJwkSet {
+    keys: Vec<RawJwk>,
+}
+
+#[derive(Deserialize)]
+struct RawJwk {
+    kid: Option<String>,
+    kty: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    crv: Option<String>,
+    x: Option<String>,
+    alg: Option<String>,
+}
+
+impl From<RawJwkSet> for JwkSet {
+    fn from(raw: RawJwkSet) -> Self {
+        let keys = raw
+            .keys
+            .into_iter()
+            .filter_map(|k| {
+                let key = match (k.kty.as_deref(), k.kid, k.n, k.e, k.crv.as_deref(), k.x) {
+                    (None | Some("RSA"), Some(kid), Some(n), Some(e), _, _) => Jwk::new(kid, n, e),
+                    (Some("OKP"), Some(kid), _, _, Some("Ed25519"), Some(x)) => {
+                        Jwk::ed25519(kid, x)
+                    }
+                    _ => return None,
+                };
+                Some(Jwk { alg: k.alg, ..key })
+            })
+            .collect();
+
+        JwkSet { keys }
+    }
+}
+
+/// A public key of a JWK set. Build one with `Jwk::new` or `Jwk::ed25519`, new fields are added
+/// as they're needed so it can't be built with a struct literal.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+#[non_exhaustive]
+pub struct Jwk {
+    pub kid: String,
+    /// The modulus of an RSA key, empty for other keys.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub n: String,
+    /// The exponent of an RSA key, empty for other keys.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub e: String,
+    /// The algorithm the key is meant to be used with, i.e. `RS256`. Not every JWK set
+    /// advertises it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alg: Option<String>,
+    /// The key type, `RSA` or `OKP`. `None` is an RSA key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kty: Option<String>,
+    /// The curve of an `OKP` key, `Ed25519`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crv: Option<String>,
+    /// The public key of an `OKP` key, base64url encoded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x: Option<String>,
+}
+
+impl Jwk {
+    /// An RSA key with the given `kid`, modulus `n` and exponent `e`, both base64url encoded as
+    /// in a JWK set.
+    pub fn new(kid: impl Into<String>, n: impl Into<String>, e: impl Into<String>) -> Self {
+        Jwk {
+            kid: kid.into(),
+            n: n.into(),
+            e: e.into(),
+            kty: Some("RSA".to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// An Ed25519 key with the given `kid` and public key `x`, base64url encoded as in a JWK
+    /// set. Verifies `EdDSA` tokens, see `set_algorithms`.
+    pub fn ed25519(kid: impl Into<String>, x: impl Into<String>) -> Self {
+        Jwk {
+            kid: kid.into(),
+            kty: Some("OKP".to_string()),
+            crv: Some("Ed25519".to_string()),
+            x: Some(x.into()),
+            ..Default::default()
+        }
+    }
+
+    /// Whether tokens signed with `alg` can be verified with this key, judging by its type.
+    pub(crate) fn supports(&self, alg: jwt::Algorithm) -> bool {
+        use jwt::Algorithm::*;
+
+        match self.kty.as_deref() {
+            Some("OKP") => alg == EdDSA,
+            _ => matches!(alg, RS256 | RS384 | RS512 | PS256 | PS384 | PS512),
+        }
+    }
+
+    fn modulus(&self) -> &str {
+        &self.n
+    }
+
+    fn exponent(&self) -> &str {
+        &self.e
+    }
+}
+
+/// The check set with `set_revocation_check`.
+#[cfg(feature = "azure-claims")]
+#[derive(Clone)]
+struct RevocationCheck(std::sync::Arc<dyn Fn(&AzureJwtClaims) -> bool + Send + Sync>);
+
+#[cfg(feature = "azure-claims")]
+impl std::fmt::Debug for RevocationCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RevocationCheck")
+    }
+}
+
+/// The resolver set with `set_audience_resolver`.
+#[cfg(feature = "azure-claims")]
+#[derive(Clone)]
+struct AudienceResolver(std::sync::Arc<ResolveAudiences>);
+
+#[cfg(feature = "azure-claims")]
+type ResolveAudiences = dyn Fn(&AzureJwtClaims) -> Vec<String> + Send + Sync;
+
+#[cfg(feature = "azure-claims")]
+impl std::fmt::Debug for AudienceResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AudienceResolver")
+    }
+}
+
+/// The configuration `from_env` reads from the environment.
+#[derive(Debug, PartialEq, Eq)]
+struct EnvConfig {
+    audience: String,
+    tenant: String,
+    authority_host: String,
+}
+
+impl EnvConfig {
+    /// Reads the configuration with `lookup`, `env_var` outside of tests.
+    fn from_vars(
+        lookup: impl Fn(&str) -> Result<Option<String>, AuthErr>,
+    ) -> Result<Self, AuthErr> {
+        let audience = lookup("AZURE_CLIENT_ID")?
+            .ok_or_else(|| AuthErr::Other("`AZURE_CLIENT_ID` is not set.".into()))?;
+        let tenant = lookup("AZURE_TENANT_ID")?.unwrap_or_else(|| AZ_DEFAULT_TENANT.into());
+        let authority_host = match lookup("AZURE_AUTHORITY_HOST")? {
+            Some(host) => {
+                let host = host.trim_end_matches('/');
+                let is_https = host
+                    .get(..8)
+                    .is_some_and(|scheme| scheme.eq_ignore_ascii_case("https://"));
+                if !is_https || host.len() == 8 {
+                    return Err(AuthErr::Other(format!(
+                        "`AZURE_AUTHORITY_HOST` is `{}`, not an https url.",
+                        host
+                    )));
+                }
+                host.to_string()
+            }
+            None => AZ_LOGIN_URL.into(),
+        };
+        Ok(EnvConfig {
+            audience,
+            tenant,
+            authority_host,
+        })
+    }
+}
+
+/// Reads an environment variable for `from_env`, `None` if it's unset or empty.
+fn env_var(name: &str) -> Result<Option<String>, AuthErr> {
+    match std::env::var(name) {
+        Ok(value) if value.is_empty() => Ok(None),
+        Ok(value) => Ok(Some(value)),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(std::env::VarError::NotUnicode(_)) => {
+            Err(AuthErr::Other(format!("`{}` is not valid unicode.", name)))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct UnverifiedTenant {
+    tid: Option<String>,
+}
+
+/// Whether `aud` looks like an audience: a GUID or a URI, not a display name.
+fn check_audience_format(aud: &str) -> Result<(), AuthErr> {
+    let is_guid = aud.len() == 36
+        && aud.char_indices().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        });
+    let is_uri = match aud.split_once("://") {
+        Some((scheme, rest)) => {
+            !scheme.is_empty()
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+                && !rest.is_empty()
+                && !aud.contains(char::is_whitespace)
+        }
+        None => false,
+    };
+    match is_guid || is_uri {
+        true => Ok(()),
+        false => Err(AuthErr::UnexpectedAudienceFormat(aud.to_string())),
+    }
+}
+
+/// Reads the `tid` claim of a token without verifying anything.
+fn unverified_tenant(token: &str) -> Result<String, AuthErr> {
+    oidc::check_structure(token)?;
+    let decoded: Token<UnverifiedTenant> = oidc::insecure_decode(token)?;
+    decoded
+        .claims
+        .tid
+        .ok_or_else(|| AuthErr::ParseError("No `tid` in token.".into()))
+}
+
+/// Reads the token from an `Authorization: Bearer <token>` header.
+#[cfg(all(feature = "http", feature = "azure-claims"))]
+fn bearer_token(headers: &http::HeaderMap) -> Result<&str, AuthErr> {
+    let header = headers
+        .get(http::header::AUTHORIZATION)
+        .ok_or(AuthErr::MissingAuthorizationHeader)?
+        .to_str()
+        .map_err(|_| AuthErr::UnsupportedAuthorizationScheme("<non-ascii>".into()))?;
+
+    let (scheme, token) = header.split_once(' ').unwrap_or((header, ""));
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return Err(AuthErr::UnsupportedAuthorizationScheme(scheme.to_string()));
+    }
+
+    match token.trim() {
+        "" => Err(AuthErr::EmptyBearerToken),
+        token => Ok(token),
+    }
+}
+
+type Token<T> = jwt::TokenData<T>;
+
+#[cfg(all(test, feature = "azure-claims"))]
+mod tests {
+    use simple_base64::{engine::general_purpose, Engine};
 
     use super::*;
 
@@ -655,6 +2677,11 @@ xMd+OWT6JsInVM1ASh1mcn+Q0/Z3WqxxetCQLqaMs+FATn059dGf";
     }
 
     fn test_token_claims() -> String {
+        let now = chrono::Utc::now().timestamp();
+        test_token_claims_at(now - 1000, now - 2000, now + 1000)
+    }
+
+    fn test_token_claims_at(iat: i64, nbf: i64, exp: i64) -> String {
         format!(
             r#"{{
                 "aud": "6e74172b-be56-4843-9ff4-e66a39bb12e3",
@@ -677,21 +2704,24 @@ xMd+OWT6JsInVM1ASh1mcn+Q0/Z3WqxxetCQLqaMs+FATn059dGf";
                 "uti": "fqiBqXLPj0eQa82S-IYFAA",
                 "ver": "2.0"
             }}"#,
-            chrono::Utc::now().timestamp() - 1000,
-            chrono::Utc::now().timestamp() - 2000,
-            chrono::Utc::now().timestamp() + 1000
+            iat, nbf, exp
         )
     }
 
     // We create a test token from parts here. We use the v2 token used as example
     // in https://docs.microsoft.com/en-us/azure/active-directory/develop/id-tokens
     fn generate_test_token() -> String {
-        let private_key = jwt::EncodingKey::from_base64_secret(PRIVATE_KEY_TEST).unwrap();
-
         // we need to construct the calims in a function since we need to set
         // the expiration relative to current time
-        let test_token_payload = test_token_claims();
-        let test_token_header = test_token_header();
+        generate_test_token_from(&test_token_claims())
+    }
+
+    fn generate_test_token_from(test_token_payload: &str) -> String {
+        generate_test_token_with(&test_token_header(), test_token_payload)
+    }
+
+    fn generate_test_token_with(test_token_header: &str, test_token_payload: &str) -> String {
+        let private_key = jwt::EncodingKey::from_base64_secret(PRIVATE_KEY_TEST).unwrap();
 
         // we base64 (url-safe-base64) the header and claims and arrange
         // as a jwt payload -> header_as_base64.claims_as_base64
@@ -703,15 +2733,17 @@ xMd+OWT6JsInVM1ASh1mcn+Q0/Z3WqxxetCQLqaMs+FATn059dGf";
 
         // we create the signature using our private key
         let signature =
-            jwt::crypto::sign(&test_token, &private_key, jwt::Algorithm::RS256).expect("Signed");
-
-        let public_key = Jwk {
-            kid: "".to_string(),
-            n: PUBLIC_KEY_N.to_string(),
-            e: PUBLIC_KEY_E.to_string(),
-        };
+            jwt::crypto::sign(test_token.as_bytes(), &private_key, jwt::Algorithm::RS256)
+                .expect("Signed");
 
-        let public_key = DecodingKey::from_rsa_components(&public_key.n, &public_key.e);
+        // ring wants the modulus without the leading zero of its DER integer
+        let n = general_purpose::URL_SAFE_NO_PAD
+            .decode(PUBLIC_KEY_N)
+            .unwrap();
+        let e = general_purpose::URL_SAFE_NO_PAD
+            .decode(PUBLIC_KEY_E)
+            .unwrap();
+        let public_key = jwt::DecodingKey::from_rsa_raw_components(&n[1..], &e);
 
         // we construct a complete token which looks like: header.claims.signature
         let complete_token = format!("{}.{}", test_token, signature);
@@ -719,14 +2751,116 @@ xMd+OWT6JsInVM1ASh1mcn+Q0/Z3WqxxetCQLqaMs+FATn059dGf";
         // we verify the signature here as well to catch errors in our testing
         // code early
 
-        let verified =
-            jwt::crypto::verify(&signature, &test_token, &public_key, jwt::Algorithm::RS256)
-                .expect("verified");
+        let verified = jwt::crypto::verify(
+            &signature,
+            test_token.as_bytes(),
+            &public_key,
+            jwt::Algorithm::RS256,
+        )
+        .expect("verified");
         assert!(verified);
 
         complete_token
     }
 
+    /// Serves `responses` in order, one per connection, on a local port and returns its url.
+    fn serve(responses: Vec<(u16, String)>) -> String {
+        serve_recorded(responses).0
+    }
+
+    /// Like `serve`, the handle returns the requests that were received once every response is
+    /// sent.
+    fn serve_recorded(
+        responses: Vec<(u16, String)>,
+    ) -> (String, std::thread::JoinHandle<Vec<String>>) {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+                // read the headers, then as much of the body as they announce
+                while !request_complete(&request) {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                requests.push(String::from_utf8_lossy(&request).into_owned());
+                let resp = format!(
+                    "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(resp.as_bytes());
+            }
+            requests
+        });
+        (url, server)
+    }
+
+    fn request_complete(request: &[u8]) -> bool {
+        let request = String::from_utf8_lossy(request);
+        let Some((headers, body)) = request.split_once("\r\n\r\n") else {
+            return false;
+        };
+        let len = headers
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("content-length")
+                    .then(|| value.trim().parse().ok())?
+            })
+            .unwrap_or(0);
+        body.len() >= len
+    }
+
+    fn test_key() -> Jwk {
+        Jwk {
+            kid: "i6lGk3FZzxRcUb2C3nEQ7syHJlY".to_string(),
+            n: PUBLIC_KEY_N.to_string(),
+            e: PUBLIC_KEY_E.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// OpenID metadata with only the fields we validate with.
+    fn test_discovery(issuer: &str, jwks_uri: &str) -> OpenIdConfiguration {
+        OpenIdConfiguration {
+            issuer: issuer.to_string(),
+            jwks_uri: jwks_uri.to_string(),
+            authorization_endpoint: None,
+            token_endpoint: None,
+            userinfo_endpoint: None,
+            end_session_endpoint: None,
+            device_authorization_endpoint: None,
+            id_token_signing_alg_values_supported: None,
+            scopes_supported: None,
+            claims_supported: None,
+            response_types_supported: None,
+        }
+    }
+
+    /// A JWK set with just `test_key()`, as served by Microsoft.
+    fn test_jwks_json() -> String {
+        format!(
+            r#"{{"keys": [{{"kty": "RSA", "kid": "{}", "n": "{}", "e": "{}"}}]}}"#,
+            test_key().kid,
+            PUBLIC_KEY_N,
+            PUBLIC_KEY_E
+        )
+    }
+
+    /// An offline instance for our test audience which trusts `test_key`.
+    fn test_auth() -> AzureAuth {
+        AzureAuth::new_offline("6e74172b-be56-4843-9ff4-e66a39bb12e3", vec![test_key()]).unwrap()
+    }
+
     #[tokio::test]
     async fn decode_token() {
         let token = generate_test_token();
@@ -741,6 +2875,7 @@ xMd+OWT6JsInVM1ASh1mcn+Q0/Z3WqxxetCQLqaMs+FATn059dGf";
             kid: "i6lGk3FZzxRcUb2C3nEQ7syHJlY".to_string(),
             n: PUBLIC_KEY_N.to_string(),
             e: PUBLIC_KEY_E.to_string(),
+            ..Default::default()
         };
 
         let mut az_auth =
@@ -749,7 +2884,2176 @@ xMd+OWT6JsInVM1ASh1mcn+Q0/Z3WqxxetCQLqaMs+FATn059dGf";
         az_auth.validate_token(&token).await.unwrap();
     }
 
-    // TODO: we need a test for the retry operation.
+    #[tokio::test]
+    async fn set_audience_keeps_keys() {
+        let token = generate_test_token();
+        let key = test_key();
+
+        let mut az_auth = AzureAuth::new_offline("another_client_id", vec![key]).unwrap();
+        assert!(az_auth.validate_token(&token).await.is_err());
+
+        az_auth.set_audience("6e74172b-be56-4843-9ff4-e66a39bb12e3");
+        az_auth.validate_token(&token).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn set_tenant_offline_keeps_keys() {
+        let mut az_auth = AzureAuth::new_offline("app_secret", vec![]).unwrap();
+        az_auth
+            .set_tenant("72f988bf-86f1-41af-91ab-2d7cd011db47")
+            .await
+            .unwrap();
+
+        assert_eq!(az_auth.tenant, "72f988bf-86f1-41af-91ab-2d7cd011db47");
+        assert!(az_auth.oidc.public_keys.is_some());
+    }
+
+    #[tokio::test]
+    async fn unknown_key_is_client_error() {
+        let token = generate_test_token();
+        let mut az_auth =
+            AzureAuth::new_offline("6e74172b-be56-4843-9ff4-e66a39bb12e3", vec![]).unwrap();
+
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(err, AuthErr::KeyNotFound(_)));
+        assert!(err.is_client_error());
+        assert!(!err.is_server_error());
+    }
+
+    #[tokio::test]
+    async fn multi_tenant_rejects_unknown_tenant() {
+        let token = generate_test_token();
+        let mut az_auth = AzureAuth::new_multi_tenant(
+            "6e74172b-be56-4843-9ff4-e66a39bb12e3",
+            vec!["9188040d-6c67-4c5b-b112-36a304b66dad".to_string()],
+        )
+        .unwrap();
+
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(
+            matches!(err, AuthErr::TenantNotAllowed(tid) if tid == "72f988bf-86f1-41af-91ab-2d7cd011db47")
+        );
+    }
+
+    #[test]
+    fn parse_openid_configuration() {
+        let document = r#"{
+            "token_endpoint": "https://login.microsoftonline.com/common/oauth2/token",
+            "jwks_uri": "https://login.microsoftonline.com/common/discovery/keys",
+            "response_types_supported": ["code", "id_token", "code id_token", "token id_token", "token"],
+            "id_token_signing_alg_values_supported": ["RS256"],
+            "issuer": "https://sts.windows.net/{tenantid}/",
+            "authorization_endpoint": "https://login.microsoftonline.com/common/oauth2/authorize",
+            "http_logout_supported": true,
+            "end_session_endpoint": "https://login.microsoftonline.com/common/oauth2/logout",
+            "cloud_instance_name": "microsoftonline.com"
+        }"#;
+
+        let discovery: OpenIdConfiguration = serde_json::from_str(document).unwrap();
+        assert_eq!(discovery.issuer, "https://sts.windows.net/{tenantid}/");
+        assert_eq!(
+            discovery.jwks_uri,
+            "https://login.microsoftonline.com/common/discovery/keys"
+        );
+        assert!(discovery.userinfo_endpoint.is_none());
+    }
+
+    #[test]
+    fn jwk_set_skips_unsupported_keys() {
+        let jwks = r#"{
+            "keys": [
+                { "kty": "RSA", "use": "sig", "kid": "rsa", "n": "AQAB", "e": "AQAB" },
+                { "kty": "EC", "use": "sig", "kid": "ec", "crv": "P-256", "x": "f83O", "y": "x_FE" },
+                { "kty": "OKP", "use": "sig", "kid": "ed", "crv": "Ed25519", "x": "11qY" }
+            ]
+        }"#;
+
+        let jwks: JwkSet = serde_json::from_str(jwks).unwrap();
+        let kids: Vec<&str> = jwks.keys.iter().map(|k| k.kid.as_str()).collect();
+        assert_eq!(kids, ["rsa", "ed"]);
+        assert_eq!(jwks.keys[1].x.as_deref(), Some("11qY"));
+    }
+
+    #[test]
+    fn jwk_set_keeps_the_key_type() {
+        let jwks = r#"{ "keys": [{ "kty": "RSA", "kid": "rsa", "n": "AQAB", "e": "AQAB" }] }"#;
+        let jwks: JwkSet = serde_json::from_str(jwks).unwrap();
+
+        let serialized = serde_json::to_value(&jwks).unwrap();
+        assert_eq!(serialized["keys"][0]["kty"], "RSA");
+        assert_eq!(
+            serde_json::to_value(Jwk::new("rsa", "AQAB", "AQAB")).unwrap(),
+            serialized["keys"][0]
+        );
+    }
+
+    #[tokio::test]
+    async fn validates_eddsa_tokens() {
+        // the Ed25519 key of RFC 8037, appendix A.1, as PKCS#8 for signing
+        let seed = general_purpose::URL_SAFE_NO_PAD
+            .decode("nWGxne_9WmC6hEr0kuwsxERJxWl7MmkZcDusAxyuf2A")
+            .unwrap();
+        let mut pkcs8 = vec![
+            0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22,
+            0x04, 0x20,
+        ];
+        pkcs8.extend(seed);
+        let private_key = jwt::EncodingKey::from_ed_der(&pkcs8);
+        let ed_key = Jwk::ed25519("ed", "11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo");
+
+        let header = r#"{"typ": "JWT", "alg": "EdDSA", "kid": "ed"}"#;
+        let message = format!(
+            "{}.{}",
+            general_purpose::URL_SAFE_NO_PAD.encode(header),
+            general_purpose::URL_SAFE_NO_PAD.encode(test_token_claims())
+        );
+        let signature =
+            jwt::crypto::sign(message.as_bytes(), &private_key, jwt::Algorithm::EdDSA).unwrap();
+        let token = format!("{}.{}", message, signature);
+
+        let mut az_auth = AzureAuth::new_offline(
+            "6e74172b-be56-4843-9ff4-e66a39bb12e3",
+            vec![ed_key, test_key()],
+        )
+        .unwrap();
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(&err, AuthErr::InvalidToken(e)
+            if matches!(e.kind(), jwt::errors::ErrorKind::InvalidAlgorithm)));
+
+        az_auth.set_algorithms(vec![jwt::Algorithm::RS256, jwt::Algorithm::EdDSA]);
+        let claims = az_auth.validate_token(&token).await.unwrap().claims;
+        assert_eq!(claims.sub, "HKZpfaHyWadeOouYlitjrI-KffTm222X5rrV3xDqfKQ");
+        // the RSA key keeps verifying RS256 tokens
+        az_auth
+            .validate_token(&generate_test_token())
+            .await
+            .unwrap();
+
+        let tampered = format!("{}.{}", message.replace('J', "K"), signature);
+        assert!(az_auth.validate_token(&tampered).await.is_err());
+
+        // an RS256 token can't be verified with the Ed25519 key
+        let rs256 = generate_test_token_with(
+            r#"{"typ": "JWT", "alg": "RS256", "kid": "ed"}"#,
+            &test_token_claims(),
+        );
+        let err = az_auth.validate_token(&rs256).await.unwrap_err();
+        assert!(matches!(err, AuthErr::AlgorithmKeyMismatch { key, .. } if key == "OKP"));
+    }
+
+    #[test]
+    fn tenant_cache_evicts_least_recently_used() {
+        let mut cache = TenantCache::new(3);
+        cache.put("a".into(), TenantKeys::default());
+        cache.put("b".into(), TenantKeys::default());
+        let a = cache.take("a").unwrap();
+        cache.put("a".into(), a);
+        cache.put("c".into(), TenantKeys::default());
+        cache.put("C".into(), TenantKeys::default());
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.take("b").is_none());
+        assert!(cache.take("a").is_some());
+        assert!(cache.take("c").is_some());
+    }
+
+    #[test]
+    fn tenant_cache_shrinks_and_drops_expired() {
+        let now = Timestamp::now();
+        let mut cache = TenantCache::new(10);
+        let refreshed_at = |last_refresh| TenantKeys {
+            last_refresh: Some(last_refresh),
+            ..Default::default()
+        };
+        cache.put("a".into(), refreshed_at(now));
+        cache.put("b".into(), refreshed_at(now - clock::hours(25)));
+        cache.put("c".into(), refreshed_at(now));
+        cache.put("d".into(), TenantKeys::default());
+
+        cache.evict_expired(clock::hours(24));
+        assert_eq!(cache.len(), 3);
+
+        cache.set_max_entries(2);
+        assert_eq!(cache.len(), 1);
+        assert!(cache.take("D").is_some());
+    }
+
+    #[tokio::test]
+    async fn validate_many_keeps_per_token_results() {
+        let token = generate_test_token();
+        let mut az_auth = test_auth();
+
+        let results = az_auth.validate_many(&[&token, "not_a_token"]).await;
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+    }
+
+    #[tokio::test]
+    async fn validate_many_fetches_once_when_the_refresh_fails() {
+        let token = generate_test_token();
+        let (jwks_uri, requests) = serve_recorded(vec![(404, String::new())]);
+        let mut az_auth = AzureAuth::new_seeded(
+            "6e74172b-be56-4843-9ff4-e66a39bb12e3",
+            jwks_uri.clone(),
+            vec![test_key()],
+        )
+        .unwrap();
+        az_auth.set_min_fetch_interval(Duration::ZERO);
+        az_auth.set_last_refresh(SystemTime::now() - clock::hours(25));
+
+        let results = az_auth.validate_many(&[&token, &token, &token]).await;
+        assert_eq!(results.len(), 3);
+        match &results[0] {
+            Err(AuthErr::HttpError { url, status, .. }) => {
+                assert_eq!(url, &jwks_uri);
+                assert_eq!(*status, Some(404));
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        for result in &results[1..] {
+            assert!(matches!(result, Err(AuthErr::KeysStale)), "{:?}", result);
+        }
+        assert_eq!(requests.join().unwrap().len(), 1);
+
+        // the network is only disabled for the rest of the batch
+        assert!(!az_auth.oidc.is_network_disabled);
+    }
+
+    #[tokio::test]
+    async fn validate_for_role_rejects_token_without_roles() {
+        let token = generate_test_token();
+        let mut az_auth = test_auth();
+
+        let err = az_auth
+            .validate_for_role(&token, "admin")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AuthErr::MissingRole(role) if role == "admin"));
+    }
+
+    #[tokio::test]
+    async fn iat_in_future_uses_iat_leeway() {
+        let now = chrono::Utc::now().timestamp();
+        let token =
+            generate_test_token_from(&test_token_claims_at(now + 300, now - 2000, now + 1000));
+
+        let mut az_auth = test_auth();
+
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(err, AuthErr::IssuedInFuture));
+
+        az_auth.set_iat_leeway(600);
+        az_auth.validate_token(&token).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn issuer_is_validated_against_discovery() {
+        let token = generate_test_token();
+        let mut az_auth = test_auth();
+        let discovery = |issuer: &str| test_discovery(issuer, "");
+
+        az_auth.set_discovery(discovery("https://sts.windows.net/{tenantid}/"));
+        az_auth.set_discovery_v2(discovery(
+            "https://login.microsoftonline.com/{tenantid}/v2.0",
+        ));
+        az_auth.validate_token(&token).await.unwrap();
+
+        az_auth.set_discovery_v2(discovery("https://login.microsoftonline.com/other/v2.0"));
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(err, AuthErr::InvalidIssuer { .. }));
+    }
+
+    #[tokio::test]
+    async fn try_validate_reports_stale_keys() {
+        let token = generate_test_token();
+        let mut az_auth = AzureAuth::new_seeded(
+            "6e74172b-be56-4843-9ff4-e66a39bb12e3",
+            "http://127.0.0.1:9/keys",
+            vec![test_key()],
+        )
+        .unwrap();
+        az_auth.try_validate(&token).unwrap();
+
+        az_auth.set_last_refresh(SystemTime::now() - clock::hours(25));
+        let err = az_auth.try_validate(&token).unwrap_err();
+        assert!(matches!(err, AuthErr::KeysStale));
+    }
+
+    #[tokio::test]
+    async fn try_validate_ignores_case_for_the_loaded_tenant() {
+        let mut az_auth = AzureAuth::new_multi_tenant(
+            "6e74172b-be56-4843-9ff4-e66a39bb12e3",
+            vec!["72f988bf-86f1-41af-91ab-2d7cd011db47".into()],
+        )
+        .unwrap();
+        az_auth.tenant = "72F988BF-86F1-41AF-91AB-2D7CD011DB47".into();
+        az_auth.oidc.jwks_uri = "https://login.microsoftonline.com/keys".into();
+        az_auth.set_public_keys(vec![test_key()]);
+        az_auth.set_last_refresh(SystemTime::now());
+
+        az_auth.try_validate(&generate_test_token()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn set_tenant_ignores_case() {
+        let mut az_auth = AzureAuth::new_seeded(
+            "6e74172b-be56-4843-9ff4-e66a39bb12e3",
+            "http://127.0.0.1:9/keys",
+            vec![test_key()],
+        )
+        .unwrap();
+        az_auth.set_network_disabled(true);
+
+        az_auth.set_tenant("COMMON").await.unwrap();
+        assert_eq!(az_auth.tenant, "common");
+        az_auth.try_validate(&generate_test_token()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn oidc_validator_checks_issuer() {
+        let token = generate_test_token();
+        let mut oidc =
+            OidcValidator::new_offline("6e74172b-be56-4843-9ff4-e66a39bb12e3", vec![test_key()])
+                .unwrap();
+        oidc.set_issuer(Some(
+            "https://login.microsoftonline.com/72f988bf-86f1-41af-91ab-2d7cd011db47/v2.0".into(),
+        ));
+        let decoded: Token<AzureJwtClaims> = oidc.validate(&token).await.unwrap();
+        assert_eq!(decoded.claims.tid, "72f988bf-86f1-41af-91ab-2d7cd011db47");
+
+        oidc.set_issuer(Some("https://accounts.google.com".into()));
+        let err = oidc.validate::<AzureJwtClaims>(&token).await.unwrap_err();
+        assert!(matches!(err, AuthErr::InvalidIssuer { .. }));
+    }
+
+    #[tokio::test]
+    async fn pinned_kids_reject_other_keys() {
+        let token = generate_test_token();
+        let mut az_auth = test_auth();
+        az_auth.set_pinned_kids(vec!["some-other-kid".to_string()]);
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(err, AuthErr::KidNotPinned(_)));
+
+        az_auth.set_pinned_kids(vec![test_key().kid]);
+        az_auth.validate_token(&token).await.unwrap();
+    }
+
+    #[cfg(feature = "uuid")]
+    #[tokio::test]
+    async fn object_and_tenant_id_as_uuid() {
+        let token = generate_test_token();
+        let mut az_auth = test_auth();
+        let mut claims = az_auth.validate_token(&token).await.unwrap().claims;
+        assert_eq!(
+            claims.tenant_id().unwrap().to_string(),
+            "72f988bf-86f1-41af-91ab-2d7cd011db47"
+        );
+
+        claims.oid = "not-a-guid".to_string();
+        assert!(matches!(claims.object_id(), Err(AuthErr::ParseError(_))));
+    }
+
+    #[tokio::test]
+    async fn http_error_has_url_and_status() {
+        // a server error is retried once
+        let url = serve(vec![(503, String::new()), (503, String::new())]);
+        let err = OidcValidator::from_metadata_url(url.clone(), "aud")
+            .await
+            .unwrap_err();
+        match err {
+            AuthErr::HttpError {
+                url: failed,
+                status,
+                ..
+            } => {
+                assert_eq!(failed, url);
+                assert_eq!(status, Some(503));
+            }
+            err => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[tokio::test]
+    async fn only_transient_fetch_errors_are_retried() {
+        let token = generate_test_token();
+        let (jwks_uri, requests) = serve_recorded(vec![
+            (429, String::new()),
+            (200, test_jwks_json()),
+            (404, String::new()),
+            (200, String::new()),
+        ]);
+        let mut az_auth = AzureAuth::new_seeded(
+            "6e74172b-be56-4843-9ff4-e66a39bb12e3",
+            jwks_uri,
+            vec![test_key()],
+        )
+        .unwrap();
+        az_auth.set_min_fetch_interval(Duration::ZERO);
+
+        az_auth.set_last_refresh(SystemTime::now() - clock::hours(25));
+        az_auth.validate_token(&token).await.unwrap();
+
+        az_auth.set_last_refresh(SystemTime::now() - clock::hours(25));
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(
+            err,
+            AuthErr::HttpError {
+                status: Some(404),
+                ..
+            }
+        ));
+
+        // the 404 wasn't retried, so the next refresh gets the empty document
+        az_auth.set_last_refresh(SystemTime::now() - clock::hours(25));
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(err, AuthErr::HttpError { status: None, .. }));
+        assert_eq!(requests.join().unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn required_version_rejects_other_versions() {
+        let token = generate_test_token();
+        let mut az_auth = test_auth();
+        az_auth.set_required_version(Some(TokenVersion::V2));
+        az_auth.validate_token(&token).await.unwrap();
+
+        az_auth.set_required_version(Some(TokenVersion::V1));
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(err, AuthErr::UnexpectedTokenVersion { .. }));
+    }
+
+    #[tokio::test]
+    async fn seeded_keys_are_refreshed_on_expiry() {
+        let token = generate_test_token();
+        let jwks_uri = serve(vec![(200, test_jwks_json())]);
+        let mut az_auth = AzureAuth::new_seeded(
+            "6e74172b-be56-4843-9ff4-e66a39bb12e3",
+            jwks_uri,
+            vec![test_key()],
+        )
+        .unwrap();
+        az_auth.validate_token(&token).await.unwrap();
+
+        az_auth.set_last_refresh(SystemTime::now() - clock::hours(25));
+        az_auth.validate_token(&token).await.unwrap();
+        assert!(az_auth.oidc.is_keys_valid());
+    }
+
+    #[tokio::test]
+    async fn scopes_are_space_separated() {
+        let token = generate_test_token();
+        let mut az_auth = test_auth();
+        let mut claims = az_auth.validate_token(&token).await.unwrap().claims;
+        assert_eq!(claims.scopes(), vec!["access_as_user"]);
+
+        claims.scp = Some("User.Read  Mail.Send".to_string());
+        assert!(claims.has_scope("Mail.Send"));
+        assert!(!claims.has_scope("User.Read Mail.Send"));
+
+        claims.scp = None;
+        assert!(claims.scopes().is_empty());
+    }
+
+    #[tokio::test]
+    async fn network_disabled_reports_stale_keys() {
+        let token = generate_test_token();
+        let mut az_auth = AzureAuth::new_seeded(
+            "6e74172b-be56-4843-9ff4-e66a39bb12e3",
+            "http://127.0.0.1:9/keys",
+            vec![test_key()],
+        )
+        .unwrap();
+        az_auth.set_network_disabled(true);
+        az_auth.validate_token(&token).await.unwrap();
+
+        az_auth.set_last_refresh(SystemTime::now() - clock::hours(25));
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(err, AuthErr::KeysStale));
+    }
+
+    #[tokio::test]
+    async fn unknown_kid_refreshes_fresh_keys_once() {
+        let token = generate_test_token();
+        let stale_keys = vec![Jwk {
+            kid: "rotated-out".to_string(),
+            ..test_key()
+        }];
+        // only one response, a second refresh would fail with a connection error
+        let jwks_uri = serve(vec![(200, test_jwks_json())]);
+        let mut az_auth = AzureAuth::new_seeded(
+            "6e74172b-be56-4843-9ff4-e66a39bb12e3",
+            jwks_uri,
+            stale_keys.clone(),
+        )
+        .unwrap();
+        az_auth.validate_token(&token).await.unwrap();
+
+        az_auth.set_public_keys(stale_keys);
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(err, AuthErr::KeyNotFound(_)));
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn bearer_token_from_headers() {
+        let headers = |value: &str| {
+            let mut headers = http::HeaderMap::new();
+            headers.insert(http::header::AUTHORIZATION, value.parse().unwrap());
+            headers
+        };
+
+        assert_eq!(bearer_token(&headers("Bearer abc")).unwrap(), "abc");
+        assert!(matches!(
+            bearer_token(&http::HeaderMap::new()),
+            Err(AuthErr::MissingAuthorizationHeader)
+        ));
+        assert!(matches!(
+            bearer_token(&headers("Basic dXNlcg==")),
+            Err(AuthErr::UnsupportedAuthorizationScheme(_))
+        ));
+        assert!(matches!(
+            bearer_token(&headers("Bearer ")),
+            Err(AuthErr::EmptyBearerToken)
+        ));
+    }
+
+    #[tokio::test]
+    async fn key_alg_must_match_token_alg() {
+        let token = generate_test_token();
+        let key = Jwk {
+            alg: Some("RS512".to_string()),
+            ..test_key()
+        };
+        let mut az_auth =
+            AzureAuth::new_offline("6e74172b-be56-4843-9ff4-e66a39bb12e3", vec![key]).unwrap();
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(err, AuthErr::AlgorithmKeyMismatch { .. }));
+
+        az_auth.set_public_keys(vec![Jwk {
+            alg: Some("RS256".to_string()),
+            ..test_key()
+        }]);
+        az_auth.validate_token(&token).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn validate_detailed_reports_every_failure() {
+        let now = chrono::Utc::now().timestamp();
+        let token = generate_test_token_from(&test_token_claims_at(now + 500, now - 100, now - 30));
+        let mut az_auth = test_auth();
+        az_auth.set_leeway(10);
+
+        let detailed = az_auth.validate_detailed(&token).await.unwrap();
+        assert!(!detailed.is_valid());
+        assert_eq!(detailed.failures().len(), 2);
+        assert!(matches!(
+            detailed.failures()[0],
+            ValidationFailure::Expired { ago } if ago.as_secs() >= 30
+        ));
+        assert!(matches!(
+            detailed.failures()[1],
+            ValidationFailure::IssuedInFuture { .. }
+        ));
+        assert!(detailed.into_result().is_err());
+
+        let detailed = az_auth
+            .validate_detailed(&generate_test_token())
+            .await
+            .unwrap();
+        assert!(detailed.into_result().is_ok());
+    }
+
+    #[derive(Debug)]
+    struct FixtureKeys;
+
+    #[async_trait::async_trait]
+    impl KeySource for FixtureKeys {
+        async fn keys(&self) -> Result<Vec<Jwk>, AuthErr> {
+            Ok(vec![test_key()])
+        }
+    }
+
+    #[tokio::test]
+    async fn keys_come_from_key_source() {
+        let token = generate_test_token();
+        let mut az_auth =
+            AzureAuth::new_offline("6e74172b-be56-4843-9ff4-e66a39bb12e3", vec![]).unwrap();
+        az_auth.set_key_source(FixtureKeys);
+        az_auth.validate_token(&token).await.unwrap();
+    }
+
+    #[test]
+    fn validate_once_without_instance() {
+        let token = generate_test_token();
+        validate_once(
+            "6e74172b-be56-4843-9ff4-e66a39bb12e3",
+            &token,
+            &[test_key()],
+        )
+        .unwrap();
+
+        let err = validate_once("another-app", &token, &[test_key()]).unwrap_err();
+        assert!(err.is_client_error());
+    }
+
+    #[tokio::test]
+    async fn audience_check_can_be_disabled() {
+        let token = generate_test_token();
+        let mut az_auth = AzureAuth::new_offline("another-app", vec![test_key()]).unwrap();
+        assert!(az_auth.validate_token(&token).await.is_err());
+
+        az_auth.set_validate_audience(false);
+        az_auth.validate_token(&token).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn missing_claim_is_claims_parse_error() {
+        let claims =
+            test_token_claims().replace(r#""oid": "690222be-ff1a-4d56-abd1-7e4f7d38e474","#, "");
+        let token = generate_test_token_from(&claims);
+        let mut az_auth = test_auth();
+        match az_auth.validate_token(&token).await.unwrap_err() {
+            AuthErr::ClaimsParseError(msg) => assert!(msg.contains("oid")),
+            err => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_kid_uses_the_only_key() {
+        let header = r#"{"typ": "JWT", "alg": "RS256"}"#;
+        let token = generate_test_token_with(header, &test_token_claims());
+        let mut az_auth = test_auth();
+        az_auth.validate_token(&token).await.unwrap();
+
+        let other = Jwk {
+            kid: "other".to_string(),
+            ..test_key()
+        };
+        az_auth.set_public_keys(vec![test_key(), other]);
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(err, AuthErr::MissingKid));
+    }
+
+    #[test]
+    fn principal_picks_the_username() {
+        let mut claims = AzureJwtClaims {
+            oid: "690222be-ff1a-4d56-abd1-7e4f7d38e474".to_string(),
+            upn: Some("abeli@contoso.com".to_string()),
+            unique_name: Some("CONTOSO\\abeli".to_string()),
+            scp: Some("User.Read Mail.Send".to_string()),
+            ..Default::default()
+        };
+        let principal = claims.principal();
+        assert_eq!(principal.username.as_deref(), Some("abeli@contoso.com"));
+        assert_eq!(principal.scopes, vec!["User.Read", "Mail.Send"]);
+        assert!(principal.roles.is_empty());
+
+        claims.preferred_username = Some("abeli@microsoft.com".to_string());
+        assert_eq!(
+            claims.principal().username.as_deref(),
+            Some("abeli@microsoft.com")
+        );
+    }
+
+    #[derive(Debug, Default)]
+    struct CountingKeys(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+    #[async_trait::async_trait]
+    impl KeySource for CountingKeys {
+        async fn keys(&self) -> Result<Vec<Jwk>, AuthErr> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(vec![test_key()])
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_kid_retries_respect_min_refresh_interval() {
+        #[derive(Debug, Default)]
+        struct OtherKeys(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+        #[async_trait::async_trait]
+        impl KeySource for OtherKeys {
+            async fn keys(&self) -> Result<Vec<Jwk>, AuthErr> {
+                self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Ok(vec![Jwk {
+                    kid: "other".to_string(),
+                    ..test_key()
+                }])
+            }
+        }
+
+        let source = OtherKeys::default();
+        let fetches = source.0.clone();
+        let fetched = || fetches.load(std::sync::atomic::Ordering::SeqCst);
+        let token = generate_test_token();
+        let mut az_auth =
+            AzureAuth::new_offline("6e74172b-be56-4843-9ff4-e66a39bb12e3", vec![]).unwrap();
+        az_auth.set_key_source(source);
+        az_auth.set_min_fetch_interval(Duration::ZERO);
+        az_auth.set_min_refresh_interval(Duration::from_secs(10));
+
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(err, AuthErr::KeyNotFound(_)));
+        let after_first = fetched();
+
+        // retried just now
+        az_auth.validate_token(&token).await.unwrap_err();
+        assert_eq!(fetched(), after_first);
+
+        // well within the default of one minute, but past ours
+        az_auth.oidc.last_retry = Some(Timestamp::now() - Duration::from_secs(11));
+        az_auth.validate_token(&token).await.unwrap_err();
+        assert_eq!(fetched(), after_first + 1);
+    }
+
+    #[tokio::test]
+    async fn fetches_are_rate_limited() {
+        let source = CountingKeys::default();
+        let fetches = source.0.clone();
+        let mut az_auth =
+            AzureAuth::new_offline("6e74172b-be56-4843-9ff4-e66a39bb12e3", vec![]).unwrap();
+        az_auth.set_key_source(source);
+        az_auth.oidc.refresh_pub_keys().await.unwrap();
+        az_auth.oidc.refresh_pub_keys().await.unwrap();
+        assert_eq!(fetches.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        az_auth.set_min_fetch_interval(Duration::ZERO);
+        az_auth.oidc.refresh_pub_keys().await.unwrap();
+        assert_eq!(fetches.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn unknown_kid_retries_are_not_throttled_by_the_fetch_interval() {
+        let source = CountingKeys::default();
+        let fetches = source.0.clone();
+        let mut az_auth =
+            AzureAuth::new_offline("6e74172b-be56-4843-9ff4-e66a39bb12e3", vec![]).unwrap();
+        az_auth.set_key_source(source);
+        az_auth.oidc.refresh_pub_keys().await.unwrap();
+        assert_eq!(fetches.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        // fetched just now, but the token's key may have been rotated in since
+        let token = generate_test_token_with(
+            r#"{"typ": "JWT", "alg": "RS256", "kid": "rotated-in"}"#,
+            &test_token_claims(),
+        );
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(err, AuthErr::KeyNotFound(_)));
+        assert_eq!(fetches.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_fetches_are_not_rate_limited() {
+        let token = generate_test_token();
+        let jwks_uri = serve(vec![
+            (503, String::new()),
+            (503, String::new()),
+            (200, test_jwks_json()),
+        ]);
+        let mut az_auth = AzureAuth::new_seeded(
+            "6e74172b-be56-4843-9ff4-e66a39bb12e3",
+            jwks_uri,
+            vec![test_key()],
+        )
+        .unwrap();
+
+        az_auth.set_last_refresh(SystemTime::now() - clock::hours(25));
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(
+            err,
+            AuthErr::HttpError {
+                status: Some(503),
+                ..
+            }
+        ));
+
+        // within the default `min_fetch_interval`, but nothing was fetched
+        az_auth.validate_token(&token).await.unwrap();
+        assert!(az_auth.oidc.is_keys_valid());
+    }
+
+    #[test]
+    fn best_email_prefers_the_email_claim() {
+        let mut claims = AzureJwtClaims {
+            preferred_username: Some("abeli_contoso.com#EXT#@fabrikam.onmicrosoft.com".into()),
+            email: Some("abeli@contoso.com".into()),
+            ..Default::default()
+        };
+        assert_eq!(claims.best_email(), Some("abeli@contoso.com"));
+
+        // a guest's external UPN isn't an address
+        claims.email = None;
+        assert_eq!(claims.best_email(), None);
+        claims.upn = Some("abeli@contoso.com".into());
+        assert_eq!(claims.best_email(), Some("abeli@contoso.com"));
+
+        claims.upn = None;
+        claims.preferred_username = Some("abeli@microsoft.com".into());
+        assert_eq!(claims.best_email(), Some("abeli@microsoft.com"));
+
+        claims.preferred_username = Some("+1 555 0100".into());
+        assert_eq!(claims.best_email(), None);
+    }
+
+    #[tokio::test]
+    async fn keys_are_merged_from_all_jwks_uris() {
+        let jwk = |kid: &str| {
+            format!(
+                r#"{{"kty": "RSA", "kid": "{}", "n": "{}", "e": "{}"}}"#,
+                kid, PUBLIC_KEY_N, PUBLIC_KEY_E
+            )
+        };
+        let v1_keys = serve(vec![(
+            200,
+            format!(r#"{{"keys": [{}, {}]}}"#, jwk("v1-key"), jwk("shared")),
+        )]);
+        let v2_keys = serve(vec![(
+            200,
+            format!(
+                r#"{{"keys": [{}, {}]}}"#,
+                jwk("shared"),
+                jwk(&test_key().kid)
+            ),
+        )]);
+        let mut az_auth =
+            AzureAuth::new_seeded("6e74172b-be56-4843-9ff4-e66a39bb12e3", v1_keys, vec![]).unwrap();
+        az_auth.set_additional_jwks_uris(vec![v2_keys]);
+        az_auth.oidc.refresh_pub_keys().await.unwrap();
+
+        let keys = az_auth.oidc.public_keys.as_ref().unwrap();
+        assert!(keys.find("v1-key").is_some());
+        assert!(keys.find("shared").is_some());
+        az_auth
+            .validate_token(&generate_test_token())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn self_check_reports_every_problem() {
+        let mut az_auth = AzureAuth::new_offline(" ", vec![]).unwrap();
+        let problems = az_auth.self_check().await.unwrap_err();
+        assert_eq!(problems.len(), 2);
+
+        let mut az_auth = test_auth();
+        az_auth.self_check().await.unwrap();
+    }
+
+    #[test]
+    fn config_getters_reflect_setters() {
+        let mut az_auth = AzureAuth::new_offline("app", vec![]).unwrap();
+        assert_eq!(az_auth.leeway(), 60);
+        assert_eq!(az_auth.key_expiration_hours(), 24);
+
+        az_auth.set_leeway(5);
+        az_auth.set_expiration(12);
+        az_auth.set_audience("another-app");
+        assert_eq!(az_auth.leeway(), 5);
+        assert_eq!(az_auth.key_expiration_hours(), 12);
+        assert_eq!(az_auth.audience(), "another-app");
+    }
+
+    #[tokio::test]
+    async fn malformed_tokens_are_rejected_up_front() {
+        let mut az_auth = test_auth();
+        let token = generate_test_token();
+        let (two_segments, _) = token.rsplit_once('.').unwrap();
+
+        for token in ["", two_segments, "opaque-token", "a.b.c.d", "a.b!.c"] {
+            let err = az_auth.validate_token(token).await.unwrap_err();
+            assert!(matches!(err, AuthErr::MalformedToken(_)), "{}", token);
+            assert!(err.is_client_error());
+        }
+    }
+
+    /// A key source that only serves the key that replaced `test_key()`.
+    #[derive(Debug)]
+    struct RotatedKeys;
+
+    #[async_trait::async_trait]
+    impl KeySource for RotatedKeys {
+        async fn keys(&self) -> Result<Vec<Jwk>, AuthErr> {
+            Ok(vec![Jwk {
+                kid: "rotated-in".to_string(),
+                ..test_key()
+            }])
+        }
+    }
+
+    #[tokio::test]
+    async fn recent_keys_are_kept_for_the_grace_period() {
+        let token = generate_test_token();
+        let mut az_auth =
+            AzureAuth::new_offline("6e74172b-be56-4843-9ff4-e66a39bb12e3", vec![]).unwrap();
+        az_auth.set_key_source(FixtureKeys);
+        az_auth.set_min_fetch_interval(Duration::ZERO);
+        az_auth.validate_token(&token).await.unwrap();
+
+        az_auth.set_key_source(RotatedKeys);
+        az_auth.set_key_grace_period(Duration::from_secs(3600));
+        az_auth.oidc.refresh_pub_keys().await.unwrap();
+        az_auth.validate_token(&token).await.unwrap();
+
+        az_auth.set_key_grace_period(Duration::ZERO);
+        az_auth.oidc.refresh_pub_keys().await.unwrap();
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(err, AuthErr::KeyNotFound(_)));
+    }
+
+    #[cfg(feature = "stream")]
+    #[tokio::test]
+    async fn validates_a_stream_of_tokens() {
+        use futures_util::StreamExt;
+
+        let mut az_auth = test_auth();
+        let tokens = futures_util::stream::iter(vec![generate_test_token(), "garbage".into()]);
+        let results: Vec<_> = az_auth.validate_stream(tokens).collect().await;
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(AuthErr::MalformedToken(_))));
+    }
+
+    #[cfg(feature = "stream")]
+    #[tokio::test]
+    async fn handle_validates_a_stream_with_a_limit() {
+        use futures_util::StreamExt;
+
+        let handle = AzureAuthHandle::new(test_auth());
+        let mut tokens = vec![generate_test_token(); 5];
+        tokens.insert(2, "garbage".into());
+        let results: Vec<_> = handle
+            .validate_stream(futures_util::stream::iter(tokens), 2)
+            .collect()
+            .await;
+        assert_eq!(results.len(), 6);
+        assert!(matches!(results[2], Err(AuthErr::MalformedToken(_))));
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 5);
+    }
+
+    fn env_config(vars: &[(&str, &str)]) -> Result<EnvConfig, AuthErr> {
+        EnvConfig::from_vars(|name| {
+            Ok(vars
+                .iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string()))
+        })
+    }
+
+    #[test]
+    fn from_env_requires_the_client_id() {
+        let err = env_config(&[("AZURE_TENANT_ID", "contoso")]).unwrap_err();
+        assert!(err.to_string().contains("AZURE_CLIENT_ID"));
+    }
+
+    #[test]
+    fn from_env_defaults_to_the_common_tenant_of_the_public_cloud() {
+        let vars = env_config(&[("AZURE_CLIENT_ID", "6e74172b-be56-4843-9ff4-e66a39bb12e3")]);
+        assert_eq!(
+            vars.unwrap(),
+            EnvConfig {
+                audience: "6e74172b-be56-4843-9ff4-e66a39bb12e3".into(),
+                tenant: "common".into(),
+                authority_host: "https://login.microsoftonline.com".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn from_env_reads_the_tenant_and_the_cloud() {
+        let vars = env_config(&[
+            ("AZURE_CLIENT_ID", "6e74172b-be56-4843-9ff4-e66a39bb12e3"),
+            ("AZURE_TENANT_ID", "72f988bf-86f1-41af-91ab-2d7cd011db47"),
+            ("AZURE_AUTHORITY_HOST", "https://login.microsoftonline.us/"),
+        ])
+        .unwrap();
+        assert_eq!(vars.tenant, "72f988bf-86f1-41af-91ab-2d7cd011db47");
+        assert_eq!(vars.authority_host, "https://login.microsoftonline.us");
+        assert_eq!(
+            AzureAuth::metadata_url(&vars.authority_host, &vars.tenant),
+            "https://login.microsoftonline.us/72f988bf-86f1-41af-91ab-2d7cd011db47/.well-known/openid-configuration"
+        );
+    }
+
+    #[test]
+    fn from_env_rejects_a_cloud_that_is_not_https() {
+        for host in [
+            "http://login.chinacloudapi.cn",
+            "login.chinacloudapi.cn",
+            "https://",
+        ] {
+            let err = env_config(&[
+                ("AZURE_CLIENT_ID", "6e74172b-be56-4843-9ff4-e66a39bb12e3"),
+                ("AZURE_AUTHORITY_HOST", host),
+            ])
+            .unwrap_err();
+            assert!(err.to_string().contains("AZURE_AUTHORITY_HOST"), "{}", host);
+        }
+    }
+
+    #[tokio::test]
+    async fn set_tenant_stays_in_the_cloud_from_env() {
+        let mut az_auth =
+            AzureAuth::new_offline("6e74172b-be56-4843-9ff4-e66a39bb12e3", vec![]).unwrap();
+        az_auth.authority_host = "https://login.chinacloudapi.cn".into();
+        az_auth.set_tenant("contoso").await.unwrap();
+        assert_eq!(
+            az_auth.oidc.metadata_url.as_deref(),
+            Some("https://login.chinacloudapi.cn/contoso/.well-known/openid-configuration")
+        );
+    }
+
+    #[tokio::test]
+    async fn validate_full_returns_the_header() {
+        let mut az_auth = test_auth();
+        let (header, claims) = az_auth.validate_full(&generate_test_token()).await.unwrap();
+        assert_eq!(header.kid, test_key().kid);
+        assert_eq!(header.alg, "RS256");
+        assert_eq!(claims.name.as_deref(), Some("Abe Lincoln"));
+    }
+
+    #[tokio::test]
+    async fn personal_accounts_can_be_rejected() {
+        let claims = test_token_claims().replace(
+            "72f988bf-86f1-41af-91ab-2d7cd011db47",
+            "9188040d-6c67-4c5b-b112-36a304b66dad",
+        );
+        let token = generate_test_token_from(&claims);
+        let mut az_auth = test_auth();
+        az_auth.validate_token(&token).await.unwrap();
+
+        az_auth.set_allow_personal_accounts(false);
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(err, AuthErr::PersonalAccountNotAllowed));
+        az_auth
+            .validate_token(&generate_test_token())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn warm_tenants_reports_each_tenant() {
+        let mut az_auth = AzureAuth::new_multi_tenant(
+            "6e74172b-be56-4843-9ff4-e66a39bb12e3",
+            vec!["72f988bf-86f1-41af-91ab-2d7cd011db47".into()],
+        )
+        .unwrap();
+        az_auth.set_network_disabled(true);
+
+        let errors = az_auth
+            .warm_tenants(&["72f988bf-86f1-41af-91ab-2d7cd011db47", "other"])
+            .await
+            .unwrap_err();
+        assert!(matches!(errors[0].1, AuthErr::KeysStale));
+        assert!(matches!(errors[1].1, AuthErr::TenantNotAllowed(_)));
+    }
+
+    #[tokio::test]
+    async fn warm_tenants_ignores_case_for_the_current_tenant() {
+        let mut az_auth = AzureAuth::new_multi_tenant(
+            "6e74172b-be56-4843-9ff4-e66a39bb12e3",
+            vec!["72f988bf-86f1-41af-91ab-2d7cd011db47".into()],
+        )
+        .unwrap();
+        az_auth.set_network_disabled(true);
+        // as if a token of the tenant was validated just now
+        az_auth.tenant = "72f988bf-86f1-41af-91ab-2d7cd011db47".into();
+        az_auth.oidc.jwks_uri = "https://login.microsoftonline.com/keys".into();
+        az_auth.set_public_keys(vec![test_key()]);
+        az_auth.set_last_refresh(SystemTime::now());
+
+        az_auth
+            .warm_tenants(&["72F988BF-86F1-41AF-91AB-2D7CD011DB47"])
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_checks_have_their_own_error() {
+        let now = chrono::Utc::now().timestamp();
+        let expired =
+            generate_test_token_from(&test_token_claims_at(now - 7200, now - 7200, now - 3600));
+        let mut az_auth = test_auth();
+        let err = az_auth.validate_token(&expired).await.unwrap_err();
+        assert!(matches!(err, AuthErr::TokenExpired));
+
+        az_auth.set_audience("another-app");
+        let err = az_auth
+            .validate_token(&generate_test_token())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AuthErr::InvalidAudience { .. }));
+        assert!(err.is_client_error());
+    }
+
+    #[test]
+    fn require_mfa_checks_amr() {
+        let mut claims = AzureJwtClaims {
+            amr: Some(vec!["pwd".into()]),
+            ..Default::default()
+        };
+        assert!(matches!(claims.require_mfa(), Err(AuthErr::MfaRequired)));
+
+        claims.amr = Some(vec!["pwd".into(), "mfa".into()]);
+        claims.require_mfa().unwrap();
+
+        claims.amr = None;
+        assert!(claims.require_mfa().is_err());
+    }
+
+    #[test]
+    fn personal_accounts_are_recognized() {
+        let mut claims = AzureJwtClaims {
+            tid: CONSUMER_TENANT_ID.into(),
+            ..Default::default()
+        };
+        assert!(claims.is_personal_account());
+
+        claims.tid = "72f988bf-86f1-41af-91ab-2d7cd011db47".into();
+        assert!(!claims.is_personal_account());
+    }
+
+    #[test]
+    fn refresh_jitter_shortens_the_key_expiration() {
+        let mut az_auth = test_auth();
+        az_auth.oidc.jitter_point = 0.5;
+        az_auth.set_last_refresh(SystemTime::now() - clock::hours(20));
+        assert!(az_auth.oidc.is_keys_valid());
+
+        az_auth.set_refresh_jitter(0.5);
+        assert!(!az_auth.oidc.is_keys_valid());
+        az_auth.set_last_refresh(SystemTime::now() - clock::hours(17));
+        assert!(az_auth.oidc.is_keys_valid());
+    }
+
+    #[cfg(feature = "watch")]
+    #[tokio::test]
+    async fn watched_jwks_file_is_reloaded() {
+        let jwks = |kid: &str| test_jwks_json().replace(&test_key().kid, kid);
+        let dir = std::env::temp_dir().join(format!("azure-jwt-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("jwks.json");
+        std::fs::write(&path, jwks("rotated-out")).unwrap();
+
+        let token = generate_test_token();
+        let mut az_auth =
+            AzureAuth::from_jwks_file_watched("6e74172b-be56-4843-9ff4-e66a39bb12e3", &path)
+                .unwrap();
+        assert!(az_auth.validate_token(&token).await.is_err());
+
+        std::fs::write(&path, jwks(&test_key().kid)).unwrap();
+        let mut result = Err(AuthErr::KeysStale);
+        for _ in 0..50 {
+            result = az_auth.validate_token(&token).await;
+            if result.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn custom_validation_can_keep_the_audience() {
+        let token = generate_test_token();
+        let validator = jwt::Validation::new(jwt::Algorithm::RS256);
+        let mut az_auth = AzureAuth::new_offline("another-app", vec![test_key()]).unwrap();
+        az_auth
+            .validate_custom::<AzureJwtClaims>(&token, &validator)
+            .await
+            .unwrap();
+
+        let err = az_auth
+            .validate_custom_with_audience::<AzureJwtClaims>(&token, validator)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AuthErr::InvalidAudience { .. }));
+    }
+
+    #[test]
+    fn inspect_decodes_without_keys() {
+        let inspection = AzureAuth::inspect(&generate_test_token()).unwrap();
+        assert_eq!(inspection.header.kid, test_key().kid);
+        assert_eq!(inspection.segment_count, 3);
+        assert_eq!(inspection.version, Some(TokenVersion::V2));
+        assert_eq!(
+            inspection.claims.unwrap().tid,
+            "72f988bf-86f1-41af-91ab-2d7cd011db47"
+        );
+
+        assert!(matches!(
+            AzureAuth::inspect("garbage"),
+            Err(AuthErr::MalformedToken(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn opaque_claims_are_ignored() {
+        let claims = test_token_claims().replace(
+            r#""ver": "2.0""#,
+            r#""ver": "2.0",
+                "ipaddr": "203.0.113.7",
+                "xms_tcdt": 1289241547,
+                "wids": ["b79fbf4d-3ef9-4689-8143-76b194e85509"]"#,
+        );
+        assert!(claims.contains(r#""aio""#) && claims.contains(r#""uti""#));
+        let token = generate_test_token_from(&claims);
+        let mut az_auth = test_auth();
+        let claims = az_auth.validate_token(&token).await.unwrap().claims;
+        assert_eq!(claims.ipddr.as_deref(), Some("203.0.113.7"));
+        assert_eq!(claims.token_id(), Some("fqiBqXLPj0eQa82S-IYFAA"));
+    }
+
+    #[test]
+    fn cache_status_reports_the_keys() {
+        let mut az_auth = AzureAuth::new_seeded(
+            "6e74172b-be56-4843-9ff4-e66a39bb12e3",
+            "http://127.0.0.1:9/keys",
+            vec![test_key()],
+        )
+        .unwrap();
+        let status = az_auth.cache_status();
+        assert!(status.keys_loaded && !status.expired);
+        assert_eq!(status.key_count, 1);
+
+        let refreshed = SystemTime::now() - clock::hours(25);
+        az_auth.set_last_refresh(refreshed);
+        let status = az_auth.cache_status();
+        assert!(status.expired);
+        assert_eq!(
+            status.last_refresh.unwrap(),
+            SystemTime::from(Timestamp::from(refreshed))
+        );
+    }
+
+    #[tokio::test]
+    async fn validates_a_split_token() {
+        let token = generate_test_token();
+        let parts: Vec<&str> = token.split('.').collect();
+        let mut az_auth = test_auth();
+        az_auth
+            .validate_parts(parts[0], parts[1], parts[2])
+            .await
+            .unwrap();
+
+        let err = az_auth
+            .validate_parts(parts[0], "a.b", parts[2])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AuthErr::MalformedToken(_)));
+    }
+
+    #[tokio::test]
+    async fn idps_can_be_restricted() {
+        let issuer = "https://login.microsoftonline.com/72f988bf-86f1-41af-91ab-2d7cd011db47/v2.0";
+        let token = generate_test_token();
+        let guest = generate_test_token_from(
+            &test_token_claims().replace(r#""ver": "2.0""#, r#""ver": "2.0", "idp": "live.com""#),
+        );
+        let mut az_auth = test_auth();
+        az_auth.set_allowed_idps(vec![issuer.to_string()]);
+
+        az_auth.validate_token(&token).await.unwrap();
+        let err = az_auth.validate_token(&guest).await.unwrap_err();
+        assert!(matches!(err, AuthErr::IdpNotAllowed(idp) if idp == "live.com"));
+    }
+
+    #[tokio::test]
+    async fn from_discovery_makes_no_calls() {
+        let discovery = |issuer: &str| test_discovery(issuer, "http://127.0.0.1:9/keys");
+        let mut az_auth = AzureAuth::from_discovery(
+            "6e74172b-be56-4843-9ff4-e66a39bb12e3",
+            discovery("https://sts.windows.net/{tenantid}/"),
+            Some(vec![test_key()]),
+        )
+        .unwrap();
+        az_auth.set_discovery_v2(discovery(
+            "https://login.microsoftonline.com/{tenantid}/v2.0",
+        ));
+        az_auth.set_network_disabled(true);
+        az_auth
+            .validate_token(&generate_test_token())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn invalid_audience_shows_both_audiences() {
+        let mut az_auth = AzureAuth::new_offline(
+            "api://6e74172b-be56-4843-9ff4-e66a39bb12e3",
+            vec![test_key()],
+        )
+        .unwrap();
+        let err = az_auth
+            .validate_token(&generate_test_token())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            AuthErr::InvalidAudience { expected, found }
+                if expected == "api://6e74172b-be56-4843-9ff4-e66a39bb12e3"
+                    && found == "6e74172b-be56-4843-9ff4-e66a39bb12e3"
+        ));
+    }
+
+    #[tokio::test]
+    async fn trusted_keys_survive_a_refresh() {
+        let token = generate_test_token();
+        let mut az_auth =
+            AzureAuth::new_offline("6e74172b-be56-4843-9ff4-e66a39bb12e3", vec![]).unwrap();
+        az_auth.add_trusted_key(test_key());
+        az_auth.validate_token(&token).await.unwrap();
+
+        az_auth.set_public_keys(vec![]);
+        az_auth.validate_token(&token).await.unwrap();
+        assert_eq!(az_auth.cache_status().trusted_key_count, 1);
+
+        assert!(az_auth.remove_trusted_key(&test_key().kid));
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(err, AuthErr::KeyNotFound(_)));
+    }
+
+    #[cfg(feature = "introspection")]
+    #[tokio::test]
+    async fn introspects_a_token() {
+        let body = r#"{"active":true,"scope":"read write","exp":1700000000}"#;
+        let (url, server) = serve_recorded(vec![(200, body.to_string())]);
+        let endpoint = format!("{}/introspect", url);
+
+        let client = IntrospectionClient::new(endpoint, "client", "secret").unwrap();
+        let response = client.introspect("opaque").await.unwrap();
+        assert!(response.active);
+        assert_eq!(response.scopes(), ["read", "write"]);
+        assert_eq!(response.exp, Some(1700000000));
+
+        // `client:secret`
+        let requests = server.join().unwrap();
+        assert!(requests[0].contains("token=opaque"));
+        assert!(requests[0].contains("Basic Y2xpZW50OnNlY3JldA=="));
+    }
+
+    #[tokio::test]
+    async fn rejects_revoked_tokens() {
+        let token = generate_test_token();
+        let mut az_auth = test_auth();
+        az_auth.set_revocation_check(|claims| claims.sub == "someone-else");
+        az_auth.validate_token(&token).await.unwrap();
+
+        az_auth.set_revocation_check(|claims| {
+            claims.sub == "HKZpfaHyWadeOouYlitjrI-KffTm222X5rrV3xDqfKQ"
+        });
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(err, AuthErr::TokenRevoked));
+    }
+
+    #[tokio::test]
+    async fn strict_mode_requires_temporal_claims() {
+        let claims = test_token_claims();
+        let start = claims.find(r#""iat""#).unwrap();
+        let end = start + claims[start..].find(',').unwrap() + 1;
+        let token = generate_test_token_from(&format!("{}{}", &claims[..start], &claims[end..]));
+        let mut az_auth = test_auth();
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(err, AuthErr::ClaimsParseError(_)));
+
+        az_auth.set_require_temporal_claims(true);
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(err, AuthErr::MissingClaim(claim) if claim == "iat"));
+        az_auth
+            .validate_token(&generate_test_token())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn validates_with_pem_certs() {
+        let cert = "-----BEGIN CERTIFICATE-----\n\
+MIIDITCCAgmgAwIBAgIUVAIFw19fYEsUqUxgAiWMbU7FImMwDQYJKoZIhvcNAQEL\n\
+BQAwHzEdMBsGA1UEAwwUYXp1cmUtand0LWFzeW5jIHRlc3QwIBcNMjYxMDE0MDUw\n\
+OTAxWhgPMjEyNjA5MjAwNTA5MDFaMB8xHTAbBgNVBAMMFGF6dXJlLWp3dC1hc3lu\n\
+YyB0ZXN0MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEA7HQY5BxK3kBm\n\
+7TaeUZZS5demnF5X0K7/0tyClUGD9ZBv7kMEdmmqzwAx6Tm7mIjiGQcJw7IxtCsG\n\
+wMhVr3HFnFHaSr/wa0RGyd3o0KVCKaynFPVZWBfn9YIB3muT/8i/Qahw/Zz/UJx1\n\
+PyKzY5kd5ZDJLJA1KDIliDpSnuQW1hpuILJTmr21MgdA9eLBjncgaw29KSpuT46x\n\
+1Y9PcTh2qne82toypeG5Jn7naQsIerA+ACxvO18UnFIAQFyn5NLe424d5InA1aO8\n\
+MpM/Lagxpw2luwpvaBmDO5tHqFOO+6lGNpD1IeFfIv2WZJk6kLPPZclJVGikpRS0\n\
+H5UYDSpWWQIDAQABo1MwUTAdBgNVHQ4EFgQU3hrX3fLg2AKWbYeCaqAziUN6fJ0w\n\
+HwYDVR0jBBgwFoAU3hrX3fLg2AKWbYeCaqAziUN6fJ0wDwYDVR0TAQH/BAUwAwEB\n\
+/zANBgkqhkiG9w0BAQsFAAOCAQEAV1mqEa2cpSgKHb+H5AxxxRr9ZwH/VzWIke1N\n\
+6lfG4fURmYe/Qw9R//0NCN/d6ZMpAEYEYUTZgQZLfjALbA6REs5Lb6WZ/UPLWpgX\n\
+ZH8/jZrtbtycIsjFQTAmr/AfGogbirN7WhRNOY31kteJckIzI5Mbog2lzumfTpIl\n\
+cIOdCpTefCYgT+50UMyko/ig2QwwKbXuf0fkBOFCwLigZmuzjp3nKENsvW92n9R2\n\
+QDdr7RipnhckSUuz4lPOzyIIPW3NbA5O+/NBuT+CaeXavHswkR9JrY3u2p41eACa\n\
+EY4o2OmDMrMtb6/8DvabmHOHQ+NLumXOt/n8aw2zyw6nI3CzYA==\n\
+-----END CERTIFICATE-----";
+        let kid = test_key().kid;
+        let mut az_auth = AzureAuth::from_pem_certs(
+            "6e74172b-be56-4843-9ff4-e66a39bb12e3",
+            vec![(kid.clone(), cert.to_string())],
+        )
+        .unwrap();
+        az_auth
+            .validate_token(&generate_test_token())
+            .await
+            .unwrap();
+
+        let truncated = cert.replace("-----END CERTIFICATE-----", "");
+        let err = AzureAuth::from_pem_certs("aud", vec![(kid, truncated)]).unwrap_err();
+        assert!(matches!(err, AuthErr::Other(_)));
+    }
+
+    #[tokio::test]
+    async fn validates_with_a_public_key_pem() {
+        let spki = "-----BEGIN PUBLIC KEY-----
+MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEA7HQY5BxK3kBm7TaeUZZS
+5demnF5X0K7/0tyClUGD9ZBv7kMEdmmqzwAx6Tm7mIjiGQcJw7IxtCsGwMhVr3HF
+nFHaSr/wa0RGyd3o0KVCKaynFPVZWBfn9YIB3muT/8i/Qahw/Zz/UJx1PyKzY5kd
+5ZDJLJA1KDIliDpSnuQW1hpuILJTmr21MgdA9eLBjncgaw29KSpuT46x1Y9PcTh2
+qne82toypeG5Jn7naQsIerA+ACxvO18UnFIAQFyn5NLe424d5InA1aO8MpM/Lagx
+pw2luwpvaBmDO5tHqFOO+6lGNpD1IeFfIv2WZJk6kLPPZclJVGikpRS0H5UYDSpW
+WQIDAQAB
+-----END PUBLIC KEY-----";
+        let pkcs1 = "-----BEGIN RSA PUBLIC KEY-----
+MIIBCgKCAQEA7HQY5BxK3kBm7TaeUZZS5demnF5X0K7/0tyClUGD9ZBv7kMEdmmq
+zwAx6Tm7mIjiGQcJw7IxtCsGwMhVr3HFnFHaSr/wa0RGyd3o0KVCKaynFPVZWBfn
+9YIB3muT/8i/Qahw/Zz/UJx1PyKzY5kd5ZDJLJA1KDIliDpSnuQW1hpuILJTmr21
+MgdA9eLBjncgaw29KSpuT46x1Y9PcTh2qne82toypeG5Jn7naQsIerA+ACxvO18U
+nFIAQFyn5NLe424d5InA1aO8MpM/Lagxpw2luwpvaBmDO5tHqFOO+6lGNpD1IeFf
+Iv2WZJk6kLPPZclJVGikpRS0H5UYDSpWWQIDAQAB
+-----END RSA PUBLIC KEY-----";
+        let aud = "6e74172b-be56-4843-9ff4-e66a39bb12e3";
+        for pem in [spki, pkcs1] {
+            let mut az_auth = AzureAuth::from_public_key_pem(aud, test_key().kid, pem).unwrap();
+            az_auth
+                .validate_token(&generate_test_token())
+                .await
+                .unwrap();
+        }
+
+        let err = AzureAuth::from_public_key_pem(aud, "kid", &spki.replace("PUBLIC", "PRIVATE"))
+            .unwrap_err();
+        assert!(matches!(err, AuthErr::Other(_)));
+    }
+
+    #[tokio::test]
+    async fn validates_as_a_custom_claims_type() {
+        #[derive(Debug, Serialize, Deserialize)]
+        struct MyClaims {
+            #[serde(flatten)]
+            azure: AzureJwtClaims,
+            department: String,
+        }
+        let token = generate_test_token_from(&test_token_claims().replace(
+            r#""ver": "2.0""#,
+            r#""ver": "2.0", "department": "Finance""#,
+        ));
+
+        let mut az_auth = test_auth();
+        let claims = az_auth
+            .validate_as::<MyClaims>(&token)
+            .await
+            .unwrap()
+            .claims;
+        assert_eq!(claims.department, "Finance");
+        assert_eq!(claims.azure.tid, "72f988bf-86f1-41af-91ab-2d7cd011db47");
+
+        az_auth.set_audience("another-app");
+        let err = az_auth.validate_as::<MyClaims>(&token).await.unwrap_err();
+        assert!(matches!(err, AuthErr::InvalidAudience { .. }));
+    }
+
+    #[tokio::test]
+    async fn strict_audience_rejects_display_names() {
+        let mut az_auth = AzureAuth::new_offline("My Web API", vec![test_key()]).unwrap();
+        let err = az_auth.set_strict_audience(true).unwrap_err();
+        assert!(matches!(err, AuthErr::UnexpectedAudienceFormat(ref aud) if aud == "My Web API"));
+        assert!(err.is_server_error());
+        // refused, so strict mode stays off
+        let err = az_auth
+            .validate_token(&generate_test_token())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AuthErr::InvalidAudience { .. }));
+
+        az_auth.set_audience("6e74172b-be56-4843-9ff4-e66a39bb12e3");
+        az_auth.set_strict_audience(true).unwrap();
+        az_auth.set_audience("My Web API");
+        let err = az_auth
+            .validate_token(&generate_test_token())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AuthErr::UnexpectedAudienceFormat(_)));
+        let err = az_auth
+            .validate_detailed(&generate_test_token())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AuthErr::UnexpectedAudienceFormat(_)));
+
+        for aud in ["api://my-web-api", "https://contoso.com/api"] {
+            az_auth.set_audience(aud);
+            az_auth.set_strict_audience(true).unwrap();
+        }
+        az_auth.set_audience("6e74172b-be56-4843-9ff4-e66a39bb12e3");
+        az_auth
+            .validate_token(&generate_test_token())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn reports_the_crate_version() {
+        assert_eq!(AzureAuth::version(), env!("CARGO_PKG_VERSION"));
+        assert_eq!(
+            oidc::USER_AGENT,
+            format!("azure_jwt_async/{}", AzureAuth::VERSION)
+        );
+    }
+
+    #[tokio::test]
+    async fn accepts_graph_audience_when_enabled() {
+        let mut az_auth = test_auth();
+        for graph in [GRAPH_AUDIENCE, GRAPH_APP_ID] {
+            let token = generate_test_token_from(
+                &test_token_claims().replace("6e74172b-be56-4843-9ff4-e66a39bb12e3", graph),
+            );
+            az_auth.set_accept_graph_audience(false);
+            let err = az_auth.validate_token(&token).await.unwrap_err();
+            assert!(matches!(err, AuthErr::InvalidAudience { .. }));
+
+            az_auth.set_accept_graph_audience(true);
+            let claims = az_auth.validate_token(&token).await.unwrap().claims;
+            assert_eq!(claims.aud, graph);
+        }
+        // our own tokens are still accepted
+        az_auth
+            .validate_token(&generate_test_token())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn refreshes_only_expired_keys() {
+        let source = CountingKeys::default();
+        let fetches = source.0.clone();
+        let mut az_auth =
+            AzureAuth::new_offline("6e74172b-be56-4843-9ff4-e66a39bb12e3", vec![]).unwrap();
+        assert!(!az_auth.refresh_if_needed().await.unwrap());
+
+        az_auth.set_key_source(source);
+        assert!(az_auth.refresh_if_needed().await.unwrap());
+        assert!(!az_auth.refresh_if_needed().await.unwrap());
+        assert_eq!(fetches.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        // expired, but fetched too recently
+        az_auth.set_last_refresh(SystemTime::now() - Duration::from_secs(25 * 3600));
+        assert!(!az_auth.refresh_if_needed().await.unwrap());
+        az_auth.set_min_fetch_interval(Duration::ZERO);
+        assert!(az_auth.refresh_if_needed().await.unwrap());
+        assert_eq!(fetches.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+        az_auth.set_last_refresh(SystemTime::now() - Duration::from_secs(25 * 3600));
+        az_auth.set_network_disabled(true);
+        let err = az_auth.refresh_if_needed().await.unwrap_err();
+        assert!(matches!(err, AuthErr::KeysStale));
+    }
+
+    #[tokio::test]
+    async fn exp_and_nbf_leeways_are_separate() {
+        let now = chrono::Utc::now().timestamp();
+        let expired =
+            generate_test_token_from(&test_token_claims_at(now - 1000, now - 1000, now - 30));
+        let early =
+            generate_test_token_from(&test_token_claims_at(now - 1000, now + 300, now + 1000));
+
+        let mut az_auth = test_auth();
+        az_auth.set_nbf_leeway(600);
+        az_auth.set_exp_leeway(10);
+        assert_eq!(az_auth.leeway(), 10);
+        let err = az_auth.validate_token(&expired).await.unwrap_err();
+        assert!(matches!(err, AuthErr::TokenExpired));
+        az_auth.validate_token(&early).await.unwrap();
+        az_auth.try_validate(&early).unwrap();
+        let detailed = az_auth.validate_detailed(&early).await.unwrap();
+        assert!(detailed.failures().is_empty());
+
+        az_auth.set_nbf_leeway(0);
+        let err = az_auth.validate_token(&early).await.unwrap_err();
+        assert!(matches!(err, AuthErr::TokenNotYetValid));
+
+        // both again
+        az_auth.set_leeway(60);
+        az_auth.validate_token(&expired).await.unwrap();
+        let err = az_auth.validate_token(&early).await.unwrap_err();
+        assert!(matches!(err, AuthErr::TokenNotYetValid));
+    }
+
+    #[tokio::test]
+    async fn accepts_padded_and_unpadded_segments() {
+        // trailing whitespace so the segments can't do without padding
+        let unaligned = |mut json: String| {
+            while json.len().is_multiple_of(3) {
+                json.push(' ');
+            }
+            json
+        };
+        let header = unaligned(test_token_header());
+        let payload = unaligned(test_token_claims());
+        let private_key = jwt::EncodingKey::from_base64_secret(PRIVATE_KEY_TEST).unwrap();
+
+        let mut az_auth = test_auth();
+        let engines = [
+            (general_purpose::URL_SAFE, true),
+            (general_purpose::URL_SAFE_NO_PAD, false),
+        ];
+        for (engine, padded) in engines {
+            let message = format!("{}.{}", engine.encode(&header), engine.encode(&payload));
+            let signature =
+                jwt::crypto::sign(message.as_bytes(), &private_key, jwt::Algorithm::RS256)
+                    .expect("Signed");
+            let padded_signature = general_purpose::URL_SAFE
+                .encode(general_purpose::URL_SAFE_NO_PAD.decode(&signature).unwrap());
+
+            for signature in [signature, padded_signature] {
+                let token = format!("{}.{}", message, signature);
+                let claims = az_auth.validate_token(&token).await.unwrap().claims;
+                assert_eq!(claims.sub, "HKZpfaHyWadeOouYlitjrI-KffTm222X5rrV3xDqfKQ");
+                assert!(AzureAuth::inspect(&token).unwrap().claims.is_some());
+                assert_eq!(
+                    AzureAuth::tenant_from_token(&token).unwrap(),
+                    "72f988bf-86f1-41af-91ab-2d7cd011db47"
+                );
+            }
+            assert_eq!(message.contains('='), padded);
+        }
+
+        // padding in the middle of a segment isn't base64url
+        let token = generate_test_token()
+            .replacen('.', "=.", 1)
+            .replacen('e', "=e", 1);
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(err, AuthErr::MalformedToken(_)));
+    }
+
+    #[tokio::test]
+    async fn validates_token_bytes() {
+        let mut az_auth = test_auth();
+        let token = generate_test_token();
+        let claims = az_auth
+            .validate_token_bytes(token.as_bytes())
+            .await
+            .unwrap()
+            .claims;
+        assert_eq!(claims.tid, "72f988bf-86f1-41af-91ab-2d7cd011db47");
+
+        let mut bytes = token.into_bytes();
+        bytes[3] = 0xff;
+        let err = az_auth.validate_token_bytes(&bytes).await.unwrap_err();
+        assert!(matches!(err, AuthErr::MalformedToken(_)));
+    }
+
+    #[tokio::test]
+    async fn issuer_check_can_be_turned_off() {
+        let token = generate_test_token();
+        let mut az_auth = test_auth();
+        az_auth.set_discovery(test_discovery("https://sts.windows.net/{tenantid}/", ""));
+        // without the v2.0 metadata we can't know the issuer of the v2.0 token
+        az_auth.set_network_disabled(true);
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(err, AuthErr::KeysStale));
+
+        az_auth.set_validate_issuer(false);
+        az_auth.validate_token(&token).await.unwrap();
+        az_auth.try_validate(&token).unwrap();
+
+        let mut other = az_auth.discovery().unwrap().clone();
+        other.issuer = "https://login.microsoftonline.com/other/v2.0".to_string();
+        az_auth.set_discovery_v2(other);
+        az_auth.validate_token(&token).await.unwrap();
+        az_auth.set_validate_issuer(true);
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(err, AuthErr::InvalidIssuer { .. }));
+    }
+
+    #[tokio::test]
+    async fn rejects_tokens_outside_their_lifetime() {
+        let now = chrono::Utc::now().timestamp();
+        let mut az_auth = test_auth();
+
+        // (iat, nbf, exp), outside the default leeway of 60s
+        let expired = test_token_claims_at(now - 2000, now - 2000, now - 120);
+        let not_yet_valid = test_token_claims_at(now - 100, now + 120, now + 1000);
+        let issued_in_future = test_token_claims_at(now + 120, now - 100, now + 1000);
+        for (claims, expected) in [
+            (expired, AuthErr::TokenExpired),
+            (not_yet_valid, AuthErr::TokenNotYetValid),
+            (issued_in_future, AuthErr::IssuedInFuture),
+        ] {
+            let token = generate_test_token_from(&claims);
+            let expected = std::mem::discriminant(&expected);
+            let err = az_auth.validate_token(&token).await.unwrap_err();
+            assert_eq!(std::mem::discriminant(&err), expected, "{}", err);
+            let err = az_auth.try_validate(&token).unwrap_err();
+            assert_eq!(std::mem::discriminant(&err), expected, "{}", err);
+        }
+
+        // within the leeway
+        let claims = test_token_claims_at(now + 30, now + 30, now - 30);
+        az_auth
+            .validate_token(&generate_test_token_from(&claims))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_refreshes_and_retries() {
+        let source = CountingKeys::default();
+        let mut az_auth =
+            AzureAuth::new_offline("6e74172b-be56-4843-9ff4-e66a39bb12e3", vec![]).unwrap();
+        az_auth.set_key_source(source);
+        let token = generate_test_token();
+
+        let (result, diagnostics) = az_auth.validate_with_diagnostics(&token).await;
+        result.unwrap();
+        assert!(diagnostics.keys_refreshed);
+        assert!(!diagnostics.retried);
+        assert!(diagnostics.retry_available);
+        assert_eq!(diagnostics.last_retry, None);
+
+        let (result, diagnostics) = az_auth.validate_with_diagnostics(&token).await;
+        result.unwrap();
+        assert!(!diagnostics.keys_refreshed);
+
+        // the source only has the test key, the retry doesn't find `other`
+        let other = test_token_header().replace("i6lGk3FZzxRcUb2C3nEQ7syHJlY", "other");
+        let other = generate_test_token_with(&other, &test_token_claims());
+        az_auth.set_min_fetch_interval(Duration::ZERO);
+        let (result, diagnostics) = az_auth.validate_with_diagnostics(&other).await;
+        assert!(matches!(result, Err(AuthErr::KeyNotFound(_))));
+        assert!(diagnostics.keys_refreshed);
+        assert!(diagnostics.retried);
+        assert!(!diagnostics.retry_available);
+        assert!(diagnostics.last_retry.is_some());
+    }
+
+    #[tokio::test]
+    async fn reads_groups_and_the_overage() {
+        let mut az_auth = test_auth();
+
+        let groups = test_token_claims().replace(
+            r#""ver": "2.0""#,
+            r#""ver": "2.0", "groups": ["9F4A5C1E-0000-4000-8000-000000000001"]"#,
+        );
+        let token = generate_test_token_from(&groups);
+        let claims = az_auth.validate_token(&token).await.unwrap().claims;
+        assert!(claims.has_group("9f4a5c1e-0000-4000-8000-000000000001"));
+        assert!(!claims.has_group("9f4a5c1e-0000-4000-8000-000000000002"));
+        assert!(!claims.has_groups_overage());
+
+        let overage = test_token_claims().replace(
+            r#""ver": "2.0""#,
+            r#""ver": "2.0",
+                "_claim_names": {"groups": "src1"},
+                "_claim_sources": {"src1": {"endpoint": "https://graph.windows.net/72f988bf-86f1-41af-91ab-2d7cd011db47/users/690222be-ff1a-4d56-abd1-7e4f7d38e474/getMemberObjects"}}"#,
+        );
+        let token = generate_test_token_from(&overage);
+        let claims = az_auth.validate_token(&token).await.unwrap().claims;
+        assert!(claims.has_groups_overage());
+        assert!(!claims.has_group("9f4a5c1e-0000-4000-8000-000000000001"));
+        assert!(claims
+            .groups_overage_endpoint()
+            .unwrap()
+            .ends_with("/getMemberObjects"));
+
+        let implicit = AzureJwtClaims {
+            hasgroups: Some(true),
+            ..Default::default()
+        };
+        assert!(implicit.has_groups_overage());
+        assert_eq!(implicit.groups_overage_endpoint(), None);
+    }
+
+    #[tokio::test]
+    async fn fetches_from_the_fallback_authority() {
+        let token = generate_test_token();
+        let jwks = test_jwks_json();
+        let primary = serve(vec![
+            (503, String::new()),
+            (503, String::new()),
+            (200, jwks.clone()),
+            (404, String::new()),
+        ]);
+        let (fallback, fallback_requests) = serve_recorded(vec![(200, jwks)]);
+        // the primary authority is the host of the jwks_uri
+        let mut az_auth = AzureAuth::new_seeded(
+            "6e74172b-be56-4843-9ff4-e66a39bb12e3",
+            format!("{}/common/discovery/v2.0/keys", primary),
+            vec![test_key()],
+        )
+        .unwrap();
+        az_auth.set_fallback_authority(Some(fallback));
+        az_auth.set_min_fetch_interval(Duration::ZERO);
+        assert!(!az_auth.cache_status().keys_from_fallback);
+
+        az_auth.set_last_refresh(SystemTime::now() - clock::hours(25));
+        az_auth.validate_token(&token).await.unwrap();
+        assert!(az_auth.oidc.is_keys_valid());
+        assert!(az_auth.cache_status().keys_from_fallback);
+        let requests = fallback_requests.join().unwrap();
+        assert!(requests[0].starts_with("GET /common/discovery/v2.0/keys "));
+
+        // the primary is tried first again
+        az_auth.set_last_refresh(SystemTime::now() - clock::hours(25));
+        az_auth.validate_token(&token).await.unwrap();
+        assert!(!az_auth.cache_status().keys_from_fallback);
+
+        // a missing document isn't transient, the fallback has no answer left anyway
+        az_auth.set_last_refresh(SystemTime::now() - clock::hours(25));
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(
+            err,
+            AuthErr::HttpError {
+                status: Some(404),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn handle_validates_from_several_threads() {
+        let az_auth = test_auth();
+        let handle = AzureAuthHandle::new(az_auth);
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let handle = handle.clone();
+                std::thread::spawn(move || {
+                    tokio_test::block_on(handle.validate(&generate_test_token()))
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert_eq!(
+                thread.join().unwrap().unwrap().aud,
+                "6e74172b-be56-4843-9ff4-e66a39bb12e3"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn audience_can_ignore_case() {
+        let token = generate_test_token();
+        let mut az_auth =
+            AzureAuth::new_offline("6E74172B-BE56-4843-9FF4-E66A39BB12E3", vec![test_key()])
+                .unwrap();
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(err, AuthErr::InvalidAudience { .. }));
+
+        az_auth.set_case_insensitive_audience(true);
+        az_auth.validate_token(&token).await.unwrap();
+        let detailed = az_auth.validate_detailed(&token).await.unwrap();
+        assert!(detailed.is_valid());
+
+        az_auth.set_audience("another-app");
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(
+            matches!(err, AuthErr::InvalidAudience { expected, .. } if expected == "another-app")
+        );
+    }
+
+    #[test]
+    fn time_until_expiry_saturates() {
+        let now = chrono::Utc::now().timestamp();
+        let claims: AzureJwtClaims =
+            serde_json::from_str(&test_token_claims_at(now - 1000, now - 1000, now + 600)).unwrap();
+        let left = claims.time_until_expiry();
+        assert!(left > Duration::from_secs(590) && left <= Duration::from_secs(600));
+
+        let claims: AzureJwtClaims =
+            serde_json::from_str(&test_token_claims_at(now - 1000, now - 1000, now - 10)).unwrap();
+        assert_eq!(claims.time_until_expiry(), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn audiences_can_depend_on_the_claims() {
+        let token = generate_test_token();
+        let mut az_auth = AzureAuth::new_offline("another-app", vec![test_key()]).unwrap();
+        az_auth.set_audience_resolver(|claims| {
+            if claims.tid == "72f988bf-86f1-41af-91ab-2d7cd011db47" {
+                vec!["6e74172b-be56-4843-9ff4-e66a39bb12e3".to_string()]
+            } else {
+                Vec::new()
+            }
+        });
+        az_auth.validate_token(&token).await.unwrap();
+
+        az_auth.set_audience_resolver(|_| vec!["app-a".to_string(), "app-b".to_string()]);
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(
+            matches!(err, AuthErr::InvalidAudience { expected, .. } if expected == "app-a, app-b")
+        );
+    }
+
+    #[test]
+    fn public_types_are_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<AzureAuth>();
+        assert_send_sync::<AzureAuthHandle>();
+        assert_send_sync::<OidcValidator>();
+        assert_send_sync::<AuthErr>();
+        assert_send_sync::<Token<AzureJwtClaims>>();
+    }
+
+    #[tokio::test]
+    async fn required_scopes_are_enforced() {
+        let claims = test_token_claims().replace("access_as_user", "User.Read Files.Read");
+        let token = generate_test_token_from(&claims);
+        let mut az_auth = test_auth();
+        az_auth.set_required_scopes(vec!["Files.Read".into(), "User.Read".into()]);
+        az_auth.validate_token(&token).await.unwrap();
+
+        az_auth.set_required_scopes(vec!["User.Read".into(), "Files.Write".into()]);
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(err, AuthErr::MissingScope(scope) if scope == "Files.Write"));
+        let err = az_auth
+            .validate_token(&generate_test_token())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AuthErr::MissingScope(scope) if scope == "User.Read"));
+    }
+
+    #[test]
+    fn exposes_the_default_validation() {
+        let mut az_auth = test_auth();
+        az_auth.set_leeway(10);
+        let validation = jwt::Validation::from(&az_auth);
+        assert_eq!(validation.algorithms, [jwt::Algorithm::RS256]);
+        assert_eq!(validation.leeway, 10);
+        assert!(validation.validate_exp);
+        assert_eq!(
+            validation.aud.unwrap().into_iter().collect::<Vec<_>>(),
+            ["6e74172b-be56-4843-9ff4-e66a39bb12e3"]
+        );
+
+        az_auth.set_validate_audience(false);
+        assert!(jwt::Validation::from(az_auth.oidc()).aud.is_none());
+    }
+
+    #[test]
+    fn pool_idle_timeout_keeps_the_keys() {
+        let mut az_auth = test_auth();
+        az_auth
+            .set_pool_idle_timeout(Duration::from_secs(300))
+            .unwrap();
+        az_auth.try_validate(&generate_test_token()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn pool_idle_timeout_closes_idle_connections() {
+        use std::io::{Read, Write};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let jwks = test_jwks_json();
+        // unlike `serve`, keeps the connections open for as many requests as they get
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let connections = std::sync::Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                accepted.fetch_add(1, Ordering::SeqCst);
+                let jwks = jwks.clone();
+                std::thread::spawn(move || loop {
+                    let mut request = Vec::new();
+                    let mut buf = [0; 4096];
+                    while !request_complete(&request) {
+                        match stream.read(&mut buf) {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let resp = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                        jwks.len(),
+                        jwks
+                    );
+                    if stream.write_all(resp.as_bytes()).is_err() {
+                        return;
+                    }
+                });
+            }
+        });
+
+        let mut az_auth =
+            AzureAuth::new_seeded("6e74172b-be56-4843-9ff4-e66a39bb12e3", url, vec![]).unwrap();
+        az_auth.set_min_fetch_interval(Duration::ZERO);
+        az_auth
+            .set_pool_idle_timeout(Duration::from_millis(100))
+            .unwrap();
+
+        az_auth.oidc.refresh_pub_keys().await.unwrap();
+        let before = connections.load(Ordering::SeqCst);
+
+        // idle for far longer than the timeout, so the next fetch needs a new connection. The
+        // default timeout of 90s would reuse the first one.
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        az_auth.oidc.refresh_pub_keys().await.unwrap();
+        assert!(connections.load(Ordering::SeqCst) > before);
+        az_auth
+            .validate_token(&generate_test_token())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn refreshes_within_the_same_second_are_warned_about() {
+        let mut az_auth =
+            AzureAuth::new_offline("6e74172b-be56-4843-9ff4-e66a39bb12e3", vec![]).unwrap();
+        az_auth.set_key_source(CountingKeys::default());
+        az_auth.set_min_refresh_interval(Duration::ZERO);
+        az_auth.set_public_keys(vec![Jwk {
+            kid: "other".to_string(),
+            ..test_key()
+        }]);
+        az_auth.set_last_refresh(SystemTime::now());
+
+        // the unknown kid makes us refresh right away, `last_refresh` stays the same second
+        let outcome = az_auth
+            .validate_with_warnings(&generate_test_token())
+            .await
+            .unwrap();
+        assert_eq!(outcome.warnings(), [ValidationWarning::KeysRefreshed]);
+    }
+
+    #[tokio::test]
+    async fn tokens_without_ver_are_v1() {
+        let claims = test_token_claims()
+            .replace(r#"IYFAA","#, r#"IYFAA""#)
+            .replace(r#""ver": "2.0""#, "");
+        assert!(!claims.contains(r#""ver""#));
+        let token = generate_test_token_from(&claims);
+        let mut az_auth = test_auth();
+        az_auth.set_required_version(Some(TokenVersion::V1));
+        let claims = az_auth.validate_token(&token).await.unwrap().claims;
+        assert_eq!(claims.ver, None);
+        assert_eq!(claims.version(), Some(TokenVersion::V1));
+    }
+
+    #[tokio::test]
+    async fn accepted_tokens_can_have_warnings() {
+        let now = chrono::Utc::now().timestamp();
+        let mut az_auth =
+            AzureAuth::new_offline("6e74172b-be56-4843-9ff4-e66a39bb12e3", vec![]).unwrap();
+        az_auth.set_key_source(CountingKeys::default());
+
+        let outcome = az_auth
+            .validate_with_warnings(&generate_test_token())
+            .await
+            .unwrap();
+        assert_eq!(outcome.warnings(), [ValidationWarning::KeysRefreshed]);
+
+        let expired =
+            generate_test_token_from(&test_token_claims_at(now - 1000, now - 1000, now - 30));
+        let outcome = az_auth.validate_with_warnings(&expired).await.unwrap();
+        assert!(matches!(
+            outcome.warnings(),
+            [ValidationWarning::ExpiredWithinLeeway { .. }]
+        ));
+
+        let expiring =
+            generate_test_token_from(&test_token_claims_at(now - 1000, now - 1000, now + 30));
+        let outcome = az_auth.validate_with_warnings(&expiring).await.unwrap();
+        assert!(matches!(
+            outcome.warnings(),
+            [ValidationWarning::ExpiresSoon { .. }]
+        ));
+        assert!(az_auth
+            .validate_with_warnings(&generate_test_token())
+            .await
+            .unwrap()
+            .warnings()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_tokens() {
+        let token = generate_test_token();
+        let mut az_auth = test_auth();
+        let huge = format!("{}.{}.sig", "a".repeat(16 * 1024), "a");
+        let err = az_auth.validate_token(&huge).await.unwrap_err();
+        assert!(matches!(err, AuthErr::TokenTooLarge { max, .. } if max == 16 * 1024));
+
+        az_auth.set_max_token_bytes(token.len());
+        az_auth.validate_token(&token).await.unwrap();
+        az_auth.set_max_token_bytes(token.len() - 1);
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(err, AuthErr::TokenTooLarge { size, .. } if size == token.len()));
+    }
+
+    #[tokio::test]
+    async fn payload_must_be_utf8() {
+        let private_key = jwt::EncodingKey::from_base64_secret(PRIVATE_KEY_TEST).unwrap();
+        let message = format!(
+            "{}.{}",
+            general_purpose::URL_SAFE_NO_PAD.encode(test_token_header()),
+            general_purpose::URL_SAFE_NO_PAD.encode(b"{\"sub\": \"\xff\xfe\"}")
+        );
+        let signature =
+            jwt::crypto::sign(message.as_bytes(), &private_key, jwt::Algorithm::RS256).unwrap();
+        let token = format!("{}.{}", message, signature);
+
+        let mut az_auth = test_auth();
+        let err = az_auth.validate_token(&token).await.unwrap_err();
+        assert!(matches!(err, AuthErr::MalformedToken(msg) if msg.contains("UTF-8")));
+    }
+
+    #[tokio::test]
+    async fn validates_other_providers_by_metadata_url() {
+        let url = "https://idp.example.com/.well-known/openid-configuration";
+        let mut az_auth = AzureAuth::new_offline("another-app", vec![]).unwrap();
+        az_auth.set_network_disabled(true);
+        let err = az_auth
+            .validate_with_metadata_url::<AzureJwtClaims>(&generate_test_token(), url)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AuthErr::KeysStale));
+
+        let provider =
+            OidcValidator::new_offline("6e74172b-be56-4843-9ff4-e66a39bb12e3", vec![test_key()])
+                .unwrap();
+        az_auth.providers.insert(url.to_string(), provider);
+        let token = az_auth
+            .validate_with_metadata_url::<AzureJwtClaims>(&generate_test_token(), url)
+            .await
+            .unwrap();
+        assert_eq!(token.claims.aud, "6e74172b-be56-4843-9ff4-e66a39bb12e3");
+
+        // settings changed after the provider was added apply to it too
+        az_auth.set_algorithms(vec![jwt::Algorithm::RS512]);
+        let err = az_auth
+            .validate_with_metadata_url::<AzureJwtClaims>(&generate_test_token(), url)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AuthErr::InvalidToken(e)
+            if matches!(e.kind(), jwt::errors::ErrorKind::InvalidAlgorithm)));
+    }
+
+    #[test]
+    fn reads_the_unverified_tenant() {
+        assert_eq!(
+            AzureAuth::tenant_from_token(&generate_test_token()).unwrap(),
+            "72f988bf-86f1-41af-91ab-2d7cd011db47"
+        );
+        let without_tid =
+            generate_test_token_from(&test_token_claims().replace(r#""tid""#, r#""tenant""#));
+        assert!(matches!(
+            AzureAuth::tenant_from_token(&without_tid),
+            Err(AuthErr::ParseError(_))
+        ));
+        assert!(matches!(
+            AzureAuth::tenant_from_token("not-a-token"),
+            Err(AuthErr::MalformedToken(_))
+        ));
+    }
 
     #[tokio::test]
     async fn refresh_rwks_uri() {
@@ -759,7 +5063,7 @@ xMd+OWT6JsInVM1ASh1mcn+Q0/Z3WqxxetCQLqaMs+FATn059dGf";
     #[tokio::test]
     async fn azure_ad_get_public_keys() {
         let mut az_auth = AzureAuth::new("app_secret").await.unwrap();
-        az_auth.refresh_pub_keys().await.unwrap();
+        az_auth.oidc.refresh_pub_keys().await.unwrap();
     }
 
     #[tokio::test]
@@ -771,8 +5075,8 @@ xMd+OWT6JsInVM1ASh1mcn+Q0/Z3WqxxetCQLqaMs+FATn059dGf";
     #[tokio::test]
     async fn is_not_valid_more_than_24h() {
         let mut az_auth = AzureAuth::new("app_secret").await.unwrap();
-        az_auth.last_refresh = Some(Local::now().naive_local() - Duration::hours(25));
+        az_auth.oidc.last_refresh = Some(Timestamp::now() - clock::hours(25));
 
-        assert!(!az_auth.is_keys_valid());
+        assert!(!az_auth.oidc.is_keys_valid());
     }
 }
@@ -0,0 +1,1109 @@
+use crate::clock::{self, Timestamp};
+use crate::keyset::KeySet;
+use crate::source::KeySource;
+use crate::telemetry;
+use crate::{AuthErr, Jwk, JwkSet, OpenIdConfiguration, Token};
+use async_recursion::async_recursion;
+use base64::Engine;
+use jsonwebtoken as jwt;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+pub(crate) const DEFAULT_LEEWAY: u64 = 60;
+/// Accepted tokens expiring within this many seconds get a `ValidationWarning::ExpiresSoon`.
+#[cfg(feature = "azure-claims")]
+pub(crate) const EXPIRES_SOON: u64 = 60;
+pub(crate) const DEFAULT_MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+pub(crate) const DEFAULT_MIN_FETCH_INTERVAL: Duration = Duration::from_secs(30);
+/// Azure tokens are a few KiB at most, even with many group or role claims.
+pub(crate) const DEFAULT_MAX_TOKEN_BYTES: usize = 16 * 1024;
+const FETCH_ATTEMPTS: u32 = 2;
+const FETCH_RETRY_DELAY: Duration = Duration::from_millis(250);
+/// Sent with every request to Microsoft, so the crate and its version show up in their logs.
+pub(crate) const USER_AGENT: &str = concat!("azure_jwt_async/", env!("CARGO_PKG_VERSION"));
+
+/// Validates tokens of any OpenID Connect provider, i.e. Okta, Auth0 or Google. The OpenID
+/// metadata of the authority tells us where its public keys are published and which issuer its
+/// tokens have, the keys are then fetched and used to verify the tokens.
+///
+/// `AzureAuth` is built on top of this and adds the Azure specifics (tenants, v1.0 and v2.0
+/// issuers, Azure claims). Use that one for Azure tokens.
+///
+/// # Defaults
+///
+/// - Public key expiration: 24h, use `set_expiration` to change it.
+/// - Hashing algorithm: RS256, use `set_algorithms` to change it.
+/// - Retry on no match, limited to once a minute. See `set_min_refresh_interval` and
+///   `set_no_retry`.
+/// - At most one successful fetch of the metadata or the public keys every 30s, except for the
+///   retry on no match. See `set_min_fetch_interval`.
+/// - 60s leeway on `exp` and `nbf`, use `set_leeway` to change it.
+/// - The `iss` claim must match the issuer in the OpenID metadata, use `set_issuer` to change
+///   or disable this.
+#[derive(Debug, Clone)]
+pub struct OidcValidator {
+    pub(crate) audience: String,
+    pub(crate) validate_audience: bool,
+    pub(crate) case_insensitive_audience: bool,
+    pub(crate) issuer: Option<String>,
+    pub(crate) metadata_url: Option<String>,
+    pub(crate) discovery: Option<OpenIdConfiguration>,
+    pub(crate) jwks_uri: String,
+    pub(crate) additional_jwks_uris: Vec<String>,
+    pub(crate) public_keys: Option<KeySet>,
+    /// Keys added with `add_trusted_key`, never replaced by a refresh.
+    pub(crate) trusted_keys: KeySet,
+    pub(crate) key_grace_period: Duration,
+    pub(crate) last_refresh: Option<Timestamp>,
+    pub(crate) exp_hours: i64,
+    pub(crate) refresh_jitter: f64,
+    /// Where in the jitter window this instance refreshes, drawn once per instance.
+    pub(crate) jitter_point: f64,
+    pub(crate) leeway: u64,
+    pub(crate) algorithms: Vec<jwt::Algorithm>,
+    pub(crate) min_refresh_interval: Duration,
+    pub(crate) min_fetch_interval: Duration,
+    pub(crate) last_fetch: Option<Timestamp>,
+    pub(crate) retry_counter: u32,
+    pub(crate) last_retry: Option<Timestamp>,
+    /// How often the keys were fetched and retried with since the validator was created, see
+    /// `AzureAuth::validate_with_diagnostics`.
+    pub(crate) refresh_count: u64,
+    pub(crate) retry_count: u64,
+    pub(crate) is_retry_enabled: bool,
+    pub(crate) is_offline: bool,
+    pub(crate) pinned_kids: Option<Vec<String>>,
+    pub(crate) require_temporal_claims: bool,
+    pub(crate) max_token_bytes: usize,
+    pub(crate) is_network_disabled: bool,
+    pub(crate) key_source: Option<Arc<dyn KeySource>>,
+    pub(crate) http: reqwest::Client,
+    /// Another authority serving the same documents as the one of `metadata_url` and
+    /// `jwks_uri`, see `AzureAuth::set_fallback_authority`.
+    pub(crate) fallback_authority: Option<String>,
+    /// Whether the current public keys were fetched from the fallback authority.
+    pub(crate) keys_from_fallback: bool,
+}
+
+/// Public keys as fetched by `OidcValidator::fetch_keys`.
+pub(crate) struct FetchedKeys {
+    pub(crate) keys: Vec<Jwk>,
+    /// `true` if any of them came from the fallback authority.
+    pub(crate) from_fallback: bool,
+}
+
+/// The scheme, host and port of `url`, i.e. `https://login.microsoftonline.com`.
+fn origin(url: &str) -> Option<&str> {
+    let host = url.find("://")? + 3;
+    let end = url[host..].find('/').map_or(url.len(), |path| host + path);
+    Some(&url[..end])
+}
+
+impl OidcValidator {
+    /// Creates a validator for tokens issued by `authority`, i.e. `https://accounts.google.com`,
+    /// for the audience `aud`. The OpenID metadata is fetched from
+    /// `{authority}/.well-known/openid-configuration` and the issuer of the tokens is taken from
+    /// it.
+    ///
+    /// # Errors
+    ///
+    /// If there is a connection issue to the authority or its metadata can't be parsed.
+    pub async fn new(authority: &str, aud: impl Into<String>) -> Result<Self, AuthErr> {
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            authority.trim_end_matches('/')
+        );
+        OidcValidator::from_metadata_url(url, aud).await
+    }
+
+    /// Same as `new`, but with the full url of the OpenID metadata document for providers that
+    /// don't publish it at the well-known location.
+    pub async fn from_metadata_url(
+        url: impl Into<String>,
+        aud: impl Into<String>,
+    ) -> Result<Self, AuthErr> {
+        let url = url.into();
+        let discovery = fetch_discovery(shared_client()?, &url).await?;
+        let mut validator = OidcValidator::with_defaults(aud.into(), Some(discovery), None, false)?;
+        validator.issuer = validator.discovery.as_ref().map(|d| d.issuer.clone());
+        validator.metadata_url = Some(url);
+        Ok(validator)
+    }
+
+    /// Does not fetch the OpenID metadata or the public keys. Use this if you want to handle
+    /// updating the public keys yourself. The issuer isn't validated unless you `set_issuer`.
+    pub fn new_offline(aud: impl Into<String>, public_keys: Vec<Jwk>) -> Result<Self, AuthErr> {
+        OidcValidator::with_defaults(aud.into(), None, Some(public_keys), true)
+    }
+
+    pub(crate) fn with_defaults(
+        aud: String,
+        discovery: Option<OpenIdConfiguration>,
+        public_keys: Option<Vec<Jwk>>,
+        is_offline: bool,
+    ) -> Result<Self, AuthErr> {
+        let last_refresh = public_keys.as_ref().map(|_| Timestamp::now());
+        Ok(OidcValidator {
+            audience: aud,
+            validate_audience: true,
+            case_insensitive_audience: false,
+            issuer: None,
+            metadata_url: None,
+            jwks_uri: discovery
+                .as_ref()
+                .map(|d| d.jwks_uri.clone())
+                .unwrap_or_default(),
+            additional_jwks_uris: Vec::new(),
+            discovery,
+            public_keys: public_keys.map(KeySet::new),
+            trusted_keys: KeySet::new(Vec::new()),
+            key_grace_period: Duration::ZERO,
+            last_refresh,
+            exp_hours: 24,
+            refresh_jitter: 0.0,
+            jitter_point: random_unit(),
+            leeway: DEFAULT_LEEWAY,
+            algorithms: vec![jwt::Algorithm::RS256],
+            min_refresh_interval: DEFAULT_MIN_REFRESH_INTERVAL,
+            min_fetch_interval: DEFAULT_MIN_FETCH_INTERVAL,
+            last_fetch: None,
+            retry_counter: 0,
+            last_retry: None,
+            refresh_count: 0,
+            retry_count: 0,
+            is_retry_enabled: true,
+            is_offline,
+            pinned_kids: None,
+            require_temporal_claims: false,
+            max_token_bytes: DEFAULT_MAX_TOKEN_BYTES,
+            is_network_disabled: false,
+            key_source: None,
+            http: shared_client()?.clone(),
+            fallback_authority: None,
+            keys_from_fallback: false,
+        })
+    }
+
+    /// Default validation, see `OidcValidator` documentation for the defaults. The claims are
+    /// mapped to your own type.
+    ///
+    /// # Errors
+    ///
+    /// `AuthErr::InvalidIssuer` if the issuer doesn't match, otherwise the errors of
+    /// `validate_custom`.
+    pub async fn validate<T>(&mut self, token: &str) -> Result<Token<T>, AuthErr>
+    where
+        for<'de> T: Serialize + Deserialize<'de>,
+    {
+        let result = self.validate_default(token).await;
+        telemetry::validation(token, &result);
+        result
+    }
+
+    async fn validate_default<T>(&mut self, token: &str) -> Result<Token<T>, AuthErr>
+    where
+        for<'de> T: Serialize + Deserialize<'de>,
+    {
+        let validator = self.default_validation();
+        let decoded: Token<T> = self.validate_with_current_keys(token, &validator).await?;
+
+        if let Some(expected) = &self.issuer {
+            // the token is verified at this point, we only read its `iss` claim again
+            let issuer: Token<IssuerClaim> = insecure_decode(token)?;
+            let found = issuer.claims.iss.unwrap_or_default();
+            if found != *expected {
+                return Err(AuthErr::InvalidIssuer {
+                    expected: expected.clone(),
+                    found,
+                });
+            }
+        }
+
+        Ok(decoded)
+    }
+
+    /// Verifies the token with our public keys and validates it with your own `Validation`.
+    /// The issuer set on the validator is not checked, use `Validation::iss` for that.
+    pub async fn validate_custom<T>(
+        &mut self,
+        token: &str,
+        validator: &jwt::Validation,
+    ) -> Result<Token<T>, AuthErr>
+    where
+        for<'de> T: Serialize + Deserialize<'de>,
+    {
+        let result = self.validate_with_current_keys(token, validator).await;
+        telemetry::validation(token, &result);
+        result
+    }
+
+    pub(crate) fn default_validation(&self) -> jwt::Validation {
+        let mut validator = jwt::Validation::new(jwt::Algorithm::RS256);
+        validator.algorithms = self.algorithms.clone();
+
+        // exp is validated by default, nbf isn't
+        validator.validate_nbf = true;
+        validator.leeway = self.leeway;
+        if self.validate_audience {
+            validator.set_audience(&[&self.audience]);
+        }
+        validator
+    }
+
+    #[async_recursion]
+    pub(crate) async fn validate_with_current_keys<T>(
+        &mut self,
+        token: &str,
+        validator: &jwt::Validation,
+    ) -> Result<Token<T>, AuthErr>
+    where
+        for<'de> T: Serialize + Deserialize<'de>,
+    {
+        self.check_size(token)?;
+        check_structure(token)?;
+
+        // the source knows its keys changed, no need to wait for them to expire
+        if self.key_source.as_ref().is_some_and(|s| s.has_changed()) {
+            self.reload_pub_keys().await?;
+        }
+
+        // if we´re in offline, we never refresh the keys. It's up to the user to do that.
+        if !self.is_keys_valid() && !self.is_offline {
+            if self.is_network_disabled {
+                return Err(AuthErr::KeysStale);
+            }
+            self.refresh_pub_keys().await?;
+        }
+
+        let kid = match self.decode_with_current_keys(token, validator) {
+            Err(AuthErr::KeyNotFound(kid)) => kid,
+            decoded => {
+                self.retry_counter = 0;
+                return decoded;
+            }
+        };
+
+        // the first time this happens let's go and refresh the keys and try once more.
+        // It could be that our keys are out of date. Rate limited by `min_refresh_interval`
+        // only, a key rotated in since our last fetch shouldn't wait for `min_fetch_interval`.
+        if self.should_retry() {
+            self.last_retry = Some(Timestamp::now());
+            self.retry_count += 1;
+            self.reload_pub_keys().await?;
+            self.retry_counter += 1;
+            self.validate_with_current_keys(token, validator).await
+        } else {
+            self.retry_counter = 0;
+            Err(AuthErr::KeyNotFound(kid))
+        }
+    }
+
+    /// Finds the key the token is signed with among the keys we have and validates the token
+    /// with it. Never refreshes anything.
+    pub(crate) fn decode_with_current_keys<T>(
+        &self,
+        token: &str,
+        validator: &jwt::Validation,
+    ) -> Result<Token<T>, AuthErr>
+    where
+        for<'de> T: Serialize + Deserialize<'de>,
+    {
+        self.check_size(token)?;
+        check_structure(token)?;
+        // does not validate the token!
+        let decoded = decode_header(token).map_err(|e| match e.kind() {
+            jwt::errors::ErrorKind::Utf8(_) => {
+                AuthErr::MalformedToken("The header isn't valid UTF-8.".into())
+            }
+            _ => e.into(),
+        })?;
+
+        let keys = match &self.public_keys {
+            None => return Err(AuthErr::Other("Internal err. No public keys found.".into())),
+            Some(keys) => keys,
+        };
+
+        let (auth_key, key) = match &decoded.kid {
+            Some(kid) => {
+                self.check_pinned(kid)?;
+                keys.find(kid)
+                    .or_else(|| self.trusted_keys.find(kid))
+                    .ok_or_else(|| AuthErr::KeyNotFound(kid.clone()))?
+            }
+            // simple providers leave out the `kid` when they only have one key
+            None => {
+                let (auth_key, key) = keys.single().ok_or(AuthErr::MissingKid)?;
+                self.check_pinned(&auth_key.kid)?;
+                (auth_key, key)
+            }
+        };
+
+        // defense in depth against algorithm confusion, the `Validation` checks the header alg
+        if let Some(alg) = &auth_key.alg {
+            if alg.parse::<jwt::Algorithm>().ok() != Some(decoded.alg) {
+                return Err(AuthErr::AlgorithmKeyMismatch {
+                    token: format!("{:?}", decoded.alg),
+                    key: alg.clone(),
+                });
+            }
+        }
+        if !auth_key.supports(decoded.alg) {
+            return Err(AuthErr::AlgorithmKeyMismatch {
+                token: format!("{:?}", decoded.alg),
+                key: auth_key.kty.clone().unwrap_or_else(|| "RSA".to_string()),
+            });
+        }
+        let key = key?;
+
+        // the signature is checked before the claims are parsed, so a json error here means the
+        // token is authentic but doesn't have the claims we expect
+        // jsonwebtoken compares the audience as is, we do it ourselves once the token is verified
+        let ignoring_case = self.case_insensitive_audience && validator.aud.is_some();
+        // jsonwebtoken refuses keys that don't fit every algorithm of the validation
+        if !validator.algorithms.contains(&decoded.alg) {
+            return Err(AuthErr::InvalidToken(
+                jwt::errors::ErrorKind::InvalidAlgorithm.into(),
+            ));
+        }
+        let mut checked = validator.clone();
+        checked.algorithms.retain(|alg| auth_key.supports(*alg));
+        // like jsonwebtoken 7 did, a `Validation` without audiences doesn't check the `aud` claim
+        if ignoring_case || validator.aud.is_none() {
+            checked.validate_aud = false;
+        }
+
+        let timer = telemetry::Timer::start();
+        let mut result = decode(token, key, &checked);
+        telemetry::decode(timer);
+        if ignoring_case && result.is_ok() {
+            let expected = validator.aud.iter().flatten();
+            let found = unverified_audiences(token);
+            if !found
+                .iter()
+                .any(|found| expected.clone().any(|aud| aud.eq_ignore_ascii_case(found)))
+            {
+                result = Err(jwt::errors::ErrorKind::InvalidAudience.into());
+            }
+        }
+        if self.require_temporal_claims {
+            // jsonwebtoken only reports these after verifying the signature
+            let is_authentic = match &result {
+                Ok(_) => true,
+                Err(e) => matches!(
+                    e.kind(),
+                    jwt::errors::ErrorKind::Json(_)
+                        | jwt::errors::ErrorKind::ExpiredSignature
+                        | jwt::errors::ErrorKind::ImmatureSignature
+                ),
+            };
+            if is_authentic {
+                check_temporal_claims(token)?;
+            }
+        }
+        result.map_err(|e| match e.kind() {
+            // only reported for authentic tokens, the payload is decoded after the signature
+            jwt::errors::ErrorKind::Utf8(_) => {
+                AuthErr::MalformedToken("The payload isn't valid UTF-8.".into())
+            }
+            jwt::errors::ErrorKind::Json(_) if !is_utf8_payload(token) => {
+                AuthErr::MalformedToken("The payload isn't valid UTF-8.".into())
+            }
+            jwt::errors::ErrorKind::Json(err) => AuthErr::ClaimsParseError(err.to_string()),
+            jwt::errors::ErrorKind::InvalidAudience => AuthErr::InvalidAudience {
+                expected: validator
+                    .aud
+                    .as_ref()
+                    .map(|aud| {
+                        let mut aud: Vec<&str> = aud.iter().map(String::as_str).collect();
+                        aud.sort_unstable();
+                        aud.join(", ")
+                    })
+                    .unwrap_or_default(),
+                found: unverified_audience(token),
+            },
+            _ => e.into(),
+        })
+    }
+
+    /// Takes the validation and refresh settings of `other`, but keeps our own audience,
+    /// metadata and keys. For validators made on the fly on behalf of `other`, called before
+    /// each of their validations so they follow its setters.
+    pub(crate) fn take_settings_of(&mut self, other: &OidcValidator) {
+        self.validate_audience = other.validate_audience;
+        self.case_insensitive_audience = other.case_insensitive_audience;
+        self.exp_hours = other.exp_hours;
+        self.refresh_jitter = other.refresh_jitter;
+        self.leeway = other.leeway;
+        self.algorithms = other.algorithms.clone();
+        self.min_refresh_interval = other.min_refresh_interval;
+        self.min_fetch_interval = other.min_fetch_interval;
+        self.is_retry_enabled = other.is_retry_enabled;
+        self.is_network_disabled = other.is_network_disabled;
+        self.require_temporal_claims = other.require_temporal_claims;
+        self.max_token_bytes = other.max_token_bytes;
+        self.http = other.http.clone();
+    }
+
+    /// Rejects tokens larger than `max_token_bytes` before we spend any work on them.
+    pub(crate) fn check_size(&self, token: &str) -> Result<(), AuthErr> {
+        if token.len() > self.max_token_bytes {
+            return Err(AuthErr::TokenTooLarge {
+                size: token.len(),
+                max: self.max_token_bytes,
+            });
+        }
+        Ok(())
+    }
+
+    fn check_pinned(&self, kid: &str) -> Result<(), AuthErr> {
+        match &self.pinned_kids {
+            Some(pinned) if !pinned.iter().any(|p| p == kid) => {
+                Err(AuthErr::KidNotPinned(kid.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn should_retry(&self) -> bool {
+        if self.is_offline || self.is_network_disabled || !self.is_retry_enabled {
+            return false;
+        }
+
+        // only refreshes caused by an unknown `kid` count towards the rate limit, otherwise a
+        // key rotated in right after the keys were fetched would be rejected for a while
+        match &self.last_retry {
+            Some(lr) => self.retry_counter == 0 && lr.elapsed() > self.min_refresh_interval,
+            None => self.retry_counter == 0,
+        }
+    }
+
+    /// How long this instance uses the keys: the expiration, shortened by a random part of the
+    /// jitter window that's fixed per instance.
+    fn key_ttl(&self) -> Duration {
+        clock::hours(self.exp_hours).mul_f64(1.0 - self.refresh_jitter * self.jitter_point)
+    }
+
+    pub(crate) fn is_keys_valid(&self) -> bool {
+        match self.last_refresh {
+            None => false,
+            Some(lr) => lr.elapsed() <= self.key_ttl(),
+        }
+    }
+
+    /// `true` if we fetched something less than `min_fetch_interval` ago, in which case the
+    /// cached metadata and keys are reused instead of fetching again.
+    pub(crate) fn is_fetch_throttled(&self) -> bool {
+        self.last_fetch
+            .is_some_and(|lf| lf.elapsed() < self.min_fetch_interval)
+    }
+
+    pub(crate) async fn refresh_pub_keys(&mut self) -> Result<(), AuthErr> {
+        if self.public_keys.is_some() && self.is_fetch_throttled() {
+            return Ok(());
+        }
+        self.reload_pub_keys().await
+    }
+
+    /// Fetches the keys right away, ignoring the `min_fetch_interval`. Only a successful fetch
+    /// counts towards it, so a failed one is retried by the next validation.
+    async fn reload_pub_keys(&mut self) -> Result<(), AuthErr> {
+        let fetched = self.fetch_keys(&self.jwks_uri).await;
+        telemetry::key_refresh(&fetched);
+        let fetched = fetched?;
+        self.last_fetch = Some(Timestamp::now());
+        self.last_refresh = Some(Timestamp::now());
+        self.refresh_count += 1;
+        self.keys_from_fallback = fetched.from_fallback;
+        self.public_keys = Some(KeySet::merged(
+            self.public_keys.as_ref(),
+            fetched.keys,
+            self.key_grace_period,
+        ));
+        Ok(())
+    }
+
+    /// Gets the keys from the key source, or fetches them from `jwks_uri` and every additional
+    /// uri. A `kid` published at more than one uri is only kept once, the first uri wins.
+    pub(crate) async fn fetch_keys(&self, jwks_uri: &str) -> Result<FetchedKeys, AuthErr> {
+        if let Some(source) = &self.key_source {
+            return Ok(FetchedKeys {
+                keys: source.keys().await?,
+                from_fallback: false,
+            });
+        }
+
+        let (mut keys, mut from_fallback) = self.fetch_jwks(jwks_uri).await?;
+        for uri in &self.additional_jwks_uris {
+            let (more, fallback) = self.fetch_jwks(uri).await?;
+            from_fallback |= fallback;
+            for key in more {
+                if !keys.iter().any(|k| k.kid == key.kid) {
+                    keys.push(key);
+                }
+            }
+        }
+        Ok(FetchedKeys {
+            keys,
+            from_fallback,
+        })
+    }
+
+    /// Refreshes the public keys if they're expired, see `AzureAuth::refresh_if_needed`.
+    ///
+    /// # Errors
+    ///
+    /// If the keys can't be fetched, `AuthErr::KeysStale` if they would have to be with
+    /// `set_network_disabled`.
+    pub async fn refresh_if_needed(&mut self) -> Result<bool, AuthErr> {
+        // in multi-tenant mode there's nothing to refresh until we saw a token
+        let has_source = !self.jwks_uri.is_empty() || self.key_source.is_some();
+        if self.is_keys_valid() || self.is_offline || !has_source {
+            return Ok(false);
+        }
+        if self.is_network_disabled {
+            return Err(AuthErr::KeysStale);
+        }
+        let last_refresh = self.last_refresh;
+        self.refresh_pub_keys().await?;
+        Ok(self.last_refresh != last_refresh)
+    }
+
+    /// Fetches the OpenID metadata at `url`, from the fallback authority if that fails.
+    pub(crate) async fn fetch_discovery(&self, url: &str) -> Result<OpenIdConfiguration, AuthErr> {
+        match fetch_discovery(&self.http, url).await {
+            Err(e) => match self.fallback_url(url, &e) {
+                Some(fallback) => {
+                    telemetry::authority_fallback(url, &fallback, &e);
+                    let discovery = fetch_discovery(&self.http, &fallback).await;
+                    telemetry::authority_fallback_done(&fallback, &discovery);
+                    discovery
+                }
+                None => Err(e),
+            },
+            discovery => discovery,
+        }
+    }
+
+    /// Fetches the JWK set at `uri`, from the fallback authority if that fails, in which case
+    /// the `bool` is `true`.
+    async fn fetch_jwks(&self, uri: &str) -> Result<(Vec<Jwk>, bool), AuthErr> {
+        match fetch_jwks(&self.http, uri).await {
+            Err(e) => match self.fallback_url(uri, &e) {
+                Some(fallback) => {
+                    telemetry::authority_fallback(uri, &fallback, &e);
+                    let keys = fetch_jwks(&self.http, &fallback).await;
+                    telemetry::authority_fallback_done(&fallback, &keys);
+                    Ok((keys?, true))
+                }
+                None => Err(e),
+            },
+            keys => Ok((keys?, false)),
+        }
+    }
+
+    /// Where to fetch `url` instead after fetching it failed with `err`. Only transient failures
+    /// of the primary authority are retried elsewhere, a document it doesn't have or can't
+    /// serve properly won't do any better at a mirror.
+    fn fallback_url(&self, url: &str, err: &AuthErr) -> Option<String> {
+        let transient = match err {
+            AuthErr::HttpError {
+                status: Some(status),
+                ..
+            } => *status >= 500 || *status == 429,
+            AuthErr::HttpError { source, .. } => is_transient(source),
+            AuthErr::ConnectionError(_) => true,
+            _ => false,
+        };
+        if !transient {
+            return None;
+        }
+        let fallback = self.fallback_authority.as_ref()?;
+        // only the documents of our own authority are mirrored
+        let primary = origin(url)?;
+        let ours = [self.metadata_url.as_deref(), Some(self.jwks_uri.as_str())];
+        if !ours
+            .into_iter()
+            .flatten()
+            .any(|u| origin(u) == Some(primary))
+        {
+            return None;
+        }
+        Some(format!(
+            "{}{}",
+            fallback.trim_end_matches('/'),
+            &url[primary.len()..]
+        ))
+    }
+
+    /// Re-fetches the OpenID metadata and with it the `jwks_uri`. The public keys are fetched
+    /// from the new uri the next time they're refreshed. Does nothing if something was fetched
+    /// less than `min_fetch_interval` ago.
+    ///
+    /// # Errors
+    ///
+    /// If there is a connection issue, or `AuthErr::Other` if the validator was created offline
+    /// and has no metadata url.
+    pub async fn refresh_rwks_uri(&mut self) -> Result<(), AuthErr> {
+        let url = match &self.metadata_url {
+            Some(url) => url,
+            None => return Err(AuthErr::Other("No OpenID metadata url to fetch.".into())),
+        };
+        if self.discovery.is_some() && self.is_fetch_throttled() {
+            return Ok(());
+        }
+        let discovery = self.fetch_discovery(url).await?;
+        self.last_fetch = Some(Timestamp::now());
+        self.jwks_uri = discovery.jwks_uri.clone();
+        self.discovery = Some(discovery);
+        Ok(())
+    }
+
+    /// The OpenID metadata document as fetched at construction or on `refresh_rwks_uri`. `None`
+    /// in offline mode.
+    pub fn discovery(&self) -> Option<&OpenIdConfiguration> {
+        self.discovery.as_ref()
+    }
+
+    /// The audience tokens must be issued for.
+    pub fn audience(&self) -> &str {
+        &self.audience
+    }
+
+    /// The leeway in seconds on `exp` and `nbf`, see `set_leeway`.
+    pub fn leeway(&self) -> u64 {
+        self.leeway
+    }
+
+    /// How long the public keys are used before they're refreshed, see `set_expiration`.
+    pub fn key_expiration_hours(&self) -> i64 {
+        self.exp_hours
+    }
+
+    /// Sets the issuer the `iss` claim must match in the default validation, `None` disables
+    /// the check.
+    pub fn set_issuer(&mut self, issuer: Option<String>) {
+        self.issuer = issuer;
+    }
+
+    /// Changes the audience tokens are validated against. The public keys are kept.
+    pub fn set_audience(&mut self, aud: impl Into<String>) {
+        self.audience = aud.into();
+    }
+
+    /// Whether `aud` is our audience, ignoring case if `set_case_insensitive_audience` says so.
+    #[cfg(feature = "azure-claims")]
+    pub(crate) fn is_audience(&self, aud: &str) -> bool {
+        self.audience_eq(&self.audience, aud)
+    }
+
+    /// Whether the `found` audience is the `expected` one, with the same case rules.
+    #[cfg(feature = "azure-claims")]
+    pub(crate) fn audience_eq(&self, expected: &str, found: &str) -> bool {
+        if self.case_insensitive_audience {
+            expected.eq_ignore_ascii_case(found)
+        } else {
+            expected == found
+        }
+    }
+
+    /// Compares the audience ignoring ASCII case, see `AzureAuth::set_case_insensitive_audience`.
+    pub fn set_case_insensitive_audience(&mut self, case_insensitive: bool) {
+        self.case_insensitive_audience = case_insensitive;
+    }
+
+    /// Turns the audience check of the default validation on or off, see
+    /// `AzureAuth::set_validate_audience`.
+    pub fn set_validate_audience(&mut self, validate: bool) {
+        self.validate_audience = validate;
+    }
+
+    /// Sets the expiration of the cached public keys in hours.
+    pub fn set_expiration(&mut self, hours: i64) {
+        self.exp_hours = hours;
+    }
+
+    /// Refreshes the keys at a random point in the last `fraction` of the expiration, see
+    /// `AzureAuth::set_refresh_jitter`. Clamped to `0.0..=1.0` (`NaN` is `0.0`), defaults to
+    /// `0.0`.
+    pub fn set_refresh_jitter(&mut self, fraction: f64) {
+        self.refresh_jitter = if fraction.is_nan() {
+            0.0
+        } else {
+            fraction.clamp(0.0, 1.0)
+        };
+    }
+
+    /// Sets the algorithms tokens may be signed with in the default validation. Defaults to
+    /// `RS256`. A key is only used with the algorithms of its type.
+    pub fn set_algorithms(&mut self, algorithms: Vec<jwt::Algorithm>) {
+        self.algorithms = algorithms;
+    }
+
+    /// Sets the leeway in seconds used when checking the `exp` and `nbf` claims in the default
+    /// validation. Defaults to 60 seconds.
+    pub fn set_leeway(&mut self, seconds: u64) {
+        self.leeway = seconds;
+    }
+
+    /// Sets the minimum time between two refreshes triggered by a token with an unknown `kid`.
+    /// Defaults to one minute.
+    pub fn set_min_refresh_interval(&mut self, interval: Duration) {
+        self.min_refresh_interval = interval;
+    }
+
+    /// Sets the minimum time between two successful fetches of the metadata or the public keys.
+    /// A token with an unknown `kid` still refreshes the keys, that's limited by
+    /// `set_min_refresh_interval` instead. Defaults to 30 seconds.
+    pub fn set_min_fetch_interval(&mut self, interval: Duration) {
+        self.min_fetch_interval = interval;
+    }
+
+    /// Keeps keys that are no longer published for `grace` after they were last fetched, see
+    /// `AzureAuth::set_key_grace_period`. Defaults to zero, the fetched keys replace ours.
+    pub fn set_key_grace_period(&mut self, grace: Duration) {
+        self.key_grace_period = grace;
+    }
+
+    pub fn set_no_retry(&mut self) {
+        self.is_retry_enabled = false;
+    }
+
+    /// Only accepts tokens signed with one of the keys in `kids`, even if other keys we have
+    /// would verify them. See `AzureAuth::set_pinned_kids`.
+    pub fn set_pinned_kids(&mut self, kids: Vec<String>) {
+        self.pinned_kids = Some(kids);
+    }
+
+    /// Closes pooled connections after being idle for `timeout`, see
+    /// `AzureAuth::set_pool_idle_timeout`.
+    ///
+    /// # Errors
+    ///
+    /// `AuthErr::ConnectionError` if the HTTP client can't be created.
+    pub fn set_pool_idle_timeout(&mut self, timeout: Duration) -> Result<(), AuthErr> {
+        self.http = reqwest::Client::builder()
+            .user_agent(USER_AGENT)
+            .pool_idle_timeout(timeout)
+            .build()?;
+        Ok(())
+    }
+
+    /// Rejects larger tokens, see `AzureAuth::set_max_token_bytes`.
+    pub fn set_max_token_bytes(&mut self, max: usize) {
+        self.max_token_bytes = max;
+    }
+
+    /// Rejects tokens without an `exp`, `nbf` or `iat` claim, see
+    /// `AzureAuth::set_require_temporal_claims`.
+    pub fn set_require_temporal_claims(&mut self, require: bool) {
+        self.require_temporal_claims = require;
+    }
+
+    /// Guarantees validation makes no network calls, see `AzureAuth::set_network_disabled`.
+    pub fn set_network_disabled(&mut self, disabled: bool) {
+        self.is_network_disabled = disabled;
+    }
+
+    /// Sets where the public keys are fetched from when they're refreshed.
+    pub fn set_jwks_uri(&mut self, uri: impl Into<String>) {
+        self.jwks_uri = uri.into();
+    }
+
+    /// Also fetches public keys from `uris` whenever the keys are refreshed, see
+    /// `AzureAuth::set_additional_jwks_uris`.
+    pub fn set_additional_jwks_uris(&mut self, uris: Vec<String>) {
+        self.additional_jwks_uris = uris;
+    }
+
+    /// Sets when the current public keys were fetched, which decides when they expire.
+    pub fn set_last_refresh(&mut self, at: SystemTime) {
+        self.last_refresh = Some(Timestamp::from(at));
+    }
+
+    /// Replaces the OpenID metadata and with it the `jwks_uri`. The issuer is left as is, use
+    /// `set_issuer` to change it.
+    pub fn set_discovery(&mut self, discovery: OpenIdConfiguration) {
+        self.jwks_uri = discovery.jwks_uri.clone();
+        self.discovery = Some(discovery);
+    }
+
+    /// Gets the public keys from `source` instead of the `jwks_uri`, see
+    /// `AzureAuth::set_key_source`.
+    pub fn set_key_source(&mut self, source: impl KeySource + 'static) {
+        self.key_source = Some(Arc::new(source));
+        self.is_offline = false;
+        // the keys we have didn't come from the source
+        self.last_refresh = None;
+        self.last_fetch = None;
+    }
+
+    /// Adds a key that is trusted in addition to the fetched ones, see
+    /// `AzureAuth::add_trusted_key`.
+    pub fn add_trusted_key(&mut self, key: Jwk) {
+        self.trusted_keys.insert(key);
+    }
+
+    /// Removes a key added with `add_trusted_key`, `false` if there was none with `kid`.
+    pub fn remove_trusted_key(&mut self, kid: &str) -> bool {
+        self.trusted_keys.remove(kid)
+    }
+
+    /// Replaces the public keys, mostly useful in offline mode.
+    pub fn set_public_keys(&mut self, pub_keys: Vec<Jwk>) {
+        self.last_refresh = Some(Timestamp::now());
+        self.keys_from_fallback = false;
+        self.public_keys = Some(KeySet::new(pub_keys));
+    }
+}
+
+/// The `Validation` the default validation hands to jsonwebtoken. The issuer isn't part of it,
+/// it's checked separately.
+impl From<&OidcValidator> for jwt::Validation {
+    fn from(validator: &OidcValidator) -> Self {
+        validator.default_validation()
+    }
+}
+
+#[derive(Deserialize)]
+struct IssuerClaim {
+    iss: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TemporalClaims {
+    exp: Option<serde_json::Value>,
+    nbf: Option<serde_json::Value>,
+    iat: Option<serde_json::Value>,
+}
+
+/// Fails with `AuthErr::MissingClaim` for the first of `exp`, `nbf` and `iat` that's missing.
+fn check_temporal_claims(token: &str) -> Result<(), AuthErr> {
+    let claims = insecure_decode::<TemporalClaims>(token)?.claims;
+    let missing = [
+        ("exp", claims.exp.is_none()),
+        ("nbf", claims.nbf.is_none()),
+        ("iat", claims.iat.is_none()),
+    ];
+    match missing.iter().find(|(_, is_missing)| *is_missing) {
+        Some((claim, _)) => Err(AuthErr::MissingClaim(claim.to_string())),
+        None => Ok(()),
+    }
+}
+
+#[derive(Deserialize)]
+struct AudienceClaim {
+    aud: Option<serde_json::Value>,
+}
+
+/// The `aud` claim of a token for error messages, a list is joined with `, `. Only used once
+/// the signature is verified.
+fn unverified_audience(token: &str) -> String {
+    unverified_audiences(token).join(", ")
+}
+
+/// The audiences in the `aud` claim of a token, which is a single string or a list.
+fn unverified_audiences(token: &str) -> Vec<String> {
+    let aud = insecure_decode::<AudienceClaim>(token)
+        .ok()
+        .and_then(|t| t.claims.aud);
+    match aud {
+        Some(serde_json::Value::String(aud)) => vec![aud],
+        Some(serde_json::Value::Array(aud)) => aud
+            .iter()
+            .filter_map(serde_json::Value::as_str)
+            .map(String::from)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// A random number in `0.0..1.0`. Good enough to spread refreshes, not for anything secret.
+fn random_unit() -> f64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(now) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(now.as_nanos());
+    }
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+fn is_utf8_payload(token: &str) -> bool {
+    token
+        .split('.')
+        .nth(1)
+        .and_then(|payload| crate::keyset::B64.decode(payload).ok())
+        .is_some_and(|payload| std::str::from_utf8(&payload).is_ok())
+}
+
+/// `token` without the base64 padding of its segments, which jsonwebtoken doesn't accept.
+fn unpadded(token: &str) -> std::borrow::Cow<'_, str> {
+    if token.contains('=') {
+        let segments: Vec<&str> = token.split('.').map(|s| s.trim_end_matches('=')).collect();
+        segments.join(".").into()
+    } else {
+        token.into()
+    }
+}
+
+/// Like `jwt::decode_header`, for padded tokens too.
+pub(crate) fn decode_header(token: &str) -> jwt::errors::Result<jwt::Header> {
+    jwt::decode_header(&unpadded(token))
+}
+
+/// Decodes the claims of `token` without verifying its signature or checking any claim.
+pub(crate) fn insecure_decode<T: DeserializeOwned>(token: &str) -> jwt::errors::Result<Token<T>> {
+    let mut validation = jwt::Validation::default();
+    validation.insecure_disable_signature_validation();
+    validation.required_spec_claims.clear();
+    validation.validate_exp = false;
+    validation.validate_aud = false;
+    jwt::decode(
+        &unpadded(token),
+        &jwt::DecodingKey::from_secret(&[]),
+        &validation,
+    )
+}
+
+/// Like `jwt::decode`, for padded tokens too. Their signature is verified over the segments as
+/// they are, before jsonwebtoken checks the claims of the unpadded token.
+fn decode<T: DeserializeOwned>(
+    token: &str,
+    key: &jwt::DecodingKey,
+    validation: &jwt::Validation,
+) -> jwt::errors::Result<Token<T>> {
+    let unpadded = unpadded(token);
+    if unpadded == token {
+        return jwt::decode(token, key, validation);
+    }
+
+    let header = jwt::decode_header(&unpadded)?;
+    if !validation.algorithms.contains(&header.alg) {
+        return Err(jwt::errors::ErrorKind::InvalidAlgorithm.into());
+    }
+    let (message, signature) = token
+        .rsplit_once('.')
+        .ok_or(jwt::errors::ErrorKind::InvalidToken)?;
+    let signature = signature.trim_end_matches('=');
+    if !jwt::crypto::verify(signature, message.as_bytes(), key, header.alg)? {
+        return Err(jwt::errors::ErrorKind::InvalidSignature.into());
+    }
+
+    let mut verified = validation.clone();
+    verified.insecure_disable_signature_validation();
+    jwt::decode(&unpadded, key, &verified)
+}
+
+/// Checks that `token` looks like a signed JWT, three base64url segments separated by dots,
+/// before we try to decode it. Gives a much clearer error than the decoding would.
+pub(crate) fn check_structure(token: &str) -> Result<(), AuthErr> {
+    if token.is_empty() {
+        return Err(AuthErr::MalformedToken("The token is empty.".into()));
+    }
+
+    let segments: Vec<&str> = token.split('.').collect();
+    if segments.len() != 3 {
+        return Err(AuthErr::MalformedToken(format!(
+            "Expected 3 segments separated by `.` but found {}.",
+            segments.len()
+        )));
+    }
+
+    if !segments.iter().all(|s| is_base64url_segment(s)) {
+        return Err(AuthErr::MalformedToken(
+            "Every segment must be non-empty base64url.".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// `true` if `segment` is a non-empty base64url string, padded or not. Padding is only allowed
+/// at the end, where we strip it before handing the token to jsonwebtoken.
+pub(crate) fn is_base64url_segment(segment: &str) -> bool {
+    let unpadded = segment.trim_end_matches('=');
+    !unpadded.is_empty()
+        && segment.len() - unpadded.len() <= 2
+        && unpadded
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// The client for fetches that aren't made by an instance, i.e. when creating one. Shared so
+/// its connections are reused.
+///
+/// # Errors
+///
+/// `AuthErr::ConnectionError` if the client can't be created, i.e. the TLS backend fails to
+/// initialize. Tried again on the next call.
+pub(crate) fn shared_client() -> Result<&'static reqwest::Client, AuthErr> {
+    static CLIENT: std::sync::OnceLock<reqwest::Client> = std::sync::OnceLock::new();
+    if let Some(client) = CLIENT.get() {
+        return Ok(client);
+    }
+    let client = reqwest::Client::builder().user_agent(USER_AGENT).build()?;
+    Ok(CLIENT.get_or_init(|| client))
+}
+
+pub(crate) async fn fetch_jwks(
+    http: &reqwest::Client,
+    jwks_uri: &str,
+) -> Result<Vec<Jwk>, AuthErr> {
+    let timer = telemetry::Timer::start();
+    let jwks = get_json::<JwkSet>(http, jwks_uri).await;
+    telemetry::jwks_fetch(timer);
+    Ok(jwks?.keys)
+}
+
+pub(crate) async fn fetch_discovery(
+    http: &reqwest::Client,
+    url: &str,
+) -> Result<OpenIdConfiguration, AuthErr> {
+    let timer = telemetry::Timer::start();
+    let discovery = get_json(http, url).await;
+    telemetry::discovery_fetch(timer);
+    discovery
+}
+
+/// Fetches and parses a json document. A dropped connection, a timeout, a server error or a 429
+/// is retried after a short delay, up to `FETCH_ATTEMPTS` times in total. This is unrelated to
+/// the retry on an unknown `kid`, which refreshes keys we did get but that turned out to be
+/// outdated.
+async fn get_json<T: DeserializeOwned>(http: &reqwest::Client, url: &str) -> Result<T, AuthErr> {
+    let mut attempt = 1;
+    loop {
+        match get_json_once(http, url).await {
+            Err(e) if attempt < FETCH_ATTEMPTS && is_transient(&e) => {
+                attempt += 1;
+                tokio::time::sleep(FETCH_RETRY_DELAY).await;
+            }
+            resp => {
+                return resp.map_err(|e| AuthErr::HttpError {
+                    url: url.to_string(),
+                    status: e.status().map(|s| s.as_u16()),
+                    source: e,
+                })
+            }
+        }
+    }
+}
+
+async fn get_json_once<T: DeserializeOwned>(
+    http: &reqwest::Client,
+    url: &str,
+) -> Result<T, reqwest::Error> {
+    http.get(url).send().await?.error_for_status()?.json().await
+}
+
+/// Whether a fetch failed in a way that may succeed if retried: no connection, a timeout, a
+/// server error or a 429. A bad url or a 404 fails again, so these aren't retried.
+fn is_transient(e: &reqwest::Error) -> bool {
+    match e.status() {
+        Some(status) => {
+            status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        }
+        None => e.is_connect() || e.is_timeout(),
+    }
+}
@@ -0,0 +1,59 @@
+use crate::{AzureJwtClaims, Token};
+use std::time::{Duration, SystemTime};
+
+/// Something worth knowing about a token that was accepted, see
+/// `AzureAuth::validate_with_warnings`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ValidationWarning {
+    /// The public keys had to be refreshed to validate the token, so this validation included a
+    /// call to Microsoft.
+    KeysRefreshed,
+    /// The token expires in `remaining`, which is less than a minute.
+    ExpiresSoon { remaining: Duration },
+    /// The token expired `ago` ago and was only accepted because of the leeway. Frequent ones
+    /// point at clients that refresh their tokens too late or at clock skew.
+    ExpiredWithinLeeway { ago: Duration },
+}
+
+/// The result of `AzureAuth::validate_with_warnings`: a valid token together with the warnings
+/// that didn't stop it from being accepted.
+#[derive(Debug)]
+pub struct ValidationOutcome {
+    pub(crate) token: Token<AzureJwtClaims>,
+    pub(crate) warnings: Vec<ValidationWarning>,
+}
+
+impl ValidationOutcome {
+    /// The warnings about the token, empty if there are none.
+    pub fn warnings(&self) -> &[ValidationWarning] {
+        &self.warnings
+    }
+
+    /// The claims of the validated token.
+    pub fn claims(&self) -> &AzureJwtClaims {
+        &self.token.claims
+    }
+
+    /// The validated token, dropping the warnings.
+    pub fn into_token(self) -> Token<AzureJwtClaims> {
+        self.token
+    }
+}
+
+/// What a validation did besides validating, see `AzureAuth::validate_with_diagnostics`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ValidationDiagnostics {
+    /// `true` if the public keys were fetched during the validation, because they were expired
+    /// or for a retry.
+    pub keys_refreshed: bool,
+    /// `true` if the `kid` of the token was unknown and the keys were fetched again to look
+    /// for it.
+    pub retried: bool,
+    /// `true` if the next unknown `kid` would be retried. `false` while the last retry is less
+    /// than `set_min_refresh_interval` ago, or if retrying is off or impossible.
+    pub retry_available: bool,
+    /// When we last retried, `None` if we never did.
+    pub last_retry: Option<SystemTime>,
+}
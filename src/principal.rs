@@ -0,0 +1,19 @@
+use serde::{Deserialize, Serialize};
+
+/// The essentials of who a token identifies, see `AzureJwtClaims::principal`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Principal {
+    /// The `oid` claim, the id of the user or service principal in the tenant.
+    pub object_id: String,
+    /// The `tid` claim, the tenant the user signed in to.
+    pub tenant_id: String,
+    /// The username, for display only. `preferred_username` in v2.0 tokens and `upn` or
+    /// `unique_name` in v1.0 tokens.
+    pub username: Option<String>,
+    /// The display name of the user.
+    pub name: Option<String>,
+    /// The roles assigned to the user, empty if there are none.
+    pub roles: Vec<String>,
+    /// The scopes consented to, empty if there are none.
+    pub scopes: Vec<String>,
+}
@@ -0,0 +1,120 @@
+use crate::{oidc, AuthErr, Jwk};
+use async_trait::async_trait;
+use std::fmt::Debug;
+
+/// Where the public keys come from. By default they're fetched from the `jwks_uri` in the
+/// OpenID metadata, implement this to get them from somewhere else instead, i.e. a secrets
+/// manager, a local cache or a test fixture. See `AzureAuth::set_key_source`.
+#[async_trait]
+pub trait KeySource: Debug + Send + Sync {
+    /// Returns the current public keys. Called whenever the keys need to be refreshed.
+    async fn keys(&self) -> Result<Vec<Jwk>, AuthErr>;
+
+    /// Whether the keys changed since `keys` was last called. Checked before every validation,
+    /// if `true` the keys are fetched again right away. Defaults to `false`, the keys are then
+    /// only fetched again when they expire or a token has an unknown `kid`.
+    fn has_changed(&self) -> bool {
+        false
+    }
+}
+
+/// Fetches the keys from a JWK set url, this is what's used when no `KeySource` is set.
+#[derive(Debug, Clone)]
+pub struct JwksFetcher {
+    jwks_uri: String,
+}
+
+impl JwksFetcher {
+    pub fn new(jwks_uri: impl Into<String>) -> Self {
+        JwksFetcher {
+            jwks_uri: jwks_uri.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl KeySource for JwksFetcher {
+    async fn keys(&self) -> Result<Vec<Jwk>, AuthErr> {
+        oidc::fetch_jwks(oidc::shared_client()?, &self.jwks_uri).await
+    }
+}
+
+/// Reads the keys from a JWK set file and watches its directory, so the keys are reloaded as soon
+/// as the file is changed or replaced. See `AzureAuth::from_jwks_file_watched`.
+#[cfg(feature = "watch")]
+pub(crate) struct WatchedJwksFile {
+    path: std::path::PathBuf,
+    changed: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    // stops watching when dropped
+    _watcher: notify::RecommendedWatcher,
+}
+
+#[cfg(feature = "watch")]
+impl WatchedJwksFile {
+    pub(crate) fn new(path: impl Into<std::path::PathBuf>) -> Result<Self, AuthErr> {
+        use notify::Watcher;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        let path = path.into();
+        let changed = Arc::new(AtomicBool::new(false));
+        let on_event = changed.clone();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                // mounted files are often replaced by swapping a symlink in the directory, so any
+                // change in it may be a change of the file. Reloading is cheap.
+                if event.is_ok_and(|e| !e.kind.is_access()) {
+                    on_event.store(true, Ordering::SeqCst);
+                }
+            })
+            .map_err(|e| AuthErr::Other(format!("Could not watch `{}`. {}", path.display(), e)))?;
+
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => std::path::Path::new("."),
+        };
+        watcher
+            .watch(dir, notify::RecursiveMode::NonRecursive)
+            .map_err(|e| AuthErr::Other(format!("Could not watch `{}`. {}", path.display(), e)))?;
+
+        Ok(WatchedJwksFile {
+            path,
+            changed,
+            _watcher: watcher,
+        })
+    }
+
+    pub(crate) fn read(&self) -> Result<Vec<Jwk>, AuthErr> {
+        self.changed
+            .store(false, std::sync::atomic::Ordering::SeqCst);
+        let json = std::fs::read(&self.path).map_err(|e| {
+            AuthErr::Other(format!("Could not read `{}`. {}", self.path.display(), e))
+        })?;
+        let jwks: crate::JwkSet = serde_json::from_slice(&json).map_err(|e| {
+            AuthErr::ParseError(format!("`{}` is no JWK set. {}", self.path.display(), e))
+        })?;
+        Ok(jwks.keys)
+    }
+}
+
+#[cfg(feature = "watch")]
+impl Debug for WatchedJwksFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WatchedJwksFile")
+            .field("path", &self.path)
+            .field("changed", &self.changed)
+            .finish()
+    }
+}
+
+#[cfg(feature = "watch")]
+#[async_trait]
+impl KeySource for WatchedJwksFile {
+    async fn keys(&self) -> Result<Vec<Jwk>, AuthErr> {
+        self.read()
+    }
+
+    fn has_changed(&self) -> bool {
+        self.changed.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
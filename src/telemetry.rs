@@ -0,0 +1,234 @@
+//! Counters for validation outcomes and key refreshes, and histograms for how long fetching and
+//! validating takes, emitted through the `metrics` crate when the `metrics` feature is enabled.
+//! Without it these functions are empty and optimized away, and `Timer` is zero sized. With the
+//! `tracing` feature rejected tokens are also logged at debug level, and an audience that looks
+//! misconfigured or a fetch from the fallback authority at warn level. Without it those two go
+//! to stderr.
+
+use crate::AuthErr;
+
+/// Measures the duration of a step for one of the histograms, in seconds.
+pub(crate) struct Timer {
+    #[cfg(feature = "metrics")]
+    start: std::time::Instant,
+}
+
+impl Timer {
+    #[inline]
+    pub(crate) fn start() -> Self {
+        Timer {
+            #[cfg(feature = "metrics")]
+            start: std::time::Instant::now(),
+        }
+    }
+
+    #[inline]
+    fn record(self, name: &'static str) {
+        #[cfg(feature = "metrics")]
+        metrics::histogram!(name).record(self.start.elapsed().as_secs_f64());
+        #[cfg(not(feature = "metrics"))]
+        let _ = name;
+    }
+}
+
+/// Records how long fetching the OpenID metadata took.
+#[inline]
+pub(crate) fn discovery_fetch(timer: Timer) {
+    timer.record("azure_jwt_discovery_fetch_seconds");
+}
+
+/// Records how long fetching a JWK set took.
+#[inline]
+pub(crate) fn jwks_fetch(timer: Timer) {
+    timer.record("azure_jwt_jwks_fetch_seconds");
+}
+
+/// Records how long verifying and decoding a token took, without fetching any keys.
+#[inline]
+pub(crate) fn decode(timer: Timer) {
+    timer.record("azure_jwt_validation_seconds");
+}
+
+/// Records the outcome of a validation of `token`.
+#[inline]
+pub(crate) fn validation<T>(token: &str, result: &Result<T, AuthErr>) {
+    #[cfg(feature = "tracing")]
+    if let Err(e) = result {
+        log_rejection(token, e);
+    }
+    #[cfg(not(feature = "tracing"))]
+    let _ = token;
+
+    #[cfg(feature = "metrics")]
+    {
+        metrics::counter!("azure_jwt_validations_total").increment(1);
+        match result {
+            Ok(_) => metrics::counter!("azure_jwt_validations_succeeded_total").increment(1),
+            Err(e) => {
+                metrics::counter!("azure_jwt_validations_failed_total", "reason" => reason(e))
+                    .increment(1)
+            }
+        }
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = result;
+}
+
+/// Records a fetch of the public keys.
+#[inline]
+pub(crate) fn key_refresh<T>(result: &Result<T, AuthErr>) {
+    #[cfg(feature = "metrics")]
+    {
+        metrics::counter!("azure_jwt_key_refreshes_total").increment(1);
+        if result.is_err() {
+            metrics::counter!("azure_jwt_key_refresh_failures_total").increment(1);
+        }
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = result;
+}
+
+/// Records that fetching `url` failed with `err` and `fallback` is fetched instead, see
+/// `AzureAuth::set_fallback_authority`. Without `tracing` this goes to stderr, falling back
+/// means the primary authority is in trouble.
+#[inline]
+pub(crate) fn authority_fallback(url: &str, fallback: &str, err: &AuthErr) {
+    #[cfg(feature = "tracing")]
+    tracing::warn!(url, fallback, error = %err, "Fetching from the fallback authority");
+    #[cfg(not(feature = "tracing"))]
+    eprintln!(
+        "warning: fetching `{}` failed ({}), fetching `{}` from the fallback authority",
+        url, err, fallback
+    );
+}
+
+/// Records whether the fallback authority served `fallback`, see `authority_fallback`.
+#[inline]
+pub(crate) fn authority_fallback_done<T>(fallback: &str, result: &Result<T, AuthErr>) {
+    #[cfg(feature = "tracing")]
+    match result {
+        Ok(_) => tracing::info!(fallback, "Fetched from the fallback authority"),
+        Err(e) => tracing::warn!(fallback, error = %e, "The fallback authority failed too"),
+    }
+    #[cfg(not(feature = "tracing"))]
+    match result {
+        Ok(_) => eprintln!("info: fetched `{}` from the fallback authority", fallback),
+        Err(e) => eprintln!(
+            "warning: fetching `{}` from the fallback authority failed too ({})",
+            fallback, e
+        ),
+    }
+
+    #[cfg(feature = "metrics")]
+    {
+        let outcome = if result.is_ok() {
+            "succeeded"
+        } else {
+            "failed"
+        };
+        metrics::counter!("azure_jwt_authority_fallbacks_total", "outcome" => outcome).increment(1);
+    }
+}
+
+/// Warns about a configured audience that doesn't look like one, see
+/// `AzureAuth::set_strict_audience`. Without `tracing` the warning goes to stderr, it's a
+/// configuration mistake that shouldn't go unnoticed.
+#[inline]
+pub(crate) fn suspicious_audience(err: &AuthErr) {
+    #[cfg(feature = "tracing")]
+    tracing::warn!("{}", err);
+    #[cfg(not(feature = "tracing"))]
+    eprintln!("warning: {}", err);
+}
+
+/// Warns that tokens for Microsoft Graph are accepted, see
+/// `AzureAuth::set_accept_graph_audience`.
+#[cfg(feature = "azure-claims")]
+#[inline]
+pub(crate) fn graph_audience_accepted() {
+    #[cfg(feature = "tracing")]
+    tracing::warn!(
+        "Accepting tokens for Microsoft Graph. Microsoft advises against validating them outside \
+         of Graph, tokens with a `nonce` header can't be verified."
+    );
+}
+
+/// Logs why `token` was rejected together with its `kid`, `alg` and `ver`. Never the token
+/// itself or anything else from it, a rejected token may still be a valid one.
+#[cfg(feature = "tracing")]
+fn log_rejection(token: &str, err: &AuthErr) {
+    #[derive(serde::Deserialize)]
+    struct VersionClaim {
+        ver: Option<String>,
+    }
+
+    if !tracing::enabled!(tracing::Level::DEBUG) {
+        return;
+    }
+    // not worth decoding, and there's no header to speak of in garbage
+    let decodable = !matches!(
+        err,
+        AuthErr::TokenTooLarge { .. } | AuthErr::MalformedToken(_)
+    );
+    let header = decodable
+        .then(|| crate::oidc::decode_header(token).ok())
+        .flatten();
+    let alg = header.as_ref().map(|h| format!("{:?}", h.alg));
+    let ver = decodable
+        .then(|| crate::oidc::insecure_decode::<VersionClaim>(token).ok())
+        .flatten()
+        .and_then(|t| t.claims.ver);
+    tracing::debug!(
+        kid = header.as_ref().and_then(|h| h.kid.as_deref()),
+        alg = alg.as_deref(),
+        ver = ver.as_deref(),
+        reason = reason(err),
+        "Token rejected"
+    );
+}
+
+/// A short, stable label for the kind of error.
+#[cfg(any(feature = "metrics", feature = "tracing"))]
+fn reason(err: &AuthErr) -> &'static str {
+    use jsonwebtoken::errors::ErrorKind;
+    use AuthErr::*;
+
+    match err {
+        InvalidToken(e) => match e.kind() {
+            ErrorKind::InvalidAudience => "invalid_audience",
+            ErrorKind::InvalidIssuer => "invalid_issuer",
+            ErrorKind::InvalidAlgorithm => "invalid_algorithm",
+            _ => "invalid_token",
+        },
+        TokenExpired => "expired",
+        TokenNotYetValid => "not_yet_valid",
+        InvalidAudience { .. } => "invalid_audience",
+        InvalidSignature => "invalid_signature",
+        ConnectionError(_) | HttpError { .. } => "connection",
+        Other(_) => "other",
+        ParseError(_) => "parse",
+        MissingKid => "missing_kid",
+        KeyNotFound(_) => "key_not_found",
+        TenantNotAllowed(_) => "tenant_not_allowed",
+        MissingRole(_) => "missing_role",
+        IssuedInFuture => "issued_in_future",
+        InvalidIssuer { .. } => "invalid_issuer",
+        KeysStale => "keys_stale",
+        KidNotPinned(_) => "kid_not_pinned",
+        UnexpectedTokenVersion { .. } => "unexpected_version",
+        AlgorithmKeyMismatch { .. } => "algorithm_key_mismatch",
+        ClaimsParseError(_) => "claims_parse",
+        MalformedToken(_) => "malformed",
+        PersonalAccountNotAllowed => "personal_account",
+        MfaRequired => "mfa_required",
+        IdpNotAllowed(_) => "idp_not_allowed",
+        TokenRevoked => "revoked",
+        MissingClaim(_) => "missing_claim",
+        MissingScope(_) => "missing_scope",
+        TokenTooLarge { .. } => "too_large",
+        UnexpectedAudienceFormat(_) => "audience_format",
+        MissingAuthorizationHeader | UnsupportedAuthorizationScheme(_) | EmptyBearerToken => {
+            "authorization_header"
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use azure_jwt_async::*;
 use jsonwebtoken as jwt;
+use serde::{Deserialize, Serialize};
 use simple_base64::{engine::general_purpose, Engine};
 
 const PUBLIC_KEY_N: &str = "AOx0GOQcSt5AZu02nlGWUuXXppxeV9Cu_9LcgpVBg_WQb-5DBHZpqs8AMek5u5iI4hkHCcOyMbQrBsDIVa9xxZxR2kq_8GtERsnd6NClQimspxT1WVgX5_WCAd5rk__Iv0GocP2c_1CcdT8is2OZHeWQySyQNSgyJYg6Up7kFtYabiCyU5q9tTIHQPXiwY53IGsNvSkqbk-OsdWPT3E4dqp3vNraMqXhuSZ-52kLCHqwPgAsbztfFJxSAEBcp-TS3uNuHeSJwNWjvDKTPy2oMacNpbsKb2gZgzubR6hTjvupRjaQ9SHhXyL9lmSZOpCzz2XJSVRopKUUtB-VGA0qVlk";
@@ -88,8 +89,8 @@ fn generate_test_token() -> String {
     .join(".");
 
     // we create the signature using our private key
-    let signature =
-        jwt::crypto::sign(&test_token, &private_key, jwt::Algorithm::RS256).expect("Signed");
+    let signature = jwt::crypto::sign(test_token.as_bytes(), &private_key, jwt::Algorithm::RS256)
+        .expect("Signed");
 
     // we construct a complete token which looks like: header.claims.signature
     let complete_token = format!("{}.{}", test_token, signature);
@@ -97,16 +98,13 @@ fn generate_test_token() -> String {
     complete_token
 }
 
+#[cfg(feature = "azure-claims")]
 #[tokio::test]
 async fn decode_token() {
     let token = generate_test_token();
     // we need to construct our own key object that matches on `kid` field
     // just as it should if we used the fetched keys from microsofts servers.
-    let key = Jwk {
-        kid: "i6lGk3FZzxRcUb2C3nEQ7syHJlY".to_string(),
-        n: PUBLIC_KEY_N.to_string(),
-        e: PUBLIC_KEY_E.to_string(),
-    };
+    let key = Jwk::new("i6lGk3FZzxRcUb2C3nEQ7syHJlY", PUBLIC_KEY_N, PUBLIC_KEY_E);
 
     let mut az_auth = AzureAuth::new("6e74172b-be56-4843-9ff4-e66a39bb12e3")
         .await
@@ -116,3 +114,39 @@ async fn decode_token() {
 
     az_auth.validate_token(&token).await.expect("validated");
 }
+
+// runs without the `azure-claims` feature too, which leaves only the custom validation
+#[tokio::test]
+async fn validates_custom_claims() {
+    #[derive(Debug, Serialize, Deserialize)]
+    struct MyClaims {
+        aud: String,
+        sub: String,
+        exp: u64,
+        name: String,
+    }
+
+    let token = generate_test_token();
+    let key = Jwk::new("i6lGk3FZzxRcUb2C3nEQ7syHJlY", PUBLIC_KEY_N, PUBLIC_KEY_E);
+    let mut az_auth =
+        AzureAuth::new_offline("6e74172b-be56-4843-9ff4-e66a39bb12e3", vec![key]).unwrap();
+
+    let mut validator = jwt::Validation::new(jwt::Algorithm::RS256);
+    validator.set_audience(&["6e74172b-be56-4843-9ff4-e66a39bb12e3"]);
+    let token_data = az_auth
+        .validate_custom::<MyClaims>(&token, &validator)
+        .await
+        .expect("validated");
+    assert_eq!(token_data.claims.name, "Abe Lincoln");
+    assert_eq!(
+        token_data.header.kid.as_deref(),
+        Some("i6lGk3FZzxRcUb2C3nEQ7syHJlY")
+    );
+
+    validator.set_audience(&["another-app"]);
+    let err = az_auth
+        .validate_custom::<MyClaims>(&token, &validator)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, AuthErr::InvalidAudience { .. }));
+}